@@ -1,4 +1,5 @@
 import argparse
+import json
 import subprocess
 import os
 import re
@@ -58,6 +59,31 @@ def parse_quack(filename):
 
     return (xs, ys)
 
+def parse_events(filename):
+    """Parse the insert events of the sidekick's JSON event log."""
+    with open(filename) as f:
+        lines = f.read().split('\n')
+    xs = []
+    ys = []
+
+    for line in lines:
+        line = line.strip()
+        if not line.startswith('{'):
+            continue
+        try:
+            event = json.loads(line)
+        except json.JSONDecodeError:
+            continue
+        if event.get('event') != 'insert':
+            continue
+        x = event['mono_ns'] / 1_000_000.
+        packet_id = event['id']
+        count = event['count']
+        xs.append(x)
+        ys.append((packet_id, count))
+
+    return (xs, ys)
+
 def parse_lost(filename, function):
     with open(filename) as f:
         lines = f.read().split('\n')
@@ -135,7 +161,7 @@ def check_subset(data):
 
 def parse_data(r1_filename, h2_filename):
     # Parse raw data and check subset properties
-    r1 = parse_quack(r1_filename)
+    r1 = parse_events(r1_filename)
     h2 = parse_quack(h2_filename)
     lost_quack = parse_lost(h2_filename, 'on_quack_received')
     lost_e2e = parse_lost(h2_filename, 'detect_lost_packets')
@@ -219,7 +245,7 @@ def run(args):
                '--loss2', args.loss, '-t', '1', '--benchmark', 'quic',
                '--bw2', args.bw,
                '-s', '2ms', '--threshold', args.t, '--quack-reset',
-               '--quack-log']
+               '--event-log', 'r1.log']
         cmd += args.args
         print(' '.join(cmd))
         p = subprocess.Popen(cmd, cwd=WORKDIR)
@@ -231,7 +257,7 @@ def run(args):
     plot_graph(xs, ys, args.n, args.loss, args.t, args.bw)
 
 if __name__ == '__main__':
-    parser = argparse.ArgumentParser(description='make sure to compile quiche with "quack_log" and "cwnd_log" feature, and sidekick with "quack_log" feature')
+    parser = argparse.ArgumentParser(description='make sure to compile quiche with "quack_log" and "cwnd_log" feature, and run sidekick with "--event-log r1.log"')
     parser.add_argument('--execute', action='store_true')
     parser.add_argument('-f', help='force execute', action='store_true')
     parser.add_argument('-n', help='data size (default: 10M)', default='10M')
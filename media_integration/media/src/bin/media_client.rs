@@ -10,8 +10,19 @@
 //!
 //! When using a quACK, immediately retransmit missing packets from the quACK
 //! i.e. a packet is missing after 3 later packets have been received. If the
-//! quACK is undecodeable, send a reset message to the socket address from
-//! which the quACK was sent.
+//! quACK is undecodeable, send a reset message to the sidekick from the
+//! socket the packets are sent from, which identifies the flow to reset. With
+//! partial resets, the reset message is the last quACK that was fully
+//! processed, which the sidekick subtracts from its quACK instead of
+//! forgetting the packets it received since. If the sidekick cannot subtract
+//! it, the sidekick resets its quACK and sends back a single-byte NACK, and
+//! the client resets its own quACK too.
+//!
+//! If the sidekick timestamps its quACKs, counts bytes, or counts CE marks,
+//! the client estimates the near-path RTT, goodput, and congestion from them
+//! and prints the estimates when it stops.
+use std::collections::HashSet;
+use std::fmt;
 use std::io;
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -21,6 +32,7 @@ use log::{debug, info, trace};
 use quack::arithmetic::{self, ModularArithmetic};
 use quack::{PowerSumQuack, PowerSumQuackU32, StrawmanAQuack, StrawmanBQuack};
 use rand::Rng;
+use sidekick::encoding::{self, WireQuack};
 use tokio::net::UdpSocket;
 use tokio::sync::mpsc;
 use tokio::sync::Mutex; // locked across calls to .await
@@ -61,6 +73,15 @@ struct Cli {
     /// QuACK threshold.
     #[arg(long, default_value_t = 8)]
     threshold: usize,
+    /// Reset by sending back the last fully-processed quACK, if any. The
+    /// sidekick must be listening for acknowledged quACKs.
+    #[arg(long)]
+    partial_reset: bool,
+    /// Before resetting because too many packets are missing, request the
+    /// recent identifiers from the sidekick to recover with. The sidekick must
+    /// be listening for acknowledged quACKs and attach recent identifiers.
+    #[arg(long)]
+    fallback: bool,
 }
 
 /// NACKs just have 4 bytes for the sequence number.
@@ -84,11 +105,46 @@ const MTU: usize = 1500;
 /// value is 7, at most packets 5 and 6 can be considered indeterminate.
 const _REORDER_THRESHOLD: u32 = 3;
 
+/// Near-path measurements from the extensions of the sidekick's quACKs.
+#[derive(Debug, Default)]
+struct NearPath {
+    /// Number of near-path RTT samples
+    rtt_samples: u64,
+    /// Smoothed near-path RTT, as in RFC 6298
+    srtt: Option<Duration>,
+    /// Minimum near-path RTT
+    min_rtt: Option<Duration>,
+    /// Latest near-path goodput, in bits/s
+    goodput: Option<f64>,
+    /// Number of packets the sidekick received marked Congestion Experienced
+    ce_marks: u32,
+}
+
+impl NearPath {
+    fn on_rtt(&mut self, rtt: Duration) {
+        self.rtt_samples += 1;
+        self.srtt = Some(self.srtt.map_or(rtt, |srtt| (srtt * 7 + rtt) / 8));
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min_rtt| min_rtt.min(rtt)));
+    }
+}
+
+impl fmt::Display for NearPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "near path: rtt_samples={} srtt={:?} min_rtt={:?} goodput={:?} ce_marks={}",
+            self.rtt_samples, self.srtt, self.min_rtt, self.goodput, self.ce_marks
+        )
+    }
+}
+
 #[derive(Clone)]
 struct PacketSender {
     sidekick: bool,
     channel: mpsc::Sender<(u32, u32)>,
-    seqno_ids: Arc<Mutex<Vec<(u32, u32)>>>,
+    /// Sequence numbers and identifiers of sent packets, and when they were
+    /// sent.
+    seqno_ids: Arc<Mutex<Vec<(u32, u32, Instant)>>>,
 }
 
 impl PacketSender {
@@ -107,7 +163,10 @@ impl PacketSender {
         // Add the new packet to the buffer and send the packet.
         // (may be some harmless reordering here)
         if self.sidekick {
-            self.seqno_ids.lock().await.push((seqno, id));
+            self.seqno_ids
+                .lock()
+                .await
+                .push((seqno, id, Instant::now()));
         }
         self.channel.send((seqno, id)).await.unwrap();
         Ok(())
@@ -118,6 +177,7 @@ impl PacketSender {
 /// Receives sequence numbers and random identifiers and fills the packets.
 async fn send_data(
     sock: Arc<UdpSocket>,
+    server_addr: SocketAddr,
     bytes: usize,
     mut rx: mpsc::Receiver<(u32, u32)>,
 ) -> io::Result<()> {
@@ -138,19 +198,35 @@ async fn send_data(
             payload[ID_OFFSET + 2] = id_bytes[2];
             payload[ID_OFFSET + 3] = id_bytes[3];
 
-            sock.send(&payload).await.unwrap();
+            sock.send_to(&payload, server_addr).await.unwrap();
         }
     });
     Ok(())
 }
 
 /// Spawn a thread that listens for end-to-end NACKs and retransmit packets
-/// when requested.
-fn listen_for_nacks(sock: Arc<UdpSocket>, mut sender: PacketSender) {
+/// when requested. NACKs of quACK resets from the sidekick at `reset_addr`
+/// are passed on to `reset_nacks`.
+fn listen_for_nacks(
+    sock: Arc<UdpSocket>,
+    server_addr: SocketAddr,
+    reset_addr: SocketAddr,
+    mut sender: PacketSender,
+    reset_nacks: mpsc::Sender<()>,
+) {
     let mut buf: [u8; NACK_BUFFER_SIZE] = [0; NACK_BUFFER_SIZE];
     tokio::spawn(async move {
         loop {
-            let len = sock.recv(&mut buf).await.unwrap();
+            let (len, from) = sock.recv_from(&mut buf).await.unwrap();
+            if from == reset_addr {
+                debug!("sidekick nacked the reset");
+                // Ignored unless listening for power sum quACKs
+                let _ = reset_nacks.send(()).await;
+                continue;
+            }
+            if from != server_addr {
+                continue;
+            }
             assert_eq!(len, NACK_BUFFER_SIZE);
             let seqno = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
             debug!("retransmit {} from nack", seqno);
@@ -201,13 +277,18 @@ fn listen_for_quacks_strawman_c(mut _sender: PacketSender, _quack_port: u16) {
 }
 
 /// Spawn a thread that listens for sidekick quACKs using the power sum quACK
-/// and retransmit packets when determined missing.
+/// and retransmit packets when determined missing. Resets are sent on
+/// `data_sock`, and the sidekick's NACKs of them are received on
+/// `reset_nacks`.
 fn listen_for_quacks_power_sum(
     mut sender: PacketSender,
-    quack_port: u16,
-    reset_addr: SocketAddr,
-    threshold: usize,
+    data_sock: Arc<UdpSocket>,
+    args: &Cli,
+    mut reset_nacks: mpsc::Receiver<()>,
+    near_path: Arc<Mutex<NearPath>>,
 ) {
+    let (quack_port, reset_addr, threshold) = (args.quack_port, args.reset_addr, args.threshold);
+    let (partial_reset, fallback) = (args.partial_reset, args.fallback);
     tokio::spawn(async move {
         let sock = UdpSocket::bind(format!("0.0.0.0:{}", quack_port))
             .await
@@ -220,11 +301,40 @@ fn listen_for_quacks_power_sum(
         let mut last_quack_reset = None;
         let sidekick_reset_threshold = Duration::from_millis(100);
 
+        // The last quACK that was fully processed, for partial resets.
+        let mut last_processed: Option<PowerSumQuackU32> = None;
+        let mut reset_buf = vec![0; PowerSumQuackU32::encoded_len(threshold)];
+
+        // Extensions of the last quACK and when it was received.
+        let mut last_extensions = None;
+
+        // Whether the recent identifiers were requested since the last reset
+        // or recovery.
+        let mut fallback_requested = false;
+
         loop {
             // Deserialize the quACK and only process it if at least one packet
-            // has been received and the quack has changed.
-            let (len, _) = sock.recv_from(&mut buf).await.unwrap();
-            let quack: PowerSumQuackU32 = bincode::deserialize(&buf[..len]).unwrap();
+            // has been received and the quack has changed. If the sidekick
+            // could not apply a partial reset, it reset its quACK instead.
+            let len = tokio::select! {
+                result = sock.recv_from(&mut buf) => result.unwrap().0,
+                Some(()) = reset_nacks.recv() => {
+                    info!("sidekick reset its quack");
+                    my_quack = PowerSumQuackU32::new(threshold);
+                    *sender.seqno_ids.lock().await = vec![];
+                    last_processed = None;
+                    fallback_requested = false;
+                    continue;
+                }
+            };
+            let (quack, extensions) =
+                match encoding::decode_message::<PowerSumQuackU32>(&buf[..len]) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        debug!("bad quack: {}", e);
+                        continue;
+                    }
+                };
             trace!(
                 "received quack count={} last_value={:?}",
                 quack.count(),
@@ -234,23 +344,86 @@ fn listen_for_quacks_power_sum(
                 continue;
             }
 
+            // Estimate the near-path goodput if the sidekick counts bytes, and
+            // the near-path congestion if it counts CE marks.
+            let recv_time = Instant::now();
+            if let Some((earlier, then)) = last_extensions {
+                let mut near_path = near_path.lock().await;
+                if let Some(goodput) = extensions.goodput_since(&earlier, recv_time - then) {
+                    debug!("near goodput {:.3} Mbit/s", goodput / 1000000.0);
+                    near_path.goodput = Some(goodput);
+                }
+                if let Some(ce_marks) = extensions.ce_marks_since(&earlier) {
+                    if ce_marks > 0 {
+                        debug!("near congestion {} CE marks", ce_marks);
+                    }
+                    near_path.ce_marks += ce_marks;
+                }
+            }
+            last_extensions = Some((extensions.clone(), recv_time));
+
             // Update our own cumulative quACK to include up to the last value
             // received (we would have sent everything in order).
             let mut seqno_ids = sender.seqno_ids.lock().await;
             let mut last_index_inserted = None;
-            for (i, &(_, id)) in seqno_ids.iter().enumerate() {
+            for (i, &(_, id, _)) in seqno_ids.iter().enumerate() {
                 if Some(id) == quack.last_value() {
                     last_index_inserted = Some(i);
                     break;
                 }
             }
             if let Some(idx) = last_index_inserted {
-                for &(seqno, id) in seqno_ids.iter().take(idx + 1) {
+                // Sample the near-path RTT if the sidekick timestamped the
+                // last packet it received.
+                if let Some(timestamps) = extensions.timestamps {
+                    let sent = seqno_ids[idx].2;
+                    if let Some(rtt) = timestamps.near_rtt(Instant::now() - sent) {
+                        debug!("near rtt {:?}", rtt);
+                        near_path.lock().await.on_rtt(rtt);
+                    }
+                }
+                for &(seqno, id, _) in seqno_ids.iter().take(idx + 1) {
                     my_quack.insert(id);
                     trace!("quack insert {} ({})", id, seqno);
                 }
             }
 
+            // If too many packets are missing to decode the quACK, recover
+            // with the recent identifiers the sidekick attached instead, if
+            // they include every packet it received that we just inserted.
+            if let (Some(idx), Some(recent_ids)) = (last_index_inserted, &extensions.recent_ids) {
+                let inserted_before = my_quack.count() - (idx as u32 + 1);
+                let received = quack.count().saturating_sub(inserted_before);
+                if my_quack.count() > quack.count() + threshold as u32 {
+                    let recent_ids: HashSet<u32> = recent_ids.iter().map(|&id| id as u32).collect();
+                    let num_received = seqno_ids
+                        .iter()
+                        .take(idx + 1)
+                        .filter(|(_, id, _)| recent_ids.contains(id))
+                        .count();
+                    if num_received as u32 == received {
+                        let missing_seqno_ids: Vec<_> = seqno_ids
+                            .drain(..(idx + 1))
+                            .filter(|(_, id, _)| !recent_ids.contains(id))
+                            .map(|(seqno, id, _)| (seqno, id))
+                            .collect();
+                        drop(seqno_ids);
+                        info!(
+                            "recovered {} missing from recent ids",
+                            missing_seqno_ids.len()
+                        );
+                        for (seqno, id) in missing_seqno_ids.into_iter() {
+                            my_quack.remove(id);
+                            debug!("retransmit {} from recent ids", seqno);
+                            sender.send(seqno).await.unwrap();
+                        }
+                        last_processed = partial_reset.then(|| my_quack.clone());
+                        fallback_requested = false;
+                        continue;
+                    }
+                }
+            }
+
             // Reset the quack if 1) the log got messed up above, 2) we're
             // still waiting to process a previous reset, or 3) the number of
             // missing packets exceeds the threshold.
@@ -264,14 +437,37 @@ fn listen_for_quacks_power_sum(
                 } else {
                     true
                 };
-                if should_reset {
+                if should_reset && reset2 && fallback && !fallback_requested {
+                    // Try to recover with the recent identifiers first.
+                    info!("requesting recent ids");
+                    data_sock
+                        .send_to(&[encoding::ACK_FALLBACK], reset_addr)
+                        .await
+                        .unwrap();
+                    fallback_requested = true;
+                    last_quack_reset = Some(now);
+                } else if should_reset {
+                    fallback_requested = false;
                     info!(
                         "reset: reordered? {} retx? {} exceeds threshold? {}",
                         reset0, reset1, reset2
                     );
-                    sock.send_to(&[0], reset_addr).await.unwrap();
-                    my_quack = PowerSumQuackU32::new(threshold);
-                    *seqno_ids = vec![];
+                    if let Some(processed) = last_processed.take() {
+                        // Only forget the packets in the processed quACK.
+                        let len = processed.encode_into(None, &mut reset_buf).unwrap();
+                        data_sock
+                            .send_to(&reset_buf[..len], reset_addr)
+                            .await
+                            .unwrap();
+                        my_quack.sub_assign(processed);
+                    } else {
+                        data_sock
+                            .send_to(&[encoding::ACK_RESET], reset_addr)
+                            .await
+                            .unwrap();
+                        my_quack = PowerSumQuackU32::new(threshold);
+                        *seqno_ids = vec![];
+                    }
                     last_quack_reset = Some(now);
                 }
                 continue;
@@ -282,6 +478,7 @@ fn listen_for_quacks_power_sum(
                 info!("successful reset");
                 last_quack_reset = None;
             }
+            fallback_requested = false;
 
             // If the number of missing packets exceeds the threshold, reset
             // the quack. If no packets are missing, continue on.
@@ -296,6 +493,7 @@ fn listen_for_quacks_power_sum(
             diff_quack.sub_assign(quack);
             if diff_quack.count() == 0 {
                 seqno_ids.drain(..(last_index_inserted + 1));
+                last_processed = partial_reset.then(|| my_quack.clone());
                 continue;
             }
 
@@ -303,7 +501,7 @@ fn listen_for_quacks_power_sum(
             // received.
             let coeffs = diff_quack.to_coeffs();
             let mut missing_seqno_ids = Vec::new();
-            for &(seqno, id) in seqno_ids.iter() {
+            for &(seqno, id, _) in seqno_ids.iter() {
                 if Some(id) == diff_quack.last_value() {
                     break;
                 }
@@ -312,7 +510,9 @@ fn listen_for_quacks_power_sum(
                 }
             }
 
-            // Retransmit any missing packets.
+            // Retransmit any missing packets. Our quACK is then the processed
+            // quACK, with the threshold the sidekick was configured with,
+            // even if the sidekick sent fewer power sums.
             seqno_ids.drain(..(last_index_inserted + 1));
             drop(seqno_ids);
             for (seqno, id) in missing_seqno_ids.into_iter() {
@@ -320,6 +520,7 @@ fn listen_for_quacks_power_sum(
                 debug!("retransmit {} from quack", seqno);
                 sender.send(seqno).await.unwrap();
             }
+            last_processed = partial_reset.then(|| my_quack.clone());
         }
     });
 }
@@ -360,15 +561,24 @@ async fn main() -> io::Result<()> {
     let args = Cli::parse();
     let (tx, rx) = mpsc::channel(100);
 
+    // Not connected to the server, to also send quACK resets to the sidekick
+    // from the address of the flow.
     let sock = {
         let sock = UdpSocket::bind("0.0.0.0:0").await?;
         info!("sending from {:?}", sock.local_addr().unwrap());
-        sock.connect(args.server_addr).await?;
         Arc::new(sock)
     };
     let sender = PacketSender::new(args.quack_style.is_some(), tx).await?;
-    send_data(sock.clone(), args.bytes, rx).await?;
-    listen_for_nacks(sock, sender.clone());
+    let near_path = Arc::new(Mutex::new(NearPath::default()));
+    send_data(sock.clone(), args.server_addr, args.bytes, rx).await?;
+    let (reset_nacks_tx, reset_nacks) = mpsc::channel(1);
+    listen_for_nacks(
+        sock.clone(),
+        args.server_addr,
+        args.reset_addr,
+        sender.clone(),
+        reset_nacks_tx,
+    );
     if let Some(quack_style) = args.quack_style {
         match quack_style {
             QuackStyle::StrawmanA => listen_for_quacks_strawman_a(sender.clone(), args.quack_port),
@@ -376,9 +586,10 @@ async fn main() -> io::Result<()> {
             QuackStyle::StrawmanC => listen_for_quacks_strawman_c(sender.clone(), args.quack_port),
             QuackStyle::PowerSum => listen_for_quacks_power_sum(
                 sender.clone(),
-                args.quack_port,
-                args.reset_addr,
-                args.threshold,
+                sock,
+                &args,
+                reset_nacks,
+                near_path.clone(),
             ),
         };
     }
@@ -388,5 +599,8 @@ async fn main() -> io::Result<()> {
         Duration::from_millis(args.frequency),
     )
     .await?;
+    if args.quack_style == Some(QuackStyle::PowerSum) {
+        info!("{}", near_path.lock().await);
+    }
     Ok(())
 }
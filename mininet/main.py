@@ -72,7 +72,8 @@ def benchmark_quack(net, args):
 
     net.h2.cmdPrint(h2_cmd)
     for _ in range(loops):
-        net.start_quack_sender(args.frequency, args.threshold, args.style)
+        net.start_quack_sender(args.frequency, args.threshold, args.style,
+            args.event_log)
         time.sleep(0.1)  # wait for the quack sender to start
         net.h2.cmdPrint(h2_cmd)
 
@@ -127,6 +128,9 @@ if __name__ == '__main__':
         metavar='PACKETS',
         help='Initializes the quACK sender and receiver with this threshold '
              '(default: 10).')
+    proto_config.add_argument('--event-log', metavar='FILENAME',
+        help='Log the quACK events of a power sum sidekick to this file in '
+             'JSON e.g., r1.log')
     proto_config.add_argument('--print-statistics', action='store_true',
         help='Print statistics on number of packets sent at each host')
 
@@ -225,10 +229,11 @@ if __name__ == '__main__':
     sclog(f'Link2 delay={args.delay2} loss={args.loss2} bw={args.bw2}')
     if args.pep:
         net.start_tcp_pep()
+    clean_logs()
     if args.sidekick:
-        net.start_quack_sender(args.frequency, args.threshold, args.style)
+        net.start_quack_sender(args.frequency, args.threshold, args.style,
+            args.event_log)
     net.set_segmentation_offloading(args.tso)
-    clean_logs()
 
     if args.ty == 'monitor':
         if args.ping is not None:
@@ -169,11 +169,13 @@ class SidekickNetwork():
         popen(self.r1, 'iptables -t mangle -A PREROUTING -i r1-eth0 -p tcp -j TPROXY --on-port 5000 --tproxy-mark 1')
         self.r1.cmd('pepsal -v >> r1.log 2>&1 &')
 
-    def start_quack_sender(self, frequency, threshold, style):
+    def start_quack_sender(self, frequency, threshold, style, event_log=None):
         """
         - `frequency`: frequency of the sidekick sender e.g. 2ms or 2p
         - `threshold`: quACK threshold
         - `style`: power_sum, strawman_a, strawman_b, OR strawman_c
+        - `event_log`: file to log the quACK events of a power sum sidekick
+          to, if any
         """
         print('', file=sys.stderr)
         sclog('Starting the QUIC sidekick sender on r1...')
@@ -187,26 +189,30 @@ class SidekickNetwork():
             raise 'Invalid frequency: {}'.format(frequency)
 
         self.r1.cmd(f'kill $(pidof sidekick)')
-        # Does ./target/release/sender exist?
+        # Does ./target/release/sidekick exist?
         env = os.environ.copy()
         env['RUST_BACKTRACE'] = '1'
         env['RUST_LOG'] = 'info'
         if style == 'multi':
-            cmd = f'./target/release/sender_multi -i r1-eth1 -t {threshold} ' + \
-                  f'--quack-addr 10.0.2.10:5103 {frequency} --my-ip 10.0.2.1' + \
+            cmd = f'./target/release/sidekick run-multi -i r1-eth1 -t {threshold} ' + \
+                  f'--quack-addr 10.0.2.10:5103 {frequency} --my-ip 10.0.2.1 ' + \
                   f'--dst-ip 10.0.1.1 --dst-port 443'
         elif style == 'power_sum':
-            cmd = f'./target/release/sender -i r1-eth1 -t {threshold} ' + \
-                  f'--target-addr 10.0.2.10:5103 {frequency} --my-addr 10.0.2.1'
+            cmd = f'./target/release/sidekick run -i r1-eth1 -t {threshold} ' + \
+                  f'--quack-addr 10.0.2.10:5103 {frequency} --my-ip 10.0.2.1'
         elif style == 'strawman_a':
-            cmd = f'./target/release/sender_strawman_a ' + \
-                  f'-i r1-eth1 --addr 10.0.2.10:5103'
+            cmd = f'./target/release/sidekick strawman -i r1-eth1 ' + \
+                  f'--style strawman-a --quack-addr 10.0.2.10:5103'
         elif style == 'strawman_b':
-            cmd = f'./target/release/sender_strawman_b ' + \
-                  f'-i r1-eth1 --addr 10.0.2.10:5103 -n 4'
+            cmd = f'./target/release/sidekick strawman -i r1-eth1 ' + \
+                  f'--style strawman-b --quack-addr 10.0.2.10:5103 -n 4'
         elif style == 'strawman_c':
-            cmd = f'./target/release/sender_strawman_tcp ' + \
-                  f'-i r1-eth1 --addr 10.0.2.10:5103'
+            cmd = f'./target/release/sidekick strawman -i r1-eth1 ' + \
+                  f'--style strawman-a --quack-addr 10.0.2.10:5103 --tcp'
+        if event_log is not None:
+            if style not in ['multi', 'power_sum']:
+                raise ValueError(f'no event log for style: {style}')
+            cmd += f' --event-log {event_log}'
         sclog(cmd)
         self.r1.popen(cmd.split(' '), stdout=sys.stdout, stderr=sys.stderr, env=env)
 
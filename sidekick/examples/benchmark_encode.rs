@@ -1,5 +1,6 @@
 use clap::Parser;
-use quack::PowerSumQuack;
+use quack::{PowerSumQuack, PowerSumQuackU32};
+use sidekick::encoding::{self, WireQuack};
 use sidekick::Sidekick;
 use signal_hook::{consts::SIGTERM, iterator::Signals};
 use std::net::{Ipv4Addr, SocketAddr};
@@ -27,24 +28,27 @@ struct Cli {
 }
 
 pub struct Benchmark {
-    pub sc: Arc<Mutex<Sidekick>>,
+    pub sc: Arc<Mutex<Sidekick<PowerSumQuackU32>>>,
     pub addr: SocketAddr,
     pub frequency: Option<Duration>,
 }
 
-async fn handle_signals(sc: Arc<Mutex<Sidekick>>, mut signals: Signals) {
+async fn handle_signals(sc: Arc<Mutex<Sidekick<PowerSumQuackU32>>>, mut signals: Signals) {
     for _ in &mut signals {
         let sc = sc.lock().unwrap();
         if let Some(start_time) = sc.start_time {
             let total = Instant::now() - start_time;
             let count = sc.quack().count();
+            let bytes = sc.bytes();
             println!("Total: {:?}", total);
             println!("Count: {}", count);
+            println!("Bytes: {}", bytes);
 
             let total_us: u128 = total.as_micros();
             let rate_pps: f64 = count as f64 * 1000000.0 / total_us as f64;
-            let rate_mbits: f64 = rate_pps * 1500.0 * 8.0 / 1000000.0;
+            let rate_mbits: f64 = bytes as f64 * 8.0 / total_us as f64;
             println!("Rate (packets/s): {:.3}", rate_pps);
+            println!("Rate (Mbit/s): {:.3}", rate_mbits);
         } else {
             println!("No start time!");
         }
@@ -53,7 +57,7 @@ async fn handle_signals(sc: Arc<Mutex<Sidekick>>, mut signals: Signals) {
 }
 
 impl Benchmark {
-    pub fn new(sc: Sidekick, addr: SocketAddr, frequency_ms: u64) -> Self {
+    pub fn new(sc: Sidekick<PowerSumQuackU32>, addr: SocketAddr, frequency_ms: u64) -> Self {
         let frequency = if frequency_ms == 0 {
             None
         } else {
@@ -79,13 +83,20 @@ impl Benchmark {
             .unwrap();
         if let Some(frequency) = self.frequency {
             let socket = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+            let threshold = self.sc.lock().unwrap().threshold;
+            let mut buf =
+                vec![0; PowerSumQuackU32::encoded_len(threshold) + encoding::MAX_EXTENSIONS_LEN];
             let mut interval = time::interval(frequency);
             interval.tick().await; // The first tick completes immediately.
             loop {
                 interval.tick().await;
-                let quack = self.sc.lock().unwrap().quack();
-                let bytes = bincode::serialize(&quack).unwrap();
-                socket.send_to(&bytes, self.addr).await.unwrap();
+                let len = self
+                    .sc
+                    .lock()
+                    .unwrap()
+                    .encode_quack(None, &mut buf)
+                    .unwrap();
+                socket.send_to(&buf[..len], self.addr).await.unwrap();
             }
         } else {
             // Park this thread until the program is killed externally.
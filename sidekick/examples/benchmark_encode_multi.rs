@@ -1,5 +1,6 @@
 use clap::Parser;
-use quack::PowerSumQuack;
+use quack::{PowerSumQuack, PowerSumQuackU32};
+use sidekick::encoding::{self, WireQuack};
 use sidekick::sidekick_multi::start_sidekick_multi;
 use sidekick::SidekickMulti;
 use signal_hook::{consts::SIGTERM, iterator::Signals};
@@ -28,27 +29,31 @@ struct Cli {
 }
 
 pub struct Benchmark {
-    pub sc: Arc<Mutex<SidekickMulti>>,
+    pub sc: Arc<Mutex<SidekickMulti<PowerSumQuackU32>>>,
     pub frequency: Option<Duration>,
     pub my_addr: [u8; 6],
 }
 
-async fn handle_signals(sc: Arc<Mutex<SidekickMulti>>, mut signals: Signals) {
+async fn handle_signals(sc: Arc<Mutex<SidekickMulti<PowerSumQuackU32>>>, mut signals: Signals) {
     for _ in &mut signals {
         let sc = sc.lock().unwrap();
         if let Some(start_time) = sc.start_time {
             let total = Instant::now() - start_time;
             let senders = sc.senders();
-            let total_count: u32 = senders.values().map(|quack| quack.count()).sum();
+            let total_count: u32 = senders.values().map(|flow| flow.quack.count()).sum();
             let avg_count = (total_count as usize) / senders.len();
+            let total_bytes: u64 = senders.values().map(|flow| flow.bytes).sum();
+            let avg_bytes = total_bytes / (senders.len() as u64);
             println!("Total time: {:?}", total);
             println!("Unique connections: {}", senders.len());
             println!("Packet count (total): {}", total_count);
             println!("Packet count (average): {}", avg_count);
+            println!("Byte count (total): {}", total_bytes);
+            println!("Byte count (average): {}", avg_bytes);
 
             let total_us: u128 = total.as_micros();
             let rate_pps: f64 = avg_count as f64 * 1000000.0 / total_us as f64;
-            let rate_mbits: f64 = rate_pps * 1500.0 * 8.0 / 1000000.0;
+            let rate_mbits: f64 = avg_bytes as f64 * 8.0 / total_us as f64;
             println!("Average rate (packets/s/client): {:.3}", rate_pps);
             println!("Average rate (Mbit/s/client): {:.3}", rate_mbits);
             println!(
@@ -67,7 +72,12 @@ async fn handle_signals(sc: Arc<Mutex<SidekickMulti>>, mut signals: Signals) {
 }
 
 impl Benchmark {
-    pub fn new(sc: SidekickMulti, frequency_ms: u64, my_ip: Ipv4Addr, my_port: u16) -> Self {
+    pub fn new(
+        sc: SidekickMulti<PowerSumQuackU32>,
+        frequency_ms: u64,
+        my_ip: Ipv4Addr,
+        my_port: u16,
+    ) -> Self {
         let frequency = if frequency_ms == 0 {
             None
         } else {
@@ -100,16 +110,19 @@ impl Benchmark {
             .unwrap();
         if let Some(frequency) = self.frequency {
             let socket = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+            let threshold = self.sc.lock().unwrap().threshold;
+            let mut buf =
+                vec![0; PowerSumQuackU32::encoded_len(threshold) + encoding::MAX_EXTENSIONS_LEN];
             let mut interval = time::interval(frequency);
             interval.tick().await; // The first tick completes immediately.
             loop {
                 interval.tick().await;
-                for (key, quack) in self.sc.lock().unwrap().senders() {
+                for (key, flow) in self.sc.lock().unwrap().senders_mut() {
                     let src_ip = IpAddr::V4(Ipv4Addr::new(key[0], key[1], key[2], key[3]));
                     let src_port = u16::from_be_bytes([key[5], key[6]]);
                     let src_addr = SocketAddr::new(src_ip, src_port);
-                    let bytes = bincode::serialize(&quack).unwrap();
-                    socket.send_to(&bytes, src_addr).await.unwrap();
+                    let len = flow.encode(None, &mut buf).unwrap();
+                    socket.send_to(&buf[..len], src_addr).await.unwrap();
                 }
             }
         } else {
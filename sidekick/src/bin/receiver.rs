@@ -0,0 +1,152 @@
+use clap::Parser;
+use log::{debug, info, warn};
+use quack::{PowerSumQuackU16, PowerSumQuackU32, PowerSumQuackU64};
+use sidekick::buffer::Direction;
+use sidekick::cli::SniffArgs;
+use sidekick::encoding::{self, WireQuack};
+use sidekick::filter::{Cidr, FlowMatch, PortRange};
+use sidekick::stats;
+use sidekick::Sidekick;
+use std::fmt::Debug;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+
+/// Receives quACKs in the sidekick protocol, sends data in the base protocol.
+/// Logs the identifiers of outgoing packets, unless `--direction` is
+/// specified, and decodes the quACKs of a remote sidekick against the log.
+#[derive(Parser)]
+struct Cli {
+    #[command(flatten)]
+    sniff: SniffArgs,
+    /// The threshold number of missing packets.
+    #[arg(long, short = 't', default_value_t = 20)]
+    threshold: usize,
+    /// Number of identifier bits: 16, 24, 32, or 64.
+    #[arg(long = "bits", short = 'b', default_value_t = 32)]
+    num_bits_id: usize,
+    /// Maximum number of identifiers of outgoing packets to log.
+    #[arg(long = "log-capacity", default_value_t = 4096)]
+    log_capacity: usize,
+    /// Address of the UDP socket to receive quACKs on e.g., <IP:PORT>.
+    #[arg(long = "listen-addr")]
+    listen_addr: SocketAddr,
+    /// Address of the remote sidekick to send quACK resets to e.g.,
+    /// <IP:PORT>. If missing, does not reset.
+    #[arg(long = "reset-addr")]
+    reset_addr: Option<SocketAddr>,
+    /// Only log the packets to this IP.
+    #[arg(long = "dst-ip")]
+    dst_ip: Option<Ipv4Addr>,
+    /// Only log the packets to this port.
+    #[arg(long = "dst-port")]
+    dst_port: Option<u16>,
+}
+
+async fn run<Q: WireQuack + Send + 'static>(args: Cli) -> Result<(), String>
+where
+    Q::Element: Clone + PartialEq + Debug + Send,
+{
+    let my_ip = args.sniff.my_ip.ok_or("--my-ip must be set")?;
+
+    // Start the sidekick, only logging the packets of the data flow.
+    let mut sc = Sidekick::<Q>::new_receiver(
+        &args.sniff.interface,
+        args.threshold,
+        args.num_bits_id,
+        args.log_capacity,
+    );
+    sc.direction = args.sniff.direction();
+    sc.filter = args.sniff.filter(FlowMatch {
+        dst: args.dst_ip.map(|addr| Cidr {
+            addr,
+            prefix_len: 32,
+        }),
+        dst_ports: args.dst_port.map(|port| PortRange {
+            start: port,
+            end: port,
+        }),
+        ..FlowMatch::default()
+    });
+    sc.short_headers_only = args.sniff.short_headers_only;
+    sc.gso = args.sniff.gso;
+    sc.profile = args
+        .sniff
+        .profile(sc.filter.clone(), args.num_bits_id)
+        .await?;
+    sc.check_ids = args.sniff.check_ids;
+    if args.sniff.collision_window > 0 {
+        sc.collision_window = args.sniff.collision_window;
+    }
+    stats::print_on_signal(sc.counters(), None)?;
+    let sc = Arc::new(Mutex::new(sc));
+    let _rx = Sidekick::start(sc.clone(), my_ip.octets())?;
+
+    // Decode each quACK from the remote sidekick.
+    let socket = UdpSocket::bind(args.listen_addr)
+        .await
+        .map_err(|e| format!("error binding to UDP socket: {:?}", e))?;
+    info!("listening for quACKs on {:?}", args.listen_addr);
+    let mut buf = vec![0; Q::encoded_len(args.threshold) + encoding::MAX_EXTENSIONS_LEN];
+    loop {
+        let (len, _) = socket.recv_from(&mut buf).await.unwrap();
+        let (quack, extensions) = match encoding::decode_message::<Q>(&buf[..len]) {
+            Ok(message) => message,
+            Err(e) => {
+                debug!("bad quack: {}", e);
+                continue;
+            }
+        };
+        let result = if let Some(window_start) = extensions.window_start {
+            sc.lock().unwrap().decode_window(quack, window_start)
+        } else {
+            sc.lock().unwrap().decode(quack)
+        };
+        match result {
+            Ok(decoded) => {
+                for id in decoded.missing {
+                    info!("missing {:?}", id);
+                }
+                if !decoded.indeterminate.is_empty() {
+                    let stats = sc.lock().unwrap().collision_stats();
+                    for id in decoded.indeterminate {
+                        info!("indeterminate {:?}", id);
+                    }
+                    info!(
+                        "{} of {} identifiers collided ({:.4})",
+                        stats.collisions,
+                        stats.ids,
+                        stats.rate()
+                    );
+                }
+            }
+            Err(e) => {
+                warn!("reset: {}", e);
+                sc.lock().unwrap().reset();
+                if let Some(reset_addr) = args.reset_addr {
+                    socket.send_to(&[0], reset_addr).await.unwrap();
+                }
+            }
+        }
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), String> {
+    env_logger::init();
+
+    let mut args = Cli::parse();
+    args.sniff.direction.get_or_insert(Direction::Outgoing);
+    debug!(
+        "interface={} threshold={} bits={} log_capacity={}",
+        args.sniff.interface, args.threshold, args.num_bits_id, args.log_capacity
+    );
+
+    // Pick the modular arithmetic that fits the identifier.
+    match args.num_bits_id {
+        16 => run::<PowerSumQuackU16>(args).await,
+        24 | 32 => run::<PowerSumQuackU32>(args).await,
+        64 => run::<PowerSumQuackU64>(args).await,
+        bits => Err(format!("unsupported number of identifier bits: {}", bits)),
+    }
+}
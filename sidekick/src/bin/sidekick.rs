@@ -0,0 +1,10 @@
+use clap::Parser;
+use sidekick::cli::{self, Cli};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), String> {
+    env_logger::init();
+
+    let args = Cli::parse();
+    cli::run(args.command).await
+}
@@ -0,0 +1,39 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+use clap::Parser;
+
+/// Sends a request to the control API of a sidekick, and prints the response.
+#[derive(Parser)]
+struct Args {
+    /// Path of the Unix socket the sidekick serves the control API on.
+    #[arg(long, short = 's')]
+    socket: PathBuf,
+    /// A request in JSON e.g., '{"cmd":"reset-flow","src":<IP:PORT>,
+    /// "dst":<IP:PORT>}', or the name of a command without arguments e.g.,
+    /// list-flows.
+    request: String,
+}
+
+fn main() -> Result<(), String> {
+    let args = Args::parse();
+    let request = if args.request.trim_start().starts_with('{') {
+        args.request
+    } else {
+        format!("{{\"cmd\":\"{}\"}}", args.request)
+    };
+
+    let mut stream = UnixStream::connect(&args.socket)
+        .map_err(|e| format!("error connecting to {}: {}", args.socket.display(), e))?;
+    writeln!(stream, "{}", request).map_err(|e| format!("error sending request: {}", e))?;
+    let mut response = String::new();
+    BufReader::new(stream)
+        .read_line(&mut response)
+        .map_err(|e| format!("error receiving response: {}", e))?;
+    if response.is_empty() {
+        return Err("no response".to_string());
+    }
+    print!("{}", response);
+    Ok(())
+}
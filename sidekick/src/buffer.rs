@@ -1,12 +1,66 @@
 use libc::c_uchar;
+use serde::Deserialize;
 
 // Ethernet (14), IP (20), TCP/UDP (8) headers
+pub const UDP_PAYLOAD_OFFSET: usize = 42;
 // The randomly-encrypted payload in a QUIC packet with a short header is at
 // offset 63.
 pub const ID_OFFSET: usize = 63;
-pub const BUFFER_SIZE: usize = ID_OFFSET + 4;
+// ECN codepoint in the low bits of the IPv4 traffic class
+pub const ECN_MASK: u8 = 0b11;
+pub const ECN_CE: u8 = 0b11;
+// IPv4 header without options (20), and the flags and fragment offset field
+const ETHERNET_HEADER_LEN: usize = 14;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV4_MORE_FRAGMENTS: u16 = 0x2000;
+const IPV4_FRAGMENT_OFFSET_MASK: u16 = 0x1fff;
+const UDP_HEADER_LEN: usize = 8;
+// Header form and fixed bits in the first byte of a QUIC packet
+pub const QUIC_HEADER_FORM_BIT: u8 = 0x80;
+pub const QUIC_FIXED_BIT: u8 = 0x40;
 
-#[derive(Debug, PartialEq, Eq)]
+// QUIC connection IDs are up to 20 bytes. The destination connection ID of a
+// packet with a short header immediately follows the first byte.
+pub const MAX_CID_LEN: usize = 20;
+pub const DCID_OFFSET: usize = UDP_PAYLOAD_OFFSET + 1;
+
+// Identifiers are up to 64 bits.
+pub const MAX_ID_LEN: usize = 8;
+// Enough bytes of each packet for the identifier of any profile, except SRTP
+// packets with long header extensions.
+pub const BUFFER_SIZE: usize = 128;
+
+// The virtio net header before each frame when PACKET_VNET_HDR is enabled:
+// flags (1), gso_type (1), hdr_len (2), gso_size (2), csum_start (2),
+// csum_offset (2). Frames coalesced by GRO are up to 64 KiB.
+pub const VNET_HDR_LEN: usize = 10;
+pub const MAX_FRAME_LEN: usize = 14 + 65535;
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/virtio_net.h
+const VIRTIO_NET_HDR_GSO_UDP: u8 = 3;
+const VIRTIO_NET_HDR_GSO_UDP_L4: u8 = 5;
+const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+// WireGuard transport data: type (1), reserved (3), receiver (4), counter (8)
+const WIREGUARD_TRANSPORT_DATA: u8 = 4;
+const WIREGUARD_HEADER_LEN: usize = 16;
+// DTLS 1.2 record header (13) and the explicit nonce of AEAD ciphers (8)
+const DTLS_APPLICATION_DATA: u8 = 23;
+const DTLS12_HEADER_LEN: usize = 13 + 8;
+// DTLS 1.3 unified header: 001CSLEE
+const DTLS13_HEADER_MASK: u8 = 0b1110_0000;
+const DTLS13_HEADER_BITS: u8 = 0b0010_0000;
+const DTLS13_CID_BIT: u8 = 0b1_0000;
+const DTLS13_SEQ_BIT: u8 = 0b1000;
+const DTLS13_LEN_BIT: u8 = 0b100;
+// RTP header (12), CSRCs (4 each), and header extension (4 + 4 * length)
+const RTP_VERSION: u8 = 2;
+const RTP_HEADER_LEN: usize = 12;
+const RTP_EXTENSION_BIT: u8 = 0b1_0000;
+const RTP_CSRC_COUNT_MASK: u8 = 0b1111;
+// RTCP packet types that share the port with RTP (RFC 5761)
+const RTCP_PACKET_TYPES: std::ops::RangeInclusive<u8> = 200..=204;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Direction {
     Incoming,
     Outgoing,
@@ -28,6 +82,328 @@ impl From<c_uchar> for Direction {
     }
 }
 
+impl std::str::FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "incoming" => Ok(Direction::Incoming),
+            "outgoing" => Ok(Direction::Outgoing),
+            _ => Err(format!("direction must be incoming or outgoing: {}", s)),
+        }
+    }
+}
+
+/// The result of validating the IPv4 and UDP headers of a sniffed frame.
+///
+/// Only a valid packet or the first fragment of a datagram has a UDP header
+/// and the start of its payload. Later fragments have neither, and a first
+/// fragment yields an identifier only if the identifier is within it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpValidation {
+    /// A complete UDP datagram
+    Valid = 0,
+    /// The first fragment of a UDP datagram
+    FirstFragment = 1,
+    /// A later fragment of a UDP datagram, without a UDP header
+    Fragment = 2,
+    /// The frame ends before the end of the IPv4 or UDP header
+    Truncated = 3,
+    /// The IPv4 version, header length, total length, or UDP length is
+    /// invalid
+    BadHeader = 4,
+    /// An IPv4 packet of another protocol
+    NotUdp = 5,
+}
+
+impl IpValidation {
+    /// Whether the packet has a UDP header and the start of its payload.
+    pub fn has_payload(&self) -> bool {
+        matches!(self, IpValidation::Valid | IpValidation::FirstFragment)
+    }
+}
+
+/// The header of a QUIC packet, from the header form and fixed bits of its
+/// first byte.
+///
+/// Only 1-RTT packets have short headers, which are followed by the encrypted
+/// payload that the identifier is parsed from. Initial, 0-RTT, Handshake, and
+/// Retry packets have long headers, with plaintext fields at `ID_OFFSET`. A
+/// short-header packet may be coalesced after long-header packets in the same
+/// datagram, but never before them, so a datagram is classified by its first
+/// packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuicHeader {
+    Short,
+    Long,
+    /// The fixed bit is not set, so this is not a QUIC packet
+    Invalid,
+}
+
+impl From<u8> for QuicHeader {
+    fn from(first_byte: u8) -> Self {
+        if first_byte & QUIC_HEADER_FORM_BIT != 0 {
+            QuicHeader::Long
+        } else if first_byte & QUIC_FIXED_BIT != 0 {
+            QuicHeader::Short
+        } else {
+            QuicHeader::Invalid
+        }
+    }
+}
+
+/// Where the pseudorandom bytes of the identifier are in the UDP payload of
+/// an encrypted protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum IdentifierProfile {
+    /// QUIC packet with a short header, after the destination connection ID
+    /// of the given length
+    Quic { dcid_len: usize },
+    /// WireGuard transport data message, after the counter
+    WireGuard,
+    /// DTLS 1.2 application data record encrypted with an AEAD cipher, after
+    /// the explicit nonce
+    Dtls12,
+    /// DTLS 1.3 record with a unified header, after the connection ID of the
+    /// given length if present and the encrypted sequence number
+    Dtls13 { cid_len: usize },
+    /// SRTP packet, after the RTP header and its extensions
+    Srtp,
+    /// Any packet, at a fixed offset in the UDP payload, e.g., selected by
+    /// calibration
+    Offset { offset: usize },
+}
+
+impl Default for IdentifierProfile {
+    fn default() -> Self {
+        IdentifierProfile::Quic {
+            dcid_len: ID_OFFSET - DCID_OFFSET,
+        }
+    }
+}
+
+impl std::str::FromStr for IdentifierProfile {
+    type Err = String;
+
+    /// Parses `quic`, `quic:<dcid_len>`, `wireguard`, `dtls1.2`, `dtls1.3`,
+    /// `dtls1.3:<cid_len>`, `srtp`, or `offset:<offset>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, len) = match s.split_once(':') {
+            Some((name, len)) => {
+                let len = len
+                    .parse::<usize>()
+                    .map_err(|_| format!("invalid profile length: {}", s))?;
+                (name, Some(len))
+            }
+            None => (s, None),
+        };
+        match (name, len) {
+            ("quic" | "dtls1.3", Some(len)) if len > MAX_CID_LEN => Err(format!(
+                "connection IDs are at most {} bytes: {}",
+                MAX_CID_LEN, s
+            )),
+            ("quic", None) => Ok(IdentifierProfile::default()),
+            ("quic", Some(dcid_len)) => Ok(IdentifierProfile::Quic { dcid_len }),
+            ("wireguard", None) => Ok(IdentifierProfile::WireGuard),
+            ("dtls1.2", None) => Ok(IdentifierProfile::Dtls12),
+            ("dtls1.3", len) => Ok(IdentifierProfile::Dtls13 {
+                cid_len: len.unwrap_or(0),
+            }),
+            ("srtp", None) => Ok(IdentifierProfile::Srtp),
+            ("offset", Some(offset)) if UDP_PAYLOAD_OFFSET + offset < BUFFER_SIZE => {
+                Ok(IdentifierProfile::Offset { offset })
+            }
+            _ => Err(format!(
+                "profile must be quic, wireguard, dtls1.2, dtls1.3, srtp, or offset: {}",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for IdentifierProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdentifierProfile::Quic { dcid_len } => write!(f, "quic:{}", dcid_len),
+            IdentifierProfile::WireGuard => write!(f, "wireguard"),
+            IdentifierProfile::Dtls12 => write!(f, "dtls1.2"),
+            IdentifierProfile::Dtls13 { cid_len } => write!(f, "dtls1.3:{}", cid_len),
+            IdentifierProfile::Srtp => write!(f, "srtp"),
+            IdentifierProfile::Offset { offset } => write!(f, "offset:{}", offset),
+        }
+    }
+}
+
+impl TryFrom<String> for IdentifierProfile {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl IdentifierProfile {
+    /// Returns the offset of the identifier in the first `n` bytes of the
+    /// buffer assuming it represents an IPv4 packet with a UDP header, or
+    /// `None` if the packet is not an encrypted packet of the protocol.
+    pub fn id_offset(&self, x: &[u8; BUFFER_SIZE], n: usize) -> Option<usize> {
+        let n = n.min(BUFFER_SIZE);
+        let first_byte = x[UDP_PAYLOAD_OFFSET];
+        let offset = match *self {
+            IdentifierProfile::Quic { dcid_len } => DCID_OFFSET + dcid_len,
+            IdentifierProfile::WireGuard => {
+                if first_byte != WIREGUARD_TRANSPORT_DATA {
+                    return None;
+                }
+                UDP_PAYLOAD_OFFSET + WIREGUARD_HEADER_LEN
+            }
+            IdentifierProfile::Dtls12 => {
+                if first_byte != DTLS_APPLICATION_DATA {
+                    return None;
+                }
+                UDP_PAYLOAD_OFFSET + DTLS12_HEADER_LEN
+            }
+            IdentifierProfile::Dtls13 { cid_len } => {
+                if first_byte & DTLS13_HEADER_MASK != DTLS13_HEADER_BITS {
+                    return None;
+                }
+                let mut len = 1;
+                if first_byte & DTLS13_CID_BIT != 0 {
+                    len += cid_len;
+                }
+                len += if first_byte & DTLS13_SEQ_BIT != 0 {
+                    2
+                } else {
+                    1
+                };
+                if first_byte & DTLS13_LEN_BIT != 0 {
+                    len += 2;
+                }
+                UDP_PAYLOAD_OFFSET + len
+            }
+            IdentifierProfile::Srtp => {
+                if first_byte >> 6 != RTP_VERSION
+                    || RTCP_PACKET_TYPES.contains(&x[UDP_PAYLOAD_OFFSET + 1])
+                {
+                    return None;
+                }
+                let csrc_count = (first_byte & RTP_CSRC_COUNT_MASK) as usize;
+                let mut offset = UDP_PAYLOAD_OFFSET + RTP_HEADER_LEN + 4 * csrc_count;
+                if first_byte & RTP_EXTENSION_BIT != 0 {
+                    if offset + 4 > n {
+                        return None;
+                    }
+                    let words = u16::from_be_bytes([x[offset + 2], x[offset + 3]]);
+                    offset += 4 + 4 * words as usize;
+                }
+                offset
+            }
+            IdentifierProfile::Offset { offset } => UDP_PAYLOAD_OFFSET + offset,
+        };
+        Some(offset)
+    }
+}
+
+/// Iterates over the UDP segments of a frame received with a virtio net
+/// header. A frame that GRO coalesced from several UDP segments of the same
+/// flow has a GSO size, and each segment is at that stride after the headers,
+/// including any IPv4 options. Any other frame is a single segment.
+#[derive(Clone, Copy, Debug)]
+pub struct GsoSegments {
+    /// Length of the received frame, including the virtio net header
+    len: usize,
+    /// Offset of the UDP payload in the frame, after the virtio net header
+    payload_offset: usize,
+    /// Length of each segment of the UDP payload, or 0 if not segmented
+    gso_size: usize,
+    /// Index of the next segment
+    next: usize,
+}
+
+impl GsoSegments {
+    pub fn new(received: &[u8]) -> Self {
+        let len = received.len();
+        if len < VNET_HDR_LEN + UDP_PAYLOAD_OFFSET {
+            return Self {
+                len,
+                payload_offset: UDP_PAYLOAD_OFFSET,
+                gso_size: 0,
+                next: 0,
+            };
+        }
+        let gso_type = received[1] & !VIRTIO_NET_HDR_GSO_ECN;
+        let gso_size = u16::from_ne_bytes([received[4], received[5]]) as usize;
+        let frame = &received[VNET_HDR_LEN..];
+        let ihl = 4 * (frame[14] & 0xf) as usize;
+        let payload_offset = ETHERNET_HEADER_LEN + ihl + UDP_HEADER_LEN;
+        let segmented = (gso_type == VIRTIO_NET_HDR_GSO_UDP
+            || gso_type == VIRTIO_NET_HDR_GSO_UDP_L4)
+            && frame[12..14] == (libc::ETH_P_IP as u16).to_be_bytes()
+            && i32::from(frame[23]) == libc::IPPROTO_UDP
+            && ihl >= IPV4_HEADER_LEN
+            && frame.len() >= payload_offset;
+        if !segmented {
+            return Self {
+                len,
+                payload_offset: UDP_PAYLOAD_OFFSET,
+                gso_size: 0,
+                next: 0,
+            };
+        }
+        Self {
+            len,
+            payload_offset,
+            gso_size,
+            next: 0,
+        }
+    }
+
+    /// Number of segments in the frame.
+    pub fn count(&self) -> usize {
+        let payload_len = self.len.saturating_sub(VNET_HDR_LEN + self.payload_offset);
+        if self.gso_size == 0 || payload_len == 0 {
+            1
+        } else {
+            payload_len.div_ceil(self.gso_size)
+        }
+    }
+
+    /// Copy the headers and the start of the next segment of the received
+    /// frame into the buffer, with the IPv4 and UDP lengths of the segment.
+    /// Returns the length of the segment as a frame, or None if there are no
+    /// more segments. Any IPv4 options are copied with the headers, to be
+    /// stripped by `UdpParser::validate`.
+    pub fn next_into(&mut self, received: &[u8], buf: &mut [u8; BUFFER_SIZE]) -> Option<usize> {
+        if self.next >= self.count() {
+            return None;
+        }
+        let received = &received[..self.len];
+        let segment = self.next;
+        self.next += 1;
+        if self.gso_size == 0 {
+            let frame = &received[VNET_HDR_LEN.min(received.len())..];
+            let n = frame.len().min(BUFFER_SIZE);
+            buf[..n].copy_from_slice(&frame[..n]);
+            return Some(frame.len());
+        }
+
+        let offset = self.payload_offset;
+        let headers = &received[VNET_HDR_LEN..VNET_HDR_LEN + offset];
+        let start = VNET_HDR_LEN + offset + segment * self.gso_size;
+        let seg_len = self.gso_size.min(received.len() - start);
+        let n = seg_len.min(BUFFER_SIZE - offset);
+        buf[..offset].copy_from_slice(headers);
+        buf[offset..offset + n].copy_from_slice(&received[start..start + n]);
+        // IPv4 total length and UDP length of the segment
+        let ip_len = (offset - ETHERNET_HEADER_LEN + seg_len) as u16;
+        let udp_len = (UDP_HEADER_LEN + seg_len) as u16;
+        buf[16..18].copy_from_slice(&ip_len.to_be_bytes());
+        buf[(offset - 4)..(offset - 2)].copy_from_slice(&udp_len.to_be_bytes());
+        Some(offset + seg_len)
+    }
+}
+
 pub struct UdpParser {
     pub src_mac: String,
     pub dst_mac: String,
@@ -76,6 +452,58 @@ impl UdpParser {
         })
     }
 
+    /// Validates the IPv4 and UDP headers of the first `n` bytes of a frame
+    /// in the buffer, assuming it represents an IPv4 packet. Strips any IPv4
+    /// options from a packet with a UDP header, so that the UDP header and
+    /// payload are at their usual offsets. Returns the validation result and
+    /// the length of the frame without options.
+    pub fn validate(x: &mut [u8; BUFFER_SIZE], n: isize) -> (IpValidation, isize) {
+        let n = n.max(0) as usize;
+        if n < ETHERNET_HEADER_LEN + IPV4_HEADER_LEN {
+            return (IpValidation::Truncated, n as isize);
+        }
+        let version = x[14] >> 4;
+        let ihl = 4 * (x[14] & 0xf) as usize;
+        let total_len = Self::parse_ip_len(x) as usize;
+        if version != 4 || ihl < IPV4_HEADER_LEN || total_len < ihl {
+            return (IpValidation::BadHeader, n as isize);
+        }
+        if i32::from(x[23]) != libc::IPPROTO_UDP {
+            return (IpValidation::NotUdp, n as isize);
+        }
+        let fragment = u16::from_be_bytes([x[20], x[21]]);
+        if fragment & IPV4_FRAGMENT_OFFSET_MASK != 0 {
+            return (IpValidation::Fragment, n as isize);
+        }
+        if n < ETHERNET_HEADER_LEN + ihl + UDP_HEADER_LEN {
+            return (IpValidation::Truncated, n as isize);
+        }
+        let more_fragments = fragment & IPV4_MORE_FRAGMENTS != 0;
+        let udp = ETHERNET_HEADER_LEN + ihl;
+        let udp_len = u16::from_be_bytes([x[udp + 4], x[udp + 5]]) as usize;
+        // The UDP length of a first fragment is that of the whole datagram.
+        if udp_len < UDP_HEADER_LEN || (!more_fragments && udp_len > total_len - ihl) {
+            return (IpValidation::BadHeader, n as isize);
+        }
+
+        // Only the bytes of the frame that are in the buffer remain after the
+        // options are stripped.
+        let options_len = ihl - IPV4_HEADER_LEN;
+        let n = if options_len > 0 {
+            x.copy_within(udp.., ETHERNET_HEADER_LEN + IPV4_HEADER_LEN);
+            x[14] = (x[14] & 0xf0) | (IPV4_HEADER_LEN / 4) as u8;
+            x[16..18].copy_from_slice(&((total_len - options_len) as u16).to_be_bytes());
+            n.min(BUFFER_SIZE) - options_len
+        } else {
+            n
+        } as isize;
+        if more_fragments {
+            (IpValidation::FirstFragment, n)
+        } else {
+            (IpValidation::Valid, n)
+        }
+    }
+
     /// Returns True if and only if the buffer represents a UDP packet.
     pub fn is_udp(x: &[u8; BUFFER_SIZE]) -> bool {
         let ip_protocol = x[23];
@@ -87,6 +515,49 @@ impl UdpParser {
         &x[30..34]
     }
 
+    /// Returns the IP total length assuming the buffer represents an IPv4
+    /// packet.
+    pub fn parse_ip_len(x: &[u8; BUFFER_SIZE]) -> u16 {
+        u16::from_be_bytes([x[16], x[17]])
+    }
+
+    /// Returns the UDP dst port assuming the buffer represents a UDP packet.
+    pub fn parse_dst_port(x: &[u8; BUFFER_SIZE]) -> u16 {
+        u16::from_be_bytes([x[36], x[37]])
+    }
+
+    /// Returns the first byte of the UDP payload assuming the buffer
+    /// represents an IPv4 packet with a UDP header, e.g., the flags of a QUIC
+    /// packet.
+    pub fn parse_first_byte(x: &[u8; BUFFER_SIZE]) -> u8 {
+        x[UDP_PAYLOAD_OFFSET]
+    }
+
+    /// Returns the header of the first QUIC packet in the UDP payload
+    /// assuming the buffer represents an IPv4 packet with a UDP header.
+    pub fn parse_quic_header(x: &[u8; BUFFER_SIZE]) -> QuicHeader {
+        Self::parse_first_byte(x).into()
+    }
+
+    /// Returns the `len`-byte destination connection ID assuming the buffer
+    /// represents a QUIC UDP packet with a short header, where `len` is at
+    /// most `MAX_CID_LEN`.
+    pub fn parse_dcid(x: &[u8; BUFFER_SIZE], len: usize) -> &[u8] {
+        &x[DCID_OFFSET..DCID_OFFSET + len]
+    }
+
+    /// Returns the ECN codepoint assuming the buffer represents an IPv4
+    /// packet.
+    pub fn parse_ecn(x: &[u8; BUFFER_SIZE]) -> u8 {
+        x[15] & ECN_MASK
+    }
+
+    /// Returns True if and only if the IPv4 packet is marked Congestion
+    /// Experienced.
+    pub fn is_ce(x: &[u8; BUFFER_SIZE]) -> bool {
+        Self::parse_ecn(x) == ECN_CE
+    }
+
     /// src_ip, src_port, dst_ip, dst_port
     pub fn parse_addr_key(x: &[u8; BUFFER_SIZE]) -> [u8; 12] {
         [
@@ -94,14 +565,238 @@ impl UdpParser {
         ]
     }
 
-    /// Returns the sidekick identifier assuming the buffer represents
-    /// a QUIC UDP packet.
-    pub fn parse_identifier(x: &[u8; BUFFER_SIZE]) -> u32 {
-        u32::from_be_bytes([
-            x[ID_OFFSET],
-            x[ID_OFFSET + 1],
-            x[ID_OFFSET + 2],
-            x[ID_OFFSET + 3],
-        ])
+    /// Returns the `bits`-bit sidekick identifier in the first `n` bytes of
+    /// the buffer assuming it represents a UDP packet of the profile's
+    /// protocol, where `bits` is a multiple of 8. Returns `None` if the packet
+    /// is not an encrypted packet of the protocol, or is too short.
+    pub fn parse_identifier_profile(
+        x: &[u8; BUFFER_SIZE],
+        n: usize,
+        bits: usize,
+        profile: &IdentifierProfile,
+    ) -> Option<u64> {
+        let start = profile.id_offset(x, n)?;
+        let end = start + bits / 8;
+        if end > n.min(BUFFER_SIZE) {
+            return None;
+        }
+        Some(
+            x[start..end]
+                .iter()
+                .fold(0, |id, &byte| (id << 8) | byte as u64),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An Ethernet frame of an IPv4 packet with `options_len` bytes of IPv4
+    /// options and a UDP payload of the bytes 0, 1, 2, ...
+    fn udp_frame(options_len: usize, payload_len: usize) -> Vec<u8> {
+        let ihl = IPV4_HEADER_LEN + options_len;
+        let udp = ETHERNET_HEADER_LEN + ihl;
+        let mut frame = vec![0; udp + UDP_HEADER_LEN + payload_len];
+        frame[12..14].copy_from_slice(&(libc::ETH_P_IP as u16).to_be_bytes());
+        frame[14] = 0x40 | (ihl / 4) as u8;
+        let ip_len = (ihl + UDP_HEADER_LEN + payload_len) as u16;
+        frame[16..18].copy_from_slice(&ip_len.to_be_bytes());
+        frame[23] = libc::IPPROTO_UDP as u8;
+        let udp_len = (UDP_HEADER_LEN + payload_len) as u16;
+        frame[(udp + 4)..(udp + 6)].copy_from_slice(&udp_len.to_be_bytes());
+        for (i, byte) in frame[(udp + UDP_HEADER_LEN)..].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        frame
+    }
+
+    /// The frame as received after a virtio net header with the GSO type and
+    /// size.
+    fn received(gso_type: u8, gso_size: u16, frame: &[u8]) -> Vec<u8> {
+        let mut received = vec![0; VNET_HDR_LEN];
+        received[1] = gso_type;
+        received[4..6].copy_from_slice(&gso_size.to_ne_bytes());
+        received.extend_from_slice(frame);
+        received
+    }
+
+    /// The length and buffer of each segment of the received frame.
+    fn split(received: &[u8]) -> Vec<(usize, [u8; BUFFER_SIZE])> {
+        let mut segments = GsoSegments::new(received);
+        let mut buf = [0; BUFFER_SIZE];
+        let mut result = vec![];
+        while let Some(n) = segments.next_into(received, &mut buf) {
+            result.push((n, buf));
+        }
+        assert_eq!(result.len(), segments.count());
+        result
+    }
+
+    /// Check that the segment is a valid UDP packet with a payload of the
+    /// given length, which starts with the given byte.
+    fn check_segment(segment: (usize, [u8; BUFFER_SIZE]), seg_len: usize, first_byte: u8) {
+        let (n, mut buf) = segment;
+        let (validation, _) = UdpParser::validate(&mut buf, n as isize);
+        assert_eq!(validation, IpValidation::Valid);
+        let ip_len = UdpParser::parse_ip_len(&buf) as usize;
+        assert_eq!(ip_len, IPV4_HEADER_LEN + UDP_HEADER_LEN + seg_len);
+        let udp_len = u16::from_be_bytes([buf[38], buf[39]]) as usize;
+        assert_eq!(udp_len, UDP_HEADER_LEN + seg_len);
+        assert_eq!(buf[UDP_PAYLOAD_OFFSET], first_byte);
+    }
+
+    #[test]
+    fn test_gso_segments() {
+        let frame = udp_frame(0, 250);
+        for gso_type in [
+            VIRTIO_NET_HDR_GSO_UDP,
+            VIRTIO_NET_HDR_GSO_UDP_L4,
+            VIRTIO_NET_HDR_GSO_UDP_L4 | VIRTIO_NET_HDR_GSO_ECN,
+        ] {
+            let segments = split(&received(gso_type, 100, &frame));
+            assert_eq!(segments.len(), 3);
+            assert_eq!(segments[2].0, UDP_PAYLOAD_OFFSET + 50);
+            check_segment(segments[0], 100, 0);
+            check_segment(segments[1], 100, 100);
+            check_segment(segments[2], 50, 200);
+        }
+    }
+
+    #[test]
+    fn test_gso_segments_ipv4_options() {
+        // IHL of 7, for 8 bytes of options
+        let frame = udp_frame(8, 250);
+        let segments = split(&received(VIRTIO_NET_HDR_GSO_UDP_L4, 100, &frame));
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[0].0, UDP_PAYLOAD_OFFSET + 8 + 100);
+        assert_eq!(segments[0].1[14], 0x47);
+        check_segment(segments[0], 100, 0);
+        check_segment(segments[1], 100, 100);
+        check_segment(segments[2], 50, 200);
+    }
+
+    #[test]
+    fn test_gso_segments_unsegmented() {
+        // Not coalesced by GRO
+        let frame = udp_frame(0, 250);
+        let segments = split(&received(0, 100, &frame));
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].0, frame.len());
+        assert_eq!(&segments[0].1[..], &frame[..BUFFER_SIZE]);
+
+        // Not UDP
+        let mut frame = udp_frame(0, 250);
+        frame[23] = libc::IPPROTO_TCP as u8;
+        let segments = split(&received(VIRTIO_NET_HDR_GSO_UDP_L4, 100, &frame));
+        assert_eq!(segments.len(), 1);
+
+        // Shorter than the headers
+        let frame = udp_frame(0, 0);
+        let received = received(VIRTIO_NET_HDR_GSO_UDP_L4, 100, &frame[..30]);
+        let segments = split(&received);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].0, 30);
+    }
+
+    /// The first `BUFFER_SIZE` bytes of the frame, and its length.
+    fn sniffed(frame: &[u8]) -> ([u8; BUFFER_SIZE], isize) {
+        let mut buf = [0; BUFFER_SIZE];
+        let n = frame.len().min(BUFFER_SIZE);
+        buf[..n].copy_from_slice(&frame[..n]);
+        (buf, frame.len() as isize)
+    }
+
+    /// The validation result of the frame after the change.
+    fn validate(frame: &[u8], change: impl FnOnce(&mut [u8; BUFFER_SIZE])) -> IpValidation {
+        let (mut buf, n) = sniffed(frame);
+        change(&mut buf);
+        UdpParser::validate(&mut buf, n).0
+    }
+
+    #[test]
+    fn test_validate() {
+        let frame = udp_frame(0, 50);
+        let (mut buf, n) = sniffed(&frame);
+        assert_eq!(UdpParser::validate(&mut buf, n), (IpValidation::Valid, n));
+        assert_eq!(&buf[..frame.len()], &frame[..]);
+    }
+
+    #[test]
+    fn test_validate_ipv4_options() {
+        // IHL of 6, for 4 bytes of options
+        let frame = udp_frame(4, 200);
+        let (mut buf, n) = sniffed(&frame);
+        let (validation, n) = UdpParser::validate(&mut buf, n);
+        assert_eq!(validation, IpValidation::Valid);
+        // Only the bytes in the buffer remain
+        assert_eq!(n as usize, BUFFER_SIZE - 4);
+        assert_eq!(buf[14], 0x45);
+        let ip_len = UdpParser::parse_ip_len(&buf) as usize;
+        assert_eq!(ip_len, IPV4_HEADER_LEN + UDP_HEADER_LEN + 200);
+        let udp_len = u16::from_be_bytes([buf[38], buf[39]]) as usize;
+        assert_eq!(udp_len, UDP_HEADER_LEN + 200);
+        assert_eq!(buf[UDP_PAYLOAD_OFFSET..][..4], [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_validate_truncated() {
+        let frame = udp_frame(0, 50);
+        for n in [-1, 0, 33, 34, 41] {
+            let (mut buf, _) = sniffed(&frame);
+            let (validation, _) = UdpParser::validate(&mut buf, n);
+            assert_eq!(validation, IpValidation::Truncated, "{}", n);
+        }
+        // Options push the UDP header past the end of the frame
+        let frame = udp_frame(8, 50);
+        let (mut buf, _) = sniffed(&frame);
+        let (validation, _) = UdpParser::validate(&mut buf, 49);
+        assert_eq!(validation, IpValidation::Truncated);
+        let (validation, _) = UdpParser::validate(&mut buf, 50);
+        assert_eq!(validation, IpValidation::Valid);
+    }
+
+    #[test]
+    fn test_validate_bad_header() {
+        let frame = udp_frame(0, 50);
+        let with_len = |i: usize, len: u16| {
+            validate(&frame, |x| {
+                x[i..(i + 2)].copy_from_slice(&len.to_be_bytes())
+            })
+        };
+        // IPv6
+        assert_eq!(validate(&frame, |x| x[14] = 0x65), IpValidation::BadHeader);
+        // IHL below 5
+        assert_eq!(validate(&frame, |x| x[14] = 0x44), IpValidation::BadHeader);
+        // IP total length shorter than the header
+        assert_eq!(with_len(16, 19), IpValidation::BadHeader);
+        // UDP length shorter than the header
+        assert_eq!(with_len(38, 7), IpValidation::BadHeader);
+        // UDP length longer than the IP payload
+        assert_eq!(with_len(38, 59), IpValidation::BadHeader);
+        assert_eq!(with_len(38, 58), IpValidation::Valid);
+    }
+
+    #[test]
+    fn test_validate_not_udp() {
+        let frame = udp_frame(0, 50);
+        let tcp = validate(&frame, |x| x[23] = libc::IPPROTO_TCP as u8);
+        assert_eq!(tcp, IpValidation::NotUdp);
+    }
+
+    #[test]
+    fn test_validate_fragments() {
+        let frame = udp_frame(0, 50);
+        // More fragments, whose UDP length is that of the whole datagram
+        let first = validate(&frame, |x| {
+            x[20] = 0x20;
+            x[38..40].copy_from_slice(&1000u16.to_be_bytes());
+        });
+        assert_eq!(first, IpValidation::FirstFragment);
+        assert!(first.has_payload());
+        // Fragment offset of 8 bytes
+        let later = validate(&frame, |x| x[21] = 1);
+        assert_eq!(later, IpValidation::Fragment);
+        assert!(!later.has_payload());
     }
 }
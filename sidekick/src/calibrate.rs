@@ -0,0 +1,359 @@
+//! Calibration of where the identifier is in the packets of an encrypted
+//! protocol, from the entropy and uniqueness of the bytes at each offset of
+//! the UDP payload.
+//!
+//! A wrong offset silently produces quACKs that never decode, e.g., if the
+//! identifier overlaps a connection ID or a sequence number. Calibration
+//! sniffs packets of each flow and recommends the earliest window of
+//! identifier bytes that looks uniformly random. At runtime, `IdMonitor`
+//! checks samples of the identifiers of a flow and warns if they do not.
+use std::collections::{HashMap, HashSet};
+
+use log::{debug, info};
+
+use crate::buffer::{Direction, IdentifierProfile, UdpParser, BUFFER_SIZE, UDP_PAYLOAD_OFFSET};
+use crate::filter::FlowFilter;
+use crate::socket::SockAddr;
+use crate::Socket;
+
+/// Minimum entropy of each identifier byte, normalized by the maximum for the
+/// number of packets, for the identifiers to look uniformly random.
+pub const MIN_ENTROPY: f64 = 0.9;
+/// Minimum fraction of unique identifiers for the identifiers to look
+/// uniformly random.
+pub const MIN_UNIQUENESS: f64 = 0.95;
+/// Minimum number of packets of a flow to calibrate.
+pub const MIN_PACKETS: usize = 32;
+/// Number of identifiers of a flow in each sample checked by `IdMonitor`.
+pub const MONITOR_SAMPLE_SIZE: usize = 128;
+
+/// How random the identifiers at an offset of the UDP payload of a flow look.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OffsetStats {
+    /// Offset in the UDP payload
+    pub offset: usize,
+    /// Number of packets long enough to have an identifier at the offset
+    pub packets: usize,
+    /// Minimum normalized entropy of any byte of the identifiers
+    pub entropy: f64,
+    /// Fraction of the identifiers that are unique
+    pub uniqueness: f64,
+}
+
+impl OffsetStats {
+    /// Whether the identifiers look uniformly random.
+    pub fn looks_random(&self) -> bool {
+        self.packets >= MIN_PACKETS
+            && self.entropy >= MIN_ENTROPY
+            && self.uniqueness >= MIN_UNIQUENESS
+    }
+}
+
+/// The UDP payloads of the sniffed packets of a flow.
+#[derive(Clone, Debug, Default)]
+pub struct FlowSamples {
+    pub payloads: Vec<Vec<u8>>,
+}
+
+impl FlowSamples {
+    /// How random the `id_len`-byte identifiers at each offset look.
+    pub fn stats(&self, id_len: usize) -> Vec<OffsetStats> {
+        let max_len = self.payloads.iter().map(Vec::len).max().unwrap_or(0);
+        (0..(max_len + 1).saturating_sub(id_len))
+            .map(|offset| {
+                let ids = self
+                    .payloads
+                    .iter()
+                    .filter(|payload| payload.len() >= offset + id_len)
+                    .map(|payload| &payload[offset..offset + id_len])
+                    .collect::<Vec<_>>();
+                let entropy = (0..id_len)
+                    .map(|i| byte_entropy(ids.iter().map(|id| id[i])))
+                    .fold(f64::INFINITY, f64::min);
+                let unique = ids.iter().collect::<HashSet<_>>().len();
+                OffsetStats {
+                    offset,
+                    packets: ids.len(),
+                    entropy,
+                    uniqueness: unique as f64 / ids.len().max(1) as f64,
+                }
+            })
+            .collect()
+    }
+
+    /// The earliest offset whose `id_len`-byte identifiers look uniformly
+    /// random, if any.
+    pub fn recommend(&self, id_len: usize) -> Option<OffsetStats> {
+        self.stats(id_len)
+            .into_iter()
+            .find(OffsetStats::looks_random)
+    }
+}
+
+/// Shannon entropy of the bytes, normalized by the maximum entropy of that
+/// many bytes, up to 1. The entropy of the observed frequencies underestimates
+/// that of the distribution, e.g., by 10% for 256 uniformly random bytes, so
+/// it has the Miller-Madow correction.
+fn byte_entropy(bytes: impl Iterator<Item = u8>) -> f64 {
+    let mut counts = [0usize; 256];
+    let mut n = 0;
+    for byte in bytes {
+        counts[byte as usize] += 1;
+        n += 1;
+    }
+    if n < 2 {
+        return 0.0;
+    }
+    let entropy: f64 = counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / n as f64;
+            -p * p.log2()
+        })
+        .sum();
+    let symbols = counts.iter().filter(|&&count| count > 0).count();
+    let correction = (symbols - 1) as f64 / (2.0 * n as f64 * std::f64::consts::LN_2);
+    ((entropy + correction) / (n.min(256) as f64).log2()).min(1.0)
+}
+
+/// Sniff the UDP packets in the direction on the interface, of the flows that
+/// the filter allows, until a flow has `packets` packets. Returns the UDP
+/// payloads of the packets of each flow.
+pub fn sniff(
+    interface: &str,
+    direction: Direction,
+    filter: &FlowFilter,
+    packets: usize,
+) -> Result<HashMap<[u8; 12], FlowSamples>, String> {
+    let mut sock = Socket::new(interface.to_string())?;
+    sock.set_promiscuous()?;
+    info!("calibrating on {} packets of a flow", packets);
+    let mut addr = SockAddr::new_sockaddr_ll();
+    let mut buf: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+    let mut flows: HashMap<[u8; 12], FlowSamples> = HashMap::new();
+    loop {
+        let n = sock.recvfrom(&mut addr, &mut buf)?;
+        if direction != addr.sll_pkttype.into()
+            || addr.sll_protocol != (libc::ETH_P_IP as u16).to_be()
+        {
+            continue;
+        }
+        let (validation, n) = UdpParser::validate(&mut buf, n);
+        if !validation.has_payload() {
+            continue;
+        }
+        let addr_key = UdpParser::parse_addr_key(&buf);
+        if !filter.allows(&addr_key, UdpParser::parse_first_byte(&buf)) {
+            continue;
+        }
+        let end = (n as usize).clamp(UDP_PAYLOAD_OFFSET, BUFFER_SIZE);
+        let flow = flows.entry(addr_key).or_default();
+        flow.payloads.push(buf[UDP_PAYLOAD_OFFSET..end].to_vec());
+        if flow.payloads.len() >= packets {
+            return Ok(flows);
+        }
+    }
+}
+
+/// Sniff until a flow has `packets` packets, and select the earliest offset
+/// at which the `bits`-bit identifiers of the busiest flow look uniformly
+/// random.
+pub fn select_profile(
+    interface: &str,
+    direction: Direction,
+    filter: &FlowFilter,
+    packets: usize,
+    bits: usize,
+) -> Result<IdentifierProfile, String> {
+    let flows = sniff(interface, direction, filter, packets)?;
+    let (addr_key, samples) = flows
+        .iter()
+        .max_by_key(|(_, samples)| samples.payloads.len())
+        .ok_or("no packets to calibrate")?;
+    let stats = samples
+        .recommend(bits / 8)
+        .ok_or(format!("no identifiers look random in flow {:?}", addr_key))?;
+    info!("selected offset {} in flow {:?}", stats.offset, addr_key);
+    debug!("{:?}", stats);
+    Ok(IdentifierProfile::Offset {
+        offset: stats.offset,
+    })
+}
+
+/// Checks samples of the identifiers of a flow as they are inserted, for more
+/// duplicates than expected of uniformly random identifiers, or bits that are
+/// usually the same.
+#[derive(Clone, Debug)]
+pub struct IdMonitor {
+    bits: usize,
+    ids: Vec<u64>,
+}
+
+impl IdMonitor {
+    pub fn new(bits: usize) -> Self {
+        Self {
+            bits,
+            ids: Vec::with_capacity(MONITOR_SAMPLE_SIZE),
+        }
+    }
+
+    /// Insert an identifier. Returns a warning if the sample is full and its
+    /// identifiers do not look uniformly random.
+    pub fn insert(&mut self, id: u64) -> Option<String> {
+        self.ids.push(id);
+        if self.ids.len() < MONITOR_SAMPLE_SIZE {
+            return None;
+        }
+        let n = self.ids.len();
+        let biased_bits = (0..self.bits)
+            .filter(|bit| {
+                let ones = self.ids.iter().filter(|id| (*id >> bit) & 1 == 1).count();
+                ones * 4 < n || ones * 4 > n * 3
+            })
+            .count();
+        self.ids.sort_unstable();
+        self.ids.dedup();
+        let duplicates = n - self.ids.len();
+        self.ids.clear();
+
+        // Expected number of colliding pairs of uniformly random identifiers
+        let expected = (n * (n - 1) / 2) as f64 / 2f64.powi(self.bits as i32);
+        if duplicates as f64 > 2.0 * expected + 1.0 {
+            Some(format!(
+                "{} of {} identifiers are duplicates, check the profile",
+                duplicates, n
+            ))
+        } else if biased_bits > 0 {
+            Some(format!(
+                "{} of {} identifier bits are biased, check the profile",
+                biased_bits, self.bits
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Length of the constant header of each payload, e.g., the first byte
+    /// and connection ID of a QUIC packet with a short header.
+    const HEADER_LEN: usize = 9;
+
+    /// The UDP payloads of a flow whose packets have a constant header
+    /// followed by random bytes.
+    fn samples(packets: usize, len: usize) -> FlowSamples {
+        let mut rng = StdRng::seed_from_u64(packets as u64);
+        let payloads = (0..packets)
+            .map(|_| {
+                let mut payload = vec![0x40, 1, 2, 3, 4, 5, 6, 7, 8];
+                payload.extend((HEADER_LEN..len).map(|_| rng.gen::<u8>()));
+                payload
+            })
+            .collect();
+        FlowSamples { payloads }
+    }
+
+    #[test]
+    fn test_byte_entropy() {
+        assert_eq!(byte_entropy(std::iter::empty()), 0.0);
+        assert_eq!(byte_entropy(std::iter::once(7)), 0.0);
+        assert_eq!(byte_entropy([7; 100].into_iter()), 0.0);
+        assert_eq!(byte_entropy(0..=255), 1.0);
+        // One bit of the eight of 256 bytes
+        let entropy = byte_entropy((0..256).map(|i| (i % 2) as u8));
+        assert!((0.12..0.13).contains(&entropy), "{}", entropy);
+    }
+
+    #[test]
+    fn test_byte_entropy_uniform() {
+        // The uncorrected entropy of around 256 random bytes is usually below
+        // the minimum.
+        for n in [MIN_PACKETS, 200, 256, 300, 1000] {
+            for seed in 0..20 {
+                let mut rng = StdRng::seed_from_u64(seed);
+                let entropy = byte_entropy((0..n).map(|_| rng.gen::<u8>()));
+                assert!(entropy >= MIN_ENTROPY, "n={} entropy={}", n, entropy);
+            }
+        }
+    }
+
+    #[test]
+    fn test_recommend() {
+        let samples = samples(64, 40);
+        let stats = samples.stats(4);
+        assert_eq!(stats.len(), 40 - 4 + 1);
+        assert_eq!(stats[0].entropy, 0.0);
+        assert_eq!(stats[0].uniqueness, 1.0 / 64.0);
+        // Overlaps the constant header
+        assert!(!stats[HEADER_LEN - 1].looks_random());
+        let recommended = samples.recommend(4).unwrap();
+        assert_eq!(recommended.offset, HEADER_LEN);
+        assert_eq!(recommended.packets, 64);
+        assert_eq!(recommended.uniqueness, 1.0);
+        assert!(recommended.entropy >= MIN_ENTROPY);
+    }
+
+    #[test]
+    fn test_recommend_short_packets() {
+        // Packets too short for an identifier at an offset are skipped.
+        let mut samples = samples(64, 40);
+        samples.payloads.extend(vec![vec![0x40; 12]; 64]);
+        let stats = samples.stats(4);
+        assert_eq!(stats[HEADER_LEN - 1].packets, 128);
+        assert_eq!(stats[HEADER_LEN].packets, 64);
+        assert_eq!(samples.recommend(4).unwrap().offset, HEADER_LEN);
+    }
+
+    #[test]
+    fn test_recommend_too_few_packets() {
+        assert_eq!(samples(MIN_PACKETS - 1, 40).recommend(4), None);
+        assert_eq!(FlowSamples::default().recommend(4), None);
+    }
+
+    #[test]
+    fn test_id_monitor() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut monitor = IdMonitor::new(32);
+        for _ in 0..(10 * MONITOR_SAMPLE_SIZE) {
+            assert_eq!(monitor.insert(rng.gen::<u32>() as u64), None);
+        }
+    }
+
+    #[test]
+    fn test_id_monitor_duplicates() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut monitor = IdMonitor::new(32);
+        for _ in 0..(MONITOR_SAMPLE_SIZE / 2 - 1) {
+            let id = rng.gen::<u32>() as u64;
+            assert_eq!(monitor.insert(id), None);
+            assert_eq!(monitor.insert(id), None);
+        }
+        let id = rng.gen::<u32>() as u64;
+        assert_eq!(monitor.insert(id), None);
+        let warning = monitor.insert(id).unwrap();
+        assert!(warning.starts_with("64 of 128 identifiers are duplicates"));
+    }
+
+    #[test]
+    fn test_id_monitor_biased() {
+        // Sequence numbers, whose high bits are always 0
+        let mut monitor = IdMonitor::new(32);
+        for id in 0..(MONITOR_SAMPLE_SIZE as u64 - 1) {
+            assert_eq!(monitor.insert(id), None);
+        }
+        let warning = monitor.insert(MONITOR_SAMPLE_SIZE as u64).unwrap();
+        assert!(
+            warning.contains("identifier bits are biased"),
+            "{}",
+            warning
+        );
+        // The next sample starts empty.
+        assert_eq!(monitor.insert(0), None);
+    }
+}
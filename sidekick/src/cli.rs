@@ -0,0 +1,984 @@
+//! Command-line interface of the `sidekick` binary, whose subcommands share
+//! the same options and defaults. The `sender`, `sender_multi`, and
+//! `sender_strawman_*` binaries are thin aliases for its subcommands.
+//! Deployments are configured with a file instead, see `config`.
+use std::fmt::Debug;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{Args, Parser, Subcommand};
+use log::{debug, info, warn};
+use quack::{PowerSumQuackU16, PowerSumQuackU32, PowerSumQuackU64};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::buffer::{Direction, IdentifierProfile, MAX_CID_LEN};
+use crate::calibrate;
+use crate::config::{self, Config, Rule};
+use crate::control;
+use crate::encoder::{self, QuackSink, SniffConfig, StrawmanAEncoder, StrawmanBEncoder, Style};
+use crate::encoding::{self, WireQuack};
+use crate::events::{EventLog, EventLogConfig};
+use crate::filter::{Cidr, FlowFilter, FlowMatch, PortRange};
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::sidekick_multi::{
+    listen_for_acks_multi, send_rule_quacks_ms, start_sidekick_multi,
+    start_sidekick_multi_frequency_pkts,
+};
+use crate::stats;
+use crate::{QuackEncoder, Sidekick, SidekickMulti};
+
+/// Default number of identifiers in a strawman B quACK.
+pub const DEFAULT_WINDOW_SIZE: usize = 20;
+
+/// Sends quACKs in the sidekick protocol, receives data in the base protocol.
+#[derive(Parser)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Sniff packets on an interface and quACK them with a power sum quACK.
+    Run(RunArgs),
+    /// Sniff packets on an interface and quACK each flow to a destination.
+    RunMulti(RunMultiArgs),
+    /// Sniff packets on an interface and quACK them with a strawman quACK.
+    Strawman(StrawmanArgs),
+    /// Replay packets captured in a pcap file into a quACK.
+    Replay(ReplayArgs),
+    /// Receive quACKs on a UDP socket and print their contents.
+    Inspect(InspectArgs),
+    /// Sniff packets on an interface and quACK flows as configured in a file.
+    Deploy(DeployArgs),
+    /// Sniff packets on an interface and recommend where the identifier is.
+    Calibrate(CalibrateArgs),
+}
+
+/// Options of a quACK.
+#[derive(Args, Clone, Debug)]
+pub struct QuackArgs {
+    /// The threshold number of missing packets, up to 255.
+    #[arg(long, short = 't', default_value_t = 20, value_parser = parse_threshold)]
+    pub threshold: usize,
+    /// Number of identifier bits: 16, 24, 32, or 64.
+    #[arg(long = "bits", short = 'b', default_value_t = 32)]
+    pub num_bits_id: usize,
+    /// Frequency at which to quack, in ms. If frequency is 0, does not quack.
+    #[arg(long = "frequency-ms")]
+    pub frequency_ms: Option<u64>,
+    /// Frequency at which to quack, in packets.
+    #[arg(long = "frequency-pkts", value_parser = clap::value_parser!(u64).range(1..))]
+    pub frequency_pkts: Option<u64>,
+    /// Address of the socket to quack to e.g., <IP:PORT>. If missing, goes
+    /// to stdout.
+    #[arg(long = "quack-addr", visible_alias = "target-addr", alias = "addr")]
+    pub quack_addr: Option<SocketAddr>,
+    /// QuACK over a TCP connection to the quACK address instead of UDP.
+    #[arg(long)]
+    pub tcp: bool,
+}
+
+impl QuackArgs {
+    /// Connect to the quACK address, if any.
+    async fn sink(&self) -> Result<Option<QuackSink>, String> {
+        match self.quack_addr {
+            Some(addr) if self.tcp => {
+                info!("quACKing over TCP to {:?}", addr);
+                QuackSink::tcp(addr).await.map(Some)
+            }
+            Some(addr) => {
+                info!("quACKing to {:?}", addr);
+                QuackSink::udp(addr).await.map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Options of the packets to sniff.
+#[derive(Args, Clone, Debug)]
+pub struct SniffArgs {
+    /// Interface to listen on e.g., `eth1'.
+    #[arg(long, short = 'i')]
+    pub interface: String,
+    /// Direction of the packets to quACK: incoming, or outgoing when deployed
+    /// on the data sender's host or egress router (default: incoming).
+    #[arg(long)]
+    pub direction: Option<Direction>,
+    /// My IPv4 address to receive quACK resets.
+    #[arg(long = "my-ip", alias = "my-addr")]
+    pub my_ip: Option<Ipv4Addr>,
+    /// Only quACK QUIC packets, whose fixed bit is set, instead of every UDP
+    /// packet on the interface.
+    #[arg(long = "quic-only")]
+    pub quic_only: bool,
+    /// Only quACK QUIC packets with short headers, skipping handshake packets
+    /// with long headers and the datagrams they are coalesced in.
+    #[arg(long = "short-headers-only")]
+    pub short_headers_only: bool,
+    /// Where the identifier is in the packets of the encrypted protocol:
+    /// quic, quic:<dcid_len>, wireguard, dtls1.2, dtls1.3, dtls1.3:<cid_len>,
+    /// srtp, or offset:<n> for any packet at n bytes into the UDP payload,
+    /// e.g., as recommended by `sidekick calibrate`.
+    #[arg(long, default_value = "quic")]
+    pub profile: IdentifierProfile,
+    /// Sniff this many packets of a flow first, and select the earliest
+    /// offset whose identifiers look uniformly random instead of `--profile`.
+    #[arg(long)]
+    pub calibrate: Option<usize>,
+    /// Warn if the identifiers do not look uniformly random.
+    #[arg(long = "check-ids")]
+    pub check_ids: bool,
+    /// Number of recent identifiers of a flow to detect collisions with, or 0
+    /// to not detect collisions.
+    #[arg(long = "collision-window", default_value_t = 0)]
+    pub collision_window: usize,
+    /// Split frames that GRO coalesced from several UDP datagrams into their
+    /// segments, so that each datagram is quACKed.
+    #[arg(long)]
+    pub gso: bool,
+}
+
+impl SniffArgs {
+    /// Direction of the packets to sniff, incoming unless specified.
+    pub fn direction(&self) -> Direction {
+        self.direction.unwrap_or(Direction::Incoming)
+    }
+
+    /// Which flows to quACK, of those that match `flows`.
+    pub fn filter(&self, flows: FlowMatch) -> FlowFilter {
+        if !self.quic_only && flows == FlowMatch::default() {
+            return FlowFilter::default();
+        }
+        FlowFilter::allow_only(FlowMatch {
+            quic: self.quic_only,
+            ..flows
+        })
+    }
+
+    /// Where the identifier is, calibrated from the flows that the filter
+    /// allows if enabled.
+    pub async fn profile(
+        &self,
+        filter: FlowFilter,
+        bits: usize,
+    ) -> Result<IdentifierProfile, String> {
+        let packets = match self.calibrate {
+            Some(packets) => packets,
+            None => return Ok(self.profile),
+        };
+        let interface = self.interface.clone();
+        let direction = self.direction();
+        tokio::task::spawn_blocking(move || {
+            calibrate::select_profile(&interface, direction, &filter, packets, bits)
+        })
+        .await
+        .map_err(|e| format!("error calibrating: {:?}", e))?
+    }
+}
+
+/// Parses a threshold that fits in a quACK message.
+fn parse_threshold(s: &str) -> Result<usize, String> {
+    let threshold = s.parse().map_err(|e| format!("{}", e))?;
+    config::validate_threshold(threshold)?;
+    Ok(threshold)
+}
+
+/// Options of the extensions and variants of a power sum quACK.
+#[derive(Args, Clone, Debug)]
+pub struct PowerSumArgs {
+    /// Number of power sums to include in each quACK, if fewer than the
+    /// threshold.
+    #[arg(long = "num-sums")]
+    pub num_sums: Option<usize>,
+    /// Include the capture time of the last packet and the emission time in
+    /// each quACK, for near-path RTT estimation.
+    #[arg(long)]
+    pub timestamps: bool,
+    /// Include the cumulative number of IP bytes received in each quACK, for
+    /// near-path goodput estimation.
+    #[arg(long = "byte-counts")]
+    pub byte_counts: bool,
+    /// Include the cumulative number of packets received with the ECN
+    /// Congestion Experienced codepoint in each quACK.
+    #[arg(long = "ce-counts")]
+    pub ce_counts: bool,
+    /// Number of most recent identifiers in each quACK, for sliding-window
+    /// quACKs that expire old identifiers instead of needing resets. If
+    /// missing, quACKs are cumulative.
+    #[arg(long)]
+    pub window: Option<usize>,
+    /// Maximum number of recently received identifiers to attach to a quACK
+    /// when the end host requests them because it could not decode the last
+    /// quACK, so it can recover without a reset.
+    #[arg(long)]
+    pub fallback: Option<usize>,
+}
+
+/// Options of the structured log of quACK events.
+#[derive(Args, Clone, Debug)]
+pub struct EventLogArgs {
+    /// Append a line of JSON to this file for each identifier inserted, quACK
+    /// reset or emitted, and flow created or evicted.
+    #[arg(long = "event-log")]
+    pub event_log: Option<PathBuf>,
+    /// Rotate the event log when it reaches this many bytes, or 0 to never
+    /// rotate it.
+    #[arg(long = "event-log-max-bytes", default_value_t = 0)]
+    pub max_bytes: u64,
+    /// Log one in every this many inserted identifiers.
+    #[arg(long = "event-log-sample", default_value_t = 1)]
+    pub sample: u64,
+}
+
+impl EventLogArgs {
+    /// Open the event log, if any.
+    fn open(&self) -> Result<Option<Arc<EventLog>>, String> {
+        open_event_log(self.event_log.clone().map(|path| EventLogConfig {
+            path,
+            max_bytes: self.max_bytes,
+            sample: self.sample,
+        }))
+    }
+}
+
+/// Sends power sum quACKs of the packets sniffed on an interface.
+#[derive(Parser, Clone, Debug)]
+pub struct RunArgs {
+    #[command(flatten)]
+    pub sniff: SniffArgs,
+    #[command(flatten)]
+    pub quack: QuackArgs,
+    #[command(flatten)]
+    pub power_sum: PowerSumArgs,
+    #[command(flatten)]
+    pub events: EventLogArgs,
+    /// Port to receive acknowledged quACKs on, which are subtracted from the
+    /// quACK instead of resetting it. Only with `--frequency-ms`.
+    #[arg(long = "ack-port")]
+    pub ack_port: Option<u16>,
+}
+
+/// Sends power sum quACKs of each flow to a destination sniffed on an
+/// interface.
+#[derive(Parser, Clone, Debug)]
+pub struct RunMultiArgs {
+    #[command(flatten)]
+    pub sniff: SniffArgs,
+    #[command(flatten)]
+    pub quack: QuackArgs,
+    #[command(flatten)]
+    pub power_sum: PowerSumArgs,
+    #[command(flatten)]
+    pub events: EventLogArgs,
+    /// My port to receive quACK resets.
+    #[arg(long = "my-port", default_value_t = 1234)]
+    pub my_port: u16,
+    /// Receive acknowledged quACKs on my IP and port, which are subtracted
+    /// from the quACK of the flow they are sent from instead of resetting it.
+    /// Only with `--frequency-ms`.
+    #[arg(long)]
+    pub acks: bool,
+    /// Destination IP.
+    #[arg(long = "dst-ip")]
+    pub dst_ip: Ipv4Addr,
+    /// Destination port.
+    #[arg(long = "dst-port", default_value_t = 443)]
+    pub dst_port: u16,
+    /// Length of the QUIC destination connection IDs to demultiplex flows by,
+    /// so that their quACKs survive NAT rebinding, or 0 to demultiplex flows
+    /// by address only.
+    #[arg(long = "cid-len", default_value_t = 0)]
+    pub cid_len: usize,
+    /// Serve Prometheus metrics over HTTP on this address e.g., <IP:PORT>.
+    /// Requires the `metrics` feature, and only with `--frequency-ms`.
+    #[arg(long = "metrics-addr")]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Serve the control API on a Unix socket at this path, to inspect and
+    /// reset flows and change how they are quACKed. The flows to the
+    /// destination are quACKed by rule 0. Only with `--frequency-ms`.
+    #[arg(long = "control-socket")]
+    pub control_socket: Option<PathBuf>,
+}
+
+/// Sends strawman quACKs of the packets sniffed on an interface.
+#[derive(Parser, Clone, Debug)]
+pub struct StrawmanArgs {
+    #[command(flatten)]
+    pub sniff: SniffArgs,
+    #[command(flatten)]
+    pub quack: QuackArgs,
+    /// Style of quACK: strawman-a (echo the last identifier), or strawman-b
+    /// (echo a sliding window of identifiers).
+    #[arg(long, default_value = "strawman-a", value_parser = strawman_style())]
+    pub style: Style,
+    /// Number of identifiers echoed in each strawman B quACK.
+    #[arg(long, short = 'n', default_value_t = DEFAULT_WINDOW_SIZE)]
+    pub window: usize,
+}
+
+/// Parses the styles of strawman quACKs, since power sum quACKs are sent with
+/// `sidekick run`.
+fn strawman_style() -> impl TypedValueParser<Value = Style> {
+    PossibleValuesParser::new(["strawman-a", "strawman-b"]).map(|s| s.parse::<Style>().unwrap())
+}
+
+/// Replays the packets captured in a pcap file into a quACK, regardless of
+/// their direction.
+#[derive(Parser, Clone, Debug)]
+pub struct ReplayArgs {
+    /// Pcap file of Ethernet frames to replay.
+    #[arg(long)]
+    pub pcap: PathBuf,
+    #[command(flatten)]
+    pub quack: QuackArgs,
+    #[command(flatten)]
+    pub power_sum: PowerSumArgs,
+    #[command(flatten)]
+    pub events: EventLogArgs,
+    /// Style of quACK: power-sum, strawman-a, or strawman-b.
+    #[arg(long, default_value = "power-sum")]
+    pub style: Style,
+    /// Only quACK QUIC packets with short headers.
+    #[arg(long = "short-headers-only")]
+    pub short_headers_only: bool,
+    /// Where the identifier is in the packets of the encrypted protocol.
+    #[arg(long, default_value = "quic")]
+    pub profile: IdentifierProfile,
+}
+
+/// Prints the contents of the power sum quACKs received on a UDP socket.
+#[derive(Parser, Clone, Debug)]
+pub struct InspectArgs {
+    /// Address of the UDP socket to receive quACKs on e.g., <IP:PORT>.
+    #[arg(long = "listen-addr")]
+    pub listen_addr: SocketAddr,
+    /// The threshold number of missing packets, up to 255.
+    #[arg(long, short = 't', default_value_t = 20, value_parser = parse_threshold)]
+    pub threshold: usize,
+    /// Number of identifier bits: 16, 24, 32, or 64.
+    #[arg(long = "bits", short = 'b', default_value_t = 32)]
+    pub num_bits_id: usize,
+}
+
+/// Recommends where the identifier is in the packets of each flow sniffed on
+/// an interface, from the entropy and uniqueness of their bytes.
+#[derive(Parser, Clone, Debug)]
+pub struct CalibrateArgs {
+    /// Interface to listen on e.g., `eth1'.
+    #[arg(long, short = 'i')]
+    pub interface: String,
+    /// Direction of the packets to calibrate on: incoming or outgoing.
+    #[arg(long, default_value = "incoming")]
+    pub direction: Direction,
+    /// Number of packets of the busiest flow to sniff.
+    #[arg(long, short = 'n', default_value_t = 1000)]
+    pub packets: usize,
+    /// Number of identifier bits: 16, 24, 32, or 64.
+    #[arg(long = "bits", short = 'b', default_value_t = 32)]
+    pub num_bits_id: usize,
+}
+
+/// Sends power sum quACKs of the flows matching the rules in a configuration
+/// file, reloading the file on SIGHUP.
+#[derive(Parser, Clone, Debug)]
+pub struct DeployArgs {
+    /// TOML configuration file e.g., `sidekick.toml'.
+    #[arg(long, short = 'c')]
+    pub config: PathBuf,
+}
+
+/// Run the subcommand.
+pub async fn run(command: Command) -> Result<(), String> {
+    match command {
+        Command::Run(args) => run_power_sum(args).await,
+        Command::RunMulti(args) => run_multi(args).await,
+        Command::Strawman(args) => strawman(args).await,
+        Command::Replay(args) => replay(args).await,
+        Command::Inspect(args) => inspect(args).await,
+        Command::Deploy(args) => deploy(args).await,
+        Command::Calibrate(args) => calibrate(args).await,
+    }
+}
+
+/// Sniff packets into the encoder and send its quACKs at the specified
+/// frequency, or print them if there is no quACK address. Calls `listen`
+/// with the shared encoder before quACKing every `--frequency-ms`. The event
+/// log, if any, is flushed if the process is interrupted.
+async fn sniff<E, F>(
+    mut encoder: E,
+    config: SniffConfig,
+    args: &QuackArgs,
+    events: Option<Arc<EventLog>>,
+    listen: F,
+) -> Result<(), String>
+where
+    E: QuackEncoder + Send + 'static,
+    F: FnOnce(Arc<Mutex<E>>),
+{
+    let mut sink = args.sink().await?;
+    stats::print_on_signal(config.counters.clone(), events)?;
+
+    // Handle a snapshotted quACK at the specified frequency.
+    if let Some(frequency_ms) = args.frequency_ms {
+        let encoder = Arc::new(Mutex::new(encoder));
+        let rx = encoder::start(encoder.clone(), config)?;
+        listen(encoder.clone());
+        if frequency_ms == 0 {
+            return Ok(());
+        }
+        let frequency = Duration::from_millis(frequency_ms);
+        if let Some(sink) = sink.as_mut() {
+            encoder::emit_every(encoder, rx, frequency, sink).await
+        } else {
+            info!("printing quACKs");
+            print_quacks(encoder, rx, frequency).await
+        }
+    } else if let Some(frequency_pkts) = args.frequency_pkts {
+        let sink = sink.as_mut().ok_or("Address must be set")?;
+        encoder::run_every_pkts(&mut encoder, &config, frequency_pkts as usize, sink).await
+    } else {
+        Ok(())
+    }
+}
+
+async fn print_quacks<E: QuackEncoder>(
+    encoder: Arc<Mutex<E>>,
+    rx: oneshot::Receiver<()>,
+    frequency: Duration,
+) -> Result<(), String> {
+    rx.await
+        .map_err(|_| "couldn't receive notice that 1st packet was sniffed")?;
+    let mut interval = time::interval(frequency);
+    // The first tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let count = encoder.lock().unwrap().count();
+        info!("quack {}", count);
+    }
+}
+
+/// Open the configured event log, if any.
+fn open_event_log(config: Option<EventLogConfig>) -> Result<Option<Arc<EventLog>>, String> {
+    config
+        .map(|config| EventLog::open(config).map(Arc::new))
+        .transpose()
+}
+
+/// Configure a sidekick with the power sum options.
+fn new_sidekick<Q: WireQuack>(
+    interface: &str,
+    quack: &QuackArgs,
+    args: &PowerSumArgs,
+) -> Sidekick<Q> {
+    let mut sc = Sidekick::<Q>::new(interface, quack.threshold, quack.num_bits_id);
+    sc.timestamps = args.timestamps;
+    sc.byte_counts = args.byte_counts;
+    sc.ce_counts = args.ce_counts;
+    sc.window = args.window.unwrap_or(0);
+    sc.fallback = args.fallback.unwrap_or(0);
+    sc.num_sums = args.num_sums;
+    sc
+}
+
+/// Sniff packets into a power sum quACK, picking the modular arithmetic that
+/// fits the identifier.
+async fn run_power_sum(args: RunArgs) -> Result<(), String> {
+    debug!("{:?}", args);
+    match args.quack.num_bits_id {
+        16 => run_power_sum_q::<PowerSumQuackU16>(args).await,
+        24 | 32 => run_power_sum_q::<PowerSumQuackU32>(args).await,
+        64 => run_power_sum_q::<PowerSumQuackU64>(args).await,
+        bits => Err(format!("unsupported number of identifier bits: {}", bits)),
+    }
+}
+
+async fn run_power_sum_q<Q: WireQuack + Send + 'static>(args: RunArgs) -> Result<(), String>
+where
+    Q::Element: Send,
+{
+    let my_ip = args.sniff.my_ip.ok_or("--my-ip must be set")?;
+    info!("my ipv4 address is {:?}", my_ip);
+    let mut sc = new_sidekick::<Q>(&args.sniff.interface, &args.quack, &args.power_sum);
+    sc.direction = args.sniff.direction();
+    sc.filter = args.sniff.filter(FlowMatch::default());
+    sc.short_headers_only = args.sniff.short_headers_only;
+    sc.gso = args.sniff.gso;
+    sc.profile = args
+        .sniff
+        .profile(sc.filter.clone(), args.quack.num_bits_id)
+        .await?;
+    sc.check_ids = args.sniff.check_ids;
+    sc.collision_window = args.sniff.collision_window;
+    sc.events = args.events.open()?;
+    if args.ack_port.is_some() && args.quack.frequency_ms.is_none() {
+        return Err("acknowledged quACKs are only received with --frequency-ms".to_string());
+    }
+    sc.ack_port = args.ack_port;
+    let config = sc.sniff_config(my_ip.octets());
+    let ack_addr = args
+        .ack_port
+        .map(|ack_port| SocketAddr::new(my_ip.into(), ack_port));
+    let events = sc.events.clone();
+    sniff(sc, config, &args.quack, events, |sc| {
+        if let Some(addr) = ack_addr {
+            tokio::spawn(Sidekick::listen_for_acks(sc, addr));
+        }
+    })
+    .await
+}
+
+/// Sniff packets into a strawman quACK, quACKing every packet unless another
+/// frequency is specified.
+async fn strawman(mut args: StrawmanArgs) -> Result<(), String> {
+    debug!("{:?}", args);
+    if args.quack.frequency_ms.is_none() && args.quack.frequency_pkts.is_none() {
+        args.quack.frequency_pkts = Some(1);
+    }
+    let filter = args.sniff.filter(FlowMatch::default());
+    let config = SniffConfig {
+        interface: args.sniff.interface.clone(),
+        bits: args.quack.num_bits_id,
+        direction: args.sniff.direction(),
+        timestamps: false,
+        gso: args.sniff.gso,
+        my_ipv4_addr: args.sniff.my_ip.map(|ip| ip.octets()),
+        ack_port: None,
+        filter: filter.clone(),
+        short_headers_only: args.sniff.short_headers_only,
+        profile: args.sniff.profile(filter, args.quack.num_bits_id).await?,
+        counters: Arc::default(),
+    };
+    match args.style {
+        Style::StrawmanA => {
+            let encoder = StrawmanAEncoder::default();
+            sniff(encoder, config, &args.quack, None, |_| {}).await
+        }
+        Style::StrawmanB => {
+            let encoder = StrawmanBEncoder::new(args.window);
+            sniff(encoder, config, &args.quack, None, |_| {}).await
+        }
+        Style::PowerSum => Err("power sum quACKs are sent with `sidekick run`".to_string()),
+    }
+}
+
+/// Sniff packets into a power sum quACK for each flow, picking the modular
+/// arithmetic that fits the identifier.
+async fn run_multi(args: RunMultiArgs) -> Result<(), String> {
+    debug!("{:?}", args);
+    match args.quack.num_bits_id {
+        16 => run_multi_q::<PowerSumQuackU16>(args).await,
+        24 | 32 => run_multi_q::<PowerSumQuackU32>(args).await,
+        64 => run_multi_q::<PowerSumQuackU64>(args).await,
+        bits => Err(format!("unsupported number of identifier bits: {}", bits)),
+    }
+}
+
+/// Serve the metrics of the sidekick over HTTP on the address, if any.
+async fn serve_metrics<Q: WireQuack + Send + 'static>(
+    sc: &Arc<Mutex<SidekickMulti<Q>>>,
+    addr: Option<SocketAddr>,
+) -> Result<(), String> {
+    let addr = match addr {
+        Some(addr) => addr,
+        None => return Ok(()),
+    };
+    #[cfg(feature = "metrics")]
+    {
+        let listener = metrics::bind(addr).await?;
+        tokio::spawn(metrics::serve(sc.clone(), listener));
+        Ok(())
+    }
+    #[cfg(not(feature = "metrics"))]
+    {
+        let _ = sc;
+        Err(format!(
+            "serving metrics on {} requires the `metrics` feature",
+            addr
+        ))
+    }
+}
+
+/// Serve the control API of the sidekick on the Unix socket, if any. Rules
+/// can only be changed if there is a channel to notify of the changes.
+fn serve_control<Q: WireQuack + Send + 'static>(
+    sc: &Arc<Mutex<SidekickMulti<Q>>>,
+    path: Option<&Path>,
+    rules_changed: Option<mpsc::UnboundedSender<()>>,
+) -> Result<(), String> {
+    if let Some(path) = path {
+        let listener = control::bind(path)?;
+        tokio::spawn(control::serve(sc.clone(), listener, rules_changed));
+    }
+    Ok(())
+}
+
+async fn run_multi_q<Q: WireQuack + Send + 'static>(args: RunMultiArgs) -> Result<(), String> {
+    let my_ip = args.sniff.my_ip.ok_or("--my-ip must be set")?;
+    let quack_addr = args.quack.quack_addr.ok_or("--quack-addr must be set")?;
+    if args.quack.tcp {
+        return Err("cannot quACK each flow over TCP".to_string());
+    }
+
+    // Start the sidekick.
+    let mut sc = SidekickMulti::<Q>::new(
+        &args.sniff.interface,
+        args.quack.threshold,
+        args.quack.num_bits_id,
+    );
+    sc.timestamps = args.power_sum.timestamps;
+    sc.byte_counts = args.power_sum.byte_counts;
+    sc.ce_counts = args.power_sum.ce_counts;
+    sc.direction = args.sniff.direction();
+    sc.window = args.power_sum.window.unwrap_or(0);
+    sc.fallback = args.power_sum.fallback.unwrap_or(0);
+    sc.acks = args.acks;
+    sc.short_headers_only = args.sniff.short_headers_only;
+    sc.gso = args.sniff.gso;
+    if args.cid_len > MAX_CID_LEN {
+        return Err(format!(
+            "connection IDs are at most {} bytes: {}",
+            MAX_CID_LEN, args.cid_len
+        ));
+    }
+    sc.cid_len = args.cid_len;
+    sc.profile = args
+        .sniff
+        .profile(sc.filter().clone(), args.quack.num_bits_id)
+        .await?;
+    sc.check_ids = args.sniff.check_ids;
+    sc.collision_window = args.sniff.collision_window;
+    sc.events = args.events.open()?;
+
+    // Only keep quACKs of the flows to the destination.
+    let flows = FlowMatch {
+        dst: Some(Cidr {
+            addr: args.dst_ip,
+            prefix_len: 32,
+        }),
+        dst_ports: Some(PortRange {
+            start: args.dst_port,
+            end: args.dst_port,
+        }),
+        ..FlowMatch::default()
+    };
+    sc.set_filter(args.sniff.filter(flows.clone()));
+
+    let mut my_addr: [u8; 6] = [0; 6];
+    my_addr[..4].copy_from_slice(&my_ip.octets());
+    my_addr[4..].copy_from_slice(&args.my_port.to_be_bytes());
+
+    // Handle snapshotted quACKs at the specified frequency.
+    info!("my address is {:?}", my_addr);
+    stats::print_on_signal(sc.counters(), sc.events.clone())?;
+    let sc = Arc::new(Mutex::new(sc));
+    if let Some(frequency_ms) = args.quack.frequency_ms {
+        assert!(frequency_ms > 0);
+        // QuACK the flows to the destination as rule 0, which the control API
+        // can change like the rules of `sidekick deploy`.
+        let rule = Rule {
+            flows: FlowMatch {
+                quic: args.sniff.quic_only,
+                ..flows
+            },
+            threshold: None,
+            profile: None,
+            frequency_ms: Some(frequency_ms),
+            frequency_pkts: None,
+            num_sums: args.power_sum.num_sums,
+            quack_addr,
+        };
+        rule.validate(args.quack.threshold)?;
+        sc.lock().unwrap().set_rules(vec![rule]);
+        let rx = start_sidekick_multi(sc.clone(), my_addr)?;
+        serve_metrics(&sc, args.metrics_addr).await?;
+        // Keep a sender so that the channel stays open without a control API
+        let (rules_changed, mut rules_changes) = mpsc::unbounded_channel();
+        serve_control(
+            &sc,
+            args.control_socket.as_deref(),
+            Some(rules_changed.clone()),
+        )?;
+        if args.acks {
+            let addr = SocketAddr::new(my_ip.into(), args.my_port);
+            let dst = SocketAddrV4::new(args.dst_ip, args.dst_port);
+            tokio::spawn(listen_for_acks_multi(sc.clone(), addr, dst));
+        }
+        rx.await
+            .map_err(|_| "couldn't receive notice that 1st packet was sniffed")?;
+        loop {
+            let tasks = spawn_rule_quacks(&sc);
+            rules_changes
+                .recv()
+                .await
+                .ok_or("stopped applying rule changes")?;
+            for task in tasks {
+                task.abort();
+            }
+        }
+    } else if let Some(frequency_pkts) = args.quack.frequency_pkts {
+        if args.metrics_addr.is_some() {
+            return Err("metrics are only served with --frequency-ms".to_string());
+        }
+        if args.control_socket.is_some() {
+            return Err("the control API is only served with --frequency-ms".to_string());
+        }
+        if args.acks {
+            return Err("acknowledged quACKs are only received with --frequency-ms".to_string());
+        }
+        start_sidekick_multi_frequency_pkts(
+            sc.clone(),
+            my_addr,
+            frequency_pkts as u32,
+            args.power_sum.num_sums,
+            quack_addr,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Replay a pcap file into a quACK, emitting it every `--frequency-pkts`
+/// packets, or only once at the end if not specified.
+async fn replay(args: ReplayArgs) -> Result<(), String> {
+    debug!("{:?}", args);
+    match (args.style, args.quack.num_bits_id) {
+        (Style::StrawmanA, _) => replay_with(StrawmanAEncoder::default(), &args).await,
+        (Style::StrawmanB, _) => {
+            let window_size = args.power_sum.window.unwrap_or(DEFAULT_WINDOW_SIZE);
+            replay_with(StrawmanBEncoder::new(window_size), &args).await
+        }
+        (Style::PowerSum, 16) => replay_power_sum::<PowerSumQuackU16>(&args).await,
+        (Style::PowerSum, 24 | 32) => replay_power_sum::<PowerSumQuackU32>(&args).await,
+        (Style::PowerSum, 64) => replay_power_sum::<PowerSumQuackU64>(&args).await,
+        (Style::PowerSum, bits) => Err(format!("unsupported number of identifier bits: {}", bits)),
+    }
+}
+
+async fn replay_power_sum<Q: WireQuack>(args: &ReplayArgs) -> Result<(), String> {
+    let mut sc = new_sidekick::<Q>("", &args.quack, &args.power_sum);
+    sc.events = args.events.open()?;
+    replay_with(sc, args).await
+}
+
+async fn replay_with<E: QuackEncoder>(mut encoder: E, args: &ReplayArgs) -> Result<(), String> {
+    let config = SniffConfig {
+        interface: String::new(),
+        bits: args.quack.num_bits_id,
+        direction: Direction::Unknown,
+        timestamps: args.power_sum.timestamps,
+        gso: false,
+        my_ipv4_addr: None,
+        ack_port: None,
+        filter: FlowFilter::default(),
+        short_headers_only: args.short_headers_only,
+        profile: args.profile,
+        counters: Arc::default(),
+    };
+    let mut sink = args.quack.sink().await?;
+    let frequency_pkts = args
+        .quack
+        .frequency_pkts
+        .map_or(usize::MAX, |frequency_pkts| frequency_pkts as usize);
+    let inserted = encoder::replay_every_pkts(
+        &mut encoder,
+        &config,
+        &args.pcap,
+        frequency_pkts,
+        sink.as_mut(),
+    )
+    .await?;
+    info!("replayed {} packets", inserted);
+    println!("{}", config.counters.stats());
+    if frequency_pkts == usize::MAX {
+        if let Some(sink) = sink.as_mut() {
+            let mut buf = vec![];
+            encoder.encode(&mut buf)?;
+            if !buf.is_empty() {
+                sink.send(&buf).await?;
+            }
+        } else {
+            info!("quack {}", encoder.count());
+        }
+    }
+    Ok(())
+}
+
+/// Print the quACKs received on a UDP socket, picking the modular arithmetic
+/// that fits the identifier.
+async fn inspect(args: InspectArgs) -> Result<(), String> {
+    match args.num_bits_id {
+        16 => inspect_q::<PowerSumQuackU16>(args).await,
+        24 | 32 => inspect_q::<PowerSumQuackU32>(args).await,
+        64 => inspect_q::<PowerSumQuackU64>(args).await,
+        bits => Err(format!("unsupported number of identifier bits: {}", bits)),
+    }
+}
+
+async fn inspect_q<Q: WireQuack>(args: InspectArgs) -> Result<(), String>
+where
+    Q::Element: Debug,
+{
+    let socket = UdpSocket::bind(args.listen_addr)
+        .await
+        .map_err(|e| format!("error binding to UDP socket: {:?}", e))?;
+    info!("listening for quACKs on {:?}", args.listen_addr);
+    let mut buf = vec![0; Q::encoded_len(args.threshold) + encoding::MAX_EXTENSIONS_LEN];
+    loop {
+        let (len, from) = socket
+            .recv_from(&mut buf)
+            .await
+            .map_err(|e| format!("error receiving quack: {:?}", e))?;
+        match encoding::decode_message::<Q>(&buf[..len]) {
+            Ok((quack, extensions)) => println!(
+                "{} count={} last_value={:?} num_sums={} {:?}",
+                from,
+                quack.count(),
+                quack.last_value(),
+                quack.threshold(),
+                extensions
+            ),
+            Err(e) => println!("{} bad quack: {}", from, e),
+        }
+    }
+}
+
+/// Sniff packets until a flow has the specified number of packets, and print
+/// the earliest offset in the UDP payload of each flow whose identifiers look
+/// uniformly random.
+async fn calibrate(args: CalibrateArgs) -> Result<(), String> {
+    debug!("{:?}", args);
+    let (interface, direction, packets) = (args.interface, args.direction, args.packets);
+    let flows = tokio::task::spawn_blocking(move || {
+        calibrate::sniff(&interface, direction, &FlowFilter::default(), packets)
+    })
+    .await
+    .map_err(|e| format!("error calibrating: {:?}", e))??;
+    for (addr_key, samples) in flows {
+        let packets = samples.payloads.len();
+        if packets < calibrate::MIN_PACKETS {
+            continue;
+        }
+        match samples.recommend(args.num_bits_id / 8) {
+            Some(stats) => println!(
+                "{:?} packets={} profile={} entropy={:.3} uniqueness={:.3}",
+                addr_key,
+                packets,
+                IdentifierProfile::Offset {
+                    offset: stats.offset
+                },
+                stats.entropy,
+                stats.uniqueness
+            ),
+            None => println!("{:?} packets={} no random identifiers", addr_key, packets),
+        }
+    }
+    Ok(())
+}
+
+/// Deploy the sidekick as configured, picking the modular arithmetic that fits
+/// the identifier.
+async fn deploy(args: DeployArgs) -> Result<(), String> {
+    let config = Config::load(&args.config)?;
+    debug!("{:?}", config);
+    match config.bits {
+        16 => deploy_q::<PowerSumQuackU16>(&args.config, config).await,
+        24 | 32 => deploy_q::<PowerSumQuackU32>(&args.config, config).await,
+        64 => deploy_q::<PowerSumQuackU64>(&args.config, config).await,
+        bits => Err(format!("unsupported number of identifier bits: {}", bits)),
+    }
+}
+
+async fn deploy_q<Q: WireQuack + Send + 'static>(
+    path: &Path,
+    mut config: Config,
+) -> Result<(), String> {
+    // Start the sidekick.
+    let mut sc = SidekickMulti::<Q>::new(&config.interface, config.threshold, config.bits);
+    sc.timestamps = config.timestamps;
+    sc.byte_counts = config.byte_counts;
+    sc.ce_counts = config.ce_counts;
+    sc.window = config.window;
+    sc.fallback = config.fallback;
+    sc.short_headers_only = config.short_headers_only;
+    sc.gso = config.gso;
+    sc.cid_len = config.cid_len;
+    sc.profile = config.profile;
+    sc.check_ids = config.check_ids;
+    sc.collision_window = config.collision_window;
+    sc.events = open_event_log(config.event_log.clone())?;
+    sc.set_rules(config.rules.clone());
+    sc.set_filter(config.filter());
+    info!("my address is {:?}", config.my_addr());
+    stats::print_on_signal(sc.counters(), sc.events.clone())?;
+    let sc = Arc::new(Mutex::new(sc));
+    let _rx = start_sidekick_multi(sc.clone(), config.my_addr())?;
+    serve_metrics(&sc, config.metrics_addr).await?;
+    let (rules_changed, mut rules_changes) = mpsc::unbounded_channel();
+    serve_control(&sc, config.control_socket.as_deref(), Some(rules_changed))?;
+
+    // QuACK the flows of each rule at the specified frequency, replacing the
+    // rules whenever the configuration file is reloaded or the rules are
+    // changed through the control API.
+    let mut reloads = config::reloads()?;
+    loop {
+        let tasks = spawn_rule_quacks(&sc);
+        let reloaded = tokio::select! {
+            reloaded = next_reload(path, &config, &mut reloads) => Some(reloaded?),
+            Some(()) = rules_changes.recv() => None,
+        };
+        for task in tasks {
+            task.abort();
+        }
+        if let Some(reloaded) = reloaded {
+            config = reloaded;
+            let mut sc = sc.lock().unwrap();
+            sc.threshold = config.threshold;
+            sc.set_rules(config.rules.clone());
+            sc.set_filter(config.filter());
+        }
+    }
+}
+
+/// QuACK the flows of each rule at the specified frequency, until the tasks
+/// are aborted before the rules are replaced.
+fn spawn_rule_quacks<Q: WireQuack + Send + 'static>(
+    sc: &Arc<Mutex<SidekickMulti<Q>>>,
+) -> Vec<JoinHandle<Result<(), String>>> {
+    sc.lock()
+        .unwrap()
+        .rules()
+        .iter()
+        .enumerate()
+        .filter(|(_, rule)| rule.frequency_ms.is_some())
+        .map(|(i, _)| tokio::spawn(send_rule_quacks_ms(sc.clone(), i)))
+        .collect()
+}
+
+/// Wait until the process receives SIGHUP and the configuration file is
+/// loaded again, skipping reloads that are invalid.
+async fn next_reload(
+    path: &Path,
+    config: &Config,
+    reloads: &mut mpsc::UnboundedReceiver<()>,
+) -> Result<Config, String> {
+    loop {
+        reloads.recv().await.ok_or("stopped listening for SIGHUP")?;
+        info!("reloading {}", path.display());
+        let reloaded = Config::load(path)
+            .and_then(|reloaded| config.validate_reload(&reloaded).map(|_| reloaded));
+        match reloaded {
+            Ok(reloaded) => return Ok(reloaded),
+            Err(e) => warn!("not reloading: {}", e),
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! Detection of identifier collisions within a flow.
+//!
+//! When two packets in the same quACK share an identifier, decoding cannot
+//! tell which of them is missing. The decoder reports such identifiers as
+//! indeterminate instead of guessing, and sidekicks on either side can count
+//! how often recent identifiers collide to report the collision rate.
+use std::collections::{HashMap, VecDeque};
+
+/// Number of identifiers and collisions seen by a `CollisionDetector`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CollisionStats {
+    /// Number of identifiers inserted
+    pub ids: u64,
+    /// Number of identifiers equal to a recent identifier
+    pub collisions: u64,
+}
+
+impl CollisionStats {
+    /// Fraction of the identifiers that collided.
+    pub fn rate(&self) -> f64 {
+        if self.ids == 0 {
+            0.0
+        } else {
+            self.collisions as f64 / self.ids as f64
+        }
+    }
+}
+
+impl std::ops::AddAssign for CollisionStats {
+    fn add_assign(&mut self, other: Self) {
+        self.ids += other.ids;
+        self.collisions += other.collisions;
+    }
+}
+
+/// Detects identifiers equal to one of the `window` most recent identifiers
+/// of a flow.
+#[derive(Clone, Debug)]
+pub struct CollisionDetector {
+    window: usize,
+    recent: VecDeque<u64>,
+    /// Number of times each recent identifier occurs
+    counts: HashMap<u64, u32>,
+    stats: CollisionStats,
+}
+
+impl CollisionDetector {
+    pub fn new(window: usize) -> Self {
+        assert!(window > 0, "ERROR: collision window must be positive");
+        Self {
+            window,
+            recent: VecDeque::with_capacity(window),
+            counts: HashMap::new(),
+            stats: CollisionStats::default(),
+        }
+    }
+
+    /// Insert an identifier. Returns whether it collides with a recent
+    /// identifier.
+    pub fn insert(&mut self, id: u64) -> bool {
+        if self.recent.len() == self.window {
+            if let Some(expired) = self.recent.pop_front() {
+                if let Some(count) = self.counts.get_mut(&expired) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.counts.remove(&expired);
+                    }
+                }
+            }
+        }
+        self.recent.push_back(id);
+        let count = self.counts.entry(id).or_insert(0);
+        let collided = *count > 0;
+        *count += 1;
+        self.stats.ids += 1;
+        if collided {
+            self.stats.collisions += 1;
+        }
+        collided
+    }
+
+    /// Forget the recent identifiers, keeping the statistics.
+    pub fn clear(&mut self) {
+        self.recent.clear();
+        self.counts.clear();
+    }
+
+    pub fn stats(&self) -> CollisionStats {
+        self.stats
+    }
+}
@@ -0,0 +1,438 @@
+//! Declarative configuration of a sidekick deployment, loaded from a TOML
+//! file by `sidekick deploy`.
+//!
+//! The sidekick sniffs the interface and keeps a quACK for every flow it is
+//! asked to quACK. Each rule matches flows by their addresses, and sets how
+//! they are quACKed. A flow is quACKed by the first rule that matches it.
+//! Flows that match a `deny` condition, which take precedence over the rules,
+//! or that match no rule are not quACKed and consume no quACK state.
+//!
+//! ```toml
+//! interface = "wlp1s0"
+//! my_ip = "10.42.0.1"
+//! my_port = 1234
+//! threshold = 20
+//!
+//! [[deny]]
+//! dst_ports = "53"
+//!
+//! [[rules]]
+//! threshold = 80
+//! profile = "quic:20"
+//! frequency_ms = 30
+//! quack_addr = "10.42.0.250:5104"
+//! match = { dst = "34.221.237.169", dst_ports = "443", quic = true }
+//! ```
+//!
+//! On SIGHUP the file is loaded again and the new rules are applied to the
+//! existing flows without dropping their quACKs. A new threshold only applies
+//! to flows created or reset after the reload, and new `deny` conditions only
+//! apply to new flows. The other settings can only be changed by restarting
+//! the sidekick.
+//!
+//! The threshold and rules can also be changed through the control socket,
+//! if any, until the file is loaded again.
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use signal_hook::{consts::SIGHUP, iterator::Signals};
+use tokio::sync::mpsc;
+
+use crate::buffer::{IdentifierProfile, MAX_CID_LEN};
+use crate::encoding::MAX_POWER_SUMS;
+use crate::events::EventLogConfig;
+use crate::filter::{Action, FilterRule, FlowFilter, FlowMatch};
+
+/// Settings of a sidekick deployment.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Interface to listen on
+    pub interface: String,
+    /// My IPv4 address to receive quACK resets
+    pub my_ip: Ipv4Addr,
+    /// My port to receive quACK resets
+    #[serde(default = "default_my_port")]
+    pub my_port: u16,
+    /// Number of identifier bits: 16, 24, 32, or 64
+    #[serde(default = "default_bits")]
+    pub bits: usize,
+    /// Threshold number of missing packets of flows whose rule does not
+    /// specify one
+    #[serde(default = "default_threshold")]
+    pub threshold: usize,
+    /// Whether to include timestamps in quACKs
+    #[serde(default)]
+    pub timestamps: bool,
+    /// Whether to include the cumulative byte count in quACKs
+    #[serde(default)]
+    pub byte_counts: bool,
+    /// Whether to include the cumulative CE-marked packet count in quACKs
+    #[serde(default)]
+    pub ce_counts: bool,
+    /// Number of most recent identifiers in a sliding-window quACK, or 0 for
+    /// a cumulative quACK
+    #[serde(default)]
+    pub window: usize,
+    /// Maximum number of recent identifiers to attach to a quACK when the end
+    /// host requests them, or 0 to never attach them
+    #[serde(default)]
+    pub fallback: usize,
+    /// Whether to only quACK QUIC packets with short headers, skipping
+    /// handshake packets and the datagrams they are coalesced in
+    #[serde(default)]
+    pub short_headers_only: bool,
+    /// Whether to split frames coalesced by GRO into their UDP segments
+    #[serde(default)]
+    pub gso: bool,
+    /// Length of the QUIC destination connection IDs to demultiplex flows by,
+    /// or 0 to demultiplex flows by address only
+    #[serde(default)]
+    pub cid_len: usize,
+    /// Where the identifier is in the packets of flows whose rule does not
+    /// specify a profile: quic, quic:<dcid_len>, wireguard, dtls1.2, dtls1.3,
+    /// dtls1.3:<cid_len>, srtp, or offset:<n>
+    #[serde(default)]
+    pub profile: IdentifierProfile,
+    /// Whether to warn if the identifiers of a flow do not look uniformly
+    /// random
+    #[serde(default)]
+    pub check_ids: bool,
+    /// Number of recent identifiers of each flow to detect collisions with,
+    /// or 0 to not detect collisions
+    #[serde(default)]
+    pub collision_window: usize,
+    /// Address to serve Prometheus metrics over HTTP on, if any, which
+    /// requires the `metrics` feature
+    #[serde(default)]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Path of the Unix socket to serve the control API on, if any
+    #[serde(default)]
+    pub control_socket: Option<PathBuf>,
+    /// Where to log quACK events as JSON lines, if anywhere
+    #[serde(default)]
+    pub event_log: Option<EventLogConfig>,
+    /// Flows not to quACK, even if they match a rule
+    #[serde(default)]
+    pub deny: Vec<FlowMatch>,
+    /// Rules for which flows to quACK and how
+    pub rules: Vec<Rule>,
+}
+
+/// How to quACK the flows that match.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Which flows the rule applies to
+    #[serde(rename = "match", default)]
+    pub flows: FlowMatch,
+    /// Threshold number of missing packets, if different from the default
+    pub threshold: Option<usize>,
+    /// Where the identifier is in the packets, if different from the default
+    pub profile: Option<IdentifierProfile>,
+    /// Frequency at which to quACK, in ms
+    pub frequency_ms: Option<u64>,
+    /// Frequency at which to quACK, in packets
+    pub frequency_pkts: Option<u32>,
+    /// Number of power sums to include in each quACK, if fewer than the
+    /// threshold
+    pub num_sums: Option<usize>,
+    /// Address of the UDP socket to quACK to
+    pub quack_addr: SocketAddr,
+}
+
+fn default_my_port() -> u16 {
+    1234
+}
+
+fn default_bits() -> usize {
+    32
+}
+
+fn default_threshold() -> usize {
+    20
+}
+
+impl Config {
+    /// Load and validate the configuration file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("error reading {}: {}", path.display(), e))?;
+        let config: Config = toml::from_str(&contents)
+            .map_err(|e| format!("error parsing {}: {}", path.display(), e))?;
+        config
+            .validate()
+            .map_err(|e| format!("invalid {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    /// Check that the settings are consistent.
+    pub fn validate(&self) -> Result<(), String> {
+        if ![16, 24, 32, 64].contains(&self.bits) {
+            return Err(format!(
+                "unsupported number of identifier bits: {}",
+                self.bits
+            ));
+        }
+        validate_threshold(self.threshold)?;
+        if self.cid_len > MAX_CID_LEN {
+            return Err(format!(
+                "connection IDs are at most {} bytes: {}",
+                MAX_CID_LEN, self.cid_len
+            ));
+        }
+        if self.rules.is_empty() {
+            return Err("no rules".to_string());
+        }
+        for (i, rule) in self.rules.iter().enumerate() {
+            rule.validate(self.threshold)
+                .map_err(|e| format!("rule {}: {}", i, e))?;
+        }
+        Ok(())
+    }
+
+    /// Check that the reloaded configuration only changes the settings that
+    /// can be changed without restarting the sidekick.
+    pub fn validate_reload(&self, reloaded: &Config) -> Result<(), String> {
+        let restart = Config {
+            threshold: self.threshold,
+            deny: self.deny.clone(),
+            rules: self.rules.clone(),
+            ..reloaded.clone()
+        };
+        if restart != *self {
+            return Err(
+                "only the threshold, deny, and rules can change without a restart".to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Which flows to quACK: those that match a rule but no `deny` condition.
+    pub fn filter(&self) -> FlowFilter {
+        filter(self.deny.iter().cloned(), &self.rules)
+    }
+
+    /// My IP and port, as in the address key of a flow.
+    pub fn my_addr(&self) -> [u8; 6] {
+        let mut my_addr = [0; 6];
+        my_addr[..4].copy_from_slice(&self.my_ip.octets());
+        my_addr[4..].copy_from_slice(&self.my_port.to_be_bytes());
+        my_addr
+    }
+}
+
+/// Which flows to quACK: those that match a rule but no `deny` condition.
+pub fn filter(deny: impl Iterator<Item = FlowMatch>, rules: &[Rule]) -> FlowFilter {
+    let deny = deny.map(|flows| FilterRule {
+        action: Action::Deny,
+        flows,
+    });
+    let allow = rules.iter().map(|rule| FilterRule {
+        action: Action::Allow,
+        flows: rule.flows.clone(),
+    });
+    FlowFilter {
+        rules: deny.chain(allow).collect(),
+        default: Action::Deny,
+    }
+}
+
+impl Rule {
+    /// Check that the settings are consistent, given the default threshold.
+    pub fn validate(&self, default_threshold: usize) -> Result<(), String> {
+        let threshold = self.threshold.unwrap_or(default_threshold);
+        validate_threshold(threshold)?;
+        match (self.frequency_ms, self.frequency_pkts) {
+            (Some(0), None) | (None, Some(0)) => Err("frequency must be positive".to_string()),
+            (Some(_), None) | (None, Some(_)) => Ok(()),
+            _ => Err("exactly one of frequency_ms and frequency_pkts must be set".to_string()),
+        }?;
+        match self.num_sums {
+            Some(num_sums) if num_sums > threshold => Err(format!(
+                "num_sums exceeds the threshold: {} > {}",
+                num_sums, threshold
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the rule applies to the flow with the address key, given the
+    /// first byte of the UDP payload of the packet that created the flow.
+    pub fn matches(&self, addr_key: &[u8; 12], first_byte: u8) -> bool {
+        self.flows.matches(addr_key, first_byte)
+    }
+}
+
+pub fn validate_threshold(threshold: usize) -> Result<(), String> {
+    if threshold == 0 || threshold > MAX_POWER_SUMS {
+        return Err(format!(
+            "threshold must be between 1 and {}: {}",
+            MAX_POWER_SUMS, threshold
+        ));
+    }
+    Ok(())
+}
+
+/// Returns a channel that receives a message whenever the process receives
+/// SIGHUP, the signal to reload the configuration file.
+pub fn reloads() -> Result<mpsc::UnboundedReceiver<()>, String> {
+    let mut signals =
+        Signals::new([SIGHUP]).map_err(|e| format!("error registering SIGHUP: {:?}", e))?;
+    let (tx, rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for _ in &mut signals {
+            if tx.send(()).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        interface = "wlp1s0"
+        my_ip = "10.42.0.1"
+
+        [[deny]]
+        dst_ports = "53"
+
+        [[rules]]
+        frequency_ms = 30
+        quack_addr = "10.42.0.250:5104"
+        match = { dst = "34.221.237.169", dst_ports = "443" }
+    "#;
+
+    fn config() -> Config {
+        toml::from_str(CONFIG).unwrap()
+    }
+
+    /// Check that the configuration is invalid after the change.
+    fn invalid(change: impl FnOnce(&mut Config)) -> String {
+        let mut config = config();
+        change(&mut config);
+        config.validate().unwrap_err()
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = config();
+        assert_eq!(config.validate(), Ok(()));
+        assert_eq!(config.my_port, 1234);
+        assert_eq!(config.bits, 32);
+        assert_eq!(config.threshold, 20);
+        assert_eq!(config.profile, IdentifierProfile::default());
+        assert_eq!(config.rules.len(), 1);
+        assert_eq!(config.rules[0].threshold, None);
+        assert_eq!(config.my_addr(), [10, 42, 0, 1, 0x04, 0xd2]);
+    }
+
+    #[test]
+    fn test_parse_example() {
+        let config: Config = toml::from_str(include_str!("../sidekick.example.toml")).unwrap();
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_parse_unknown_field() {
+        let config = format!("unknown = 1\n{}", CONFIG);
+        assert!(toml::from_str::<Config>(&config).is_err());
+        let config = CONFIG.replace("frequency_ms", "frequency");
+        assert!(toml::from_str::<Config>(&config).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(invalid(|config| config.bits = 8).contains("bits"));
+        assert!(invalid(|config| config.threshold = 0).contains("threshold"));
+        let too_many_sums = MAX_POWER_SUMS + 1;
+        assert!(invalid(|config| config.threshold = too_many_sums).contains("threshold"));
+        assert!(invalid(|config| config.cid_len = MAX_CID_LEN + 1).contains("connection IDs"));
+        assert_eq!(invalid(|config| config.rules.clear()), "no rules");
+    }
+
+    #[test]
+    fn test_validate_rule() {
+        let rule = config().rules[0].clone();
+        assert_eq!(rule.validate(20), Ok(()));
+        let frequency_pkts = Rule {
+            frequency_ms: None,
+            frequency_pkts: Some(10),
+            ..rule.clone()
+        };
+        assert_eq!(frequency_pkts.validate(20), Ok(()));
+        let both = Rule {
+            frequency_pkts: Some(10),
+            ..rule.clone()
+        };
+        assert!(both.validate(20).is_err());
+        let neither = Rule {
+            frequency_ms: None,
+            ..rule.clone()
+        };
+        assert!(neither.validate(20).is_err());
+        let zero = Rule {
+            frequency_ms: Some(0),
+            ..rule.clone()
+        };
+        assert!(zero.validate(20).is_err());
+
+        // The number of power sums is checked against the rule's threshold,
+        // if any, or else the default threshold.
+        let num_sums = Rule {
+            num_sums: Some(50),
+            ..rule.clone()
+        };
+        assert!(num_sums.validate(20).is_err());
+        assert_eq!(num_sums.validate(80), Ok(()));
+        let threshold = Rule {
+            threshold: Some(80),
+            ..num_sums
+        };
+        assert_eq!(threshold.validate(20), Ok(()));
+
+        // The error says which rule is invalid.
+        let err = invalid(|config| config.rules.push(zero));
+        assert!(err.starts_with("rule 1: "), "{}", err);
+    }
+
+    #[test]
+    fn test_validate_reload() {
+        let config = config();
+        let mut reloaded = config.clone();
+        reloaded.threshold = 40;
+        reloaded.deny.clear();
+        reloaded.rules[0].frequency_ms = Some(10);
+        assert_eq!(config.validate_reload(&reloaded), Ok(()));
+
+        let mut reloaded = config.clone();
+        reloaded.interface = "eth0".to_string();
+        assert!(config.validate_reload(&reloaded).is_err());
+        let mut reloaded = config.clone();
+        reloaded.bits = 64;
+        assert!(config.validate_reload(&reloaded).is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = config().filter();
+        let key = |dst_port: u16| {
+            let mut key = [0; 12];
+            key[6..10].copy_from_slice(&[34, 221, 237, 169]);
+            key[10..12].copy_from_slice(&dst_port.to_be_bytes());
+            key
+        };
+        assert!(filter.allows(&key(443), 0x40));
+        // Matches no rule
+        assert!(!filter.allows(&key(80), 0x40));
+        let mut config = config();
+        config.deny[0].dst_ports = Some("443".parse().unwrap());
+        // Denied even though it matches a rule
+        assert!(!config.filter().allows(&key(443), 0x40));
+    }
+}
@@ -0,0 +1,348 @@
+//! Local control API of a sidekick that quACKs multiple flows, served on a
+//! Unix socket for operators to inspect and manage flows without restarting
+//! the sidekick. The `sidekickctl` binary is a client.
+//!
+//! Each request is a line of JSON naming a `cmd`, and is answered with a line
+//! of JSON naming its `type`, or an `error`:
+//!
+//! ```text
+//! {"cmd":"list-flows"}
+//! {"cmd":"dump-quack","src":"10.42.0.2:5000","dst":"34.221.237.169:443"}
+//! {"cmd":"reset-flow","src":"10.42.0.2:5000","dst":"34.221.237.169:443"}
+//! {"cmd":"set-threshold","threshold":40}
+//! {"cmd":"set-threshold","threshold":40,"rule":0}
+//! {"cmd":"set-frequency","rule":0,"frequency_ms":50}
+//! {"cmd":"list-rules"}
+//! {"cmd":"add-rule","rule":{"frequency_ms":30,"quack_addr":"10.42.0.250:5104"}}
+//! {"cmd":"remove-rule","rule":1}
+//! ```
+//!
+//! As on a reload, a new threshold only applies to flows created or reset
+//! after the change. The rules can be changed by `sidekick deploy`, whose
+//! rules are replaced by those of the configuration file when it is reloaded,
+//! and by `sidekick run-multi --frequency-ms`, whose flows to the destination
+//! are quACKed by rule 0.
+use std::net::SocketAddrV4;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+
+use crate::config::{self, Rule};
+use crate::encoding::{self, WireQuack};
+use crate::filter::Action;
+use crate::sidekick_multi::{self, Flow};
+use crate::SidekickMulti;
+
+/// A request to the control API.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+pub enum Request {
+    /// List the flows with their counts and when they were last seen
+    ListFlows,
+    /// Dump the quACK of the flow from `src` to `dst`
+    DumpQuack {
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+    },
+    /// Reset the quACK of the flow from `src` to `dst`
+    ResetFlow {
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+    },
+    /// Set the threshold of the rule, or the default threshold
+    SetThreshold {
+        threshold: usize,
+        rule: Option<usize>,
+    },
+    /// Set the frequency at which to quACK the flows of the rule
+    SetFrequency {
+        rule: usize,
+        frequency_ms: Option<u64>,
+        frequency_pkts: Option<u32>,
+    },
+    /// List the rules in the order they are matched
+    ListRules,
+    /// Add a rule, matched after the existing rules
+    AddRule { rule: Rule },
+    /// Remove the rule, renumbering the rules after it
+    RemoveRule { rule: usize },
+}
+
+/// A response from the control API.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Response {
+    Ok,
+    Flows {
+        flows: Vec<FlowInfo>,
+    },
+    Quack {
+        count: u32,
+        threshold: usize,
+        /// The quACK message that would be sent now, in hex
+        message: String,
+    },
+    Rules {
+        rules: Vec<String>,
+    },
+    Error {
+        error: String,
+    },
+}
+
+/// A flow in the table of the sidekick.
+#[derive(Clone, Debug, Serialize)]
+pub struct FlowInfo {
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+    /// Index of the rule that quACKs the flow, if any
+    pub rule: Option<usize>,
+    pub threshold: usize,
+    /// Number of identifiers in the quACK
+    pub count: u32,
+    /// Identifiers inserted since the flow was created
+    pub inserts: u64,
+    /// QuACKs sent since the flow was created
+    pub quacks: u64,
+    /// QuACK resets since the flow was created
+    pub resets: u64,
+    /// Time since the last packet of the flow was inserted, in ms
+    pub last_seen_ms_ago: Option<u64>,
+}
+
+impl FlowInfo {
+    fn new<Q: WireQuack>(addr_key: &[u8; 12], flow: &Flow<Q>, now: SystemTime) -> Self {
+        let (src, dst) = sidekick_multi::flow_addrs(addr_key);
+        Self {
+            src,
+            dst,
+            rule: flow.rule,
+            threshold: flow.quack.threshold(),
+            count: flow.quack.count(),
+            inserts: flow.stats.inserts,
+            quacks: flow.stats.quacks,
+            resets: flow.stats.resets,
+            last_seen_ms_ago: flow.last_seen.map(|last_seen| {
+                now.duration_since(last_seen)
+                    .unwrap_or_default()
+                    .as_millis() as u64
+            }),
+        }
+    }
+}
+
+/// Handle a request, notifying `rules_changed` if the rules changed. Rules
+/// cannot be changed without it.
+pub fn handle<Q: WireQuack>(
+    sc: &mut SidekickMulti<Q>,
+    request: Request,
+    rules_changed: Option<&mpsc::UnboundedSender<()>>,
+) -> Result<Response, String> {
+    match request {
+        Request::ListFlows => {
+            let now = SystemTime::now();
+            let flows = sc
+                .senders()
+                .iter()
+                .map(|(addr_key, flow)| FlowInfo::new(addr_key, flow, now))
+                .collect();
+            Ok(Response::Flows { flows })
+        }
+        Request::DumpQuack { src, dst } => {
+            let flow = sc
+                .senders()
+                .get(&sidekick_multi::addr_key(src, dst))
+                .ok_or(format!("no flow from {} to {}", src, dst))?;
+            let threshold = flow.quack.threshold();
+            let mut buf = vec![0; Q::encoded_len(threshold) + encoding::MAX_EXTENSIONS_LEN];
+            let len = encoding::encode_message(&flow.quack, None, &flow.extensions(), &mut buf)?;
+            Ok(Response::Quack {
+                count: flow.quack.count(),
+                threshold,
+                message: buf[..len].iter().map(|b| format!("{:02x}", b)).collect(),
+            })
+        }
+        Request::ResetFlow { src, dst } => {
+            let addr_key = sidekick_multi::addr_key(src, dst);
+            if !sc.senders().contains_key(&addr_key) {
+                return Err(format!("no flow from {} to {}", src, dst));
+            }
+            sc.reset(&addr_key);
+            Ok(Response::Ok)
+        }
+        Request::SetThreshold {
+            threshold,
+            rule: None,
+        } => {
+            config::validate_threshold(threshold)?;
+            for (i, rule) in sc.rules().iter().enumerate() {
+                rule.validate(threshold)
+                    .map_err(|e| format!("rule {}: {}", i, e))?;
+            }
+            info!("setting the threshold to {}", threshold);
+            sc.threshold = threshold;
+            Ok(Response::Ok)
+        }
+        Request::SetThreshold {
+            threshold,
+            rule: Some(i),
+        } => {
+            let mut rules = sc.rules().to_vec();
+            rule_mut(&mut rules, i)?.threshold = Some(threshold);
+            set_rules(sc, rules, rules_changed)
+        }
+        Request::SetFrequency {
+            rule: i,
+            frequency_ms,
+            frequency_pkts,
+        } => {
+            let mut rules = sc.rules().to_vec();
+            let rule = rule_mut(&mut rules, i)?;
+            rule.frequency_ms = frequency_ms;
+            rule.frequency_pkts = frequency_pkts;
+            set_rules(sc, rules, rules_changed)
+        }
+        Request::ListRules => Ok(Response::Rules {
+            rules: sc
+                .rules()
+                .iter()
+                .map(|rule| format!("{:?}", rule))
+                .collect(),
+        }),
+        Request::AddRule { rule } => {
+            let mut rules = sc.rules().to_vec();
+            rules.push(rule);
+            set_rules(sc, rules, rules_changed)
+        }
+        Request::RemoveRule { rule: i } => {
+            let mut rules = sc.rules().to_vec();
+            rule_mut(&mut rules, i)?;
+            rules.remove(i);
+            set_rules(sc, rules, rules_changed)
+        }
+    }
+}
+
+fn rule_mut(rules: &mut [Rule], i: usize) -> Result<&mut Rule, String> {
+    let len = rules.len();
+    rules
+        .get_mut(i)
+        .ok_or(format!("no rule {}: there are {} rules", i, len))
+}
+
+/// Replace the rules and the filter of which flows to quACK, keeping the
+/// `deny` conditions.
+fn set_rules<Q: WireQuack>(
+    sc: &mut SidekickMulti<Q>,
+    rules: Vec<Rule>,
+    rules_changed: Option<&mpsc::UnboundedSender<()>>,
+) -> Result<Response, String> {
+    let rules_changed = rules_changed.ok_or("rules cannot be changed in this sidekick")?;
+    if rules.is_empty() {
+        return Err("no rules".to_string());
+    }
+    for (i, rule) in rules.iter().enumerate() {
+        rule.validate(sc.threshold)
+            .map_err(|e| format!("rule {}: {}", i, e))?;
+    }
+    let deny = sc
+        .filter()
+        .rules
+        .iter()
+        .filter(|rule| rule.action == Action::Deny)
+        .map(|rule| rule.flows.clone())
+        .collect::<Vec<_>>();
+    info!("replacing the rules: {:?}", rules);
+    sc.set_filter(config::filter(deny.into_iter(), &rules));
+    sc.set_rules(rules);
+    rules_changed
+        .send(())
+        .map_err(|_| "stopped applying rule changes".to_string())?;
+    Ok(Response::Ok)
+}
+
+/// Bind the Unix socket to serve the control API on, replacing the socket
+/// left behind by a previous sidekick, if any.
+pub fn bind(path: &Path) -> Result<UnixListener, String> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(format!("{} exists and is not a socket", path.display()));
+        }
+        std::fs::remove_file(path)
+            .map_err(|e| format!("error removing {}: {}", path.display(), e))?;
+    }
+    let listener = UnixListener::bind(path)
+        .map_err(|e| format!("error binding to {}: {:?}", path.display(), e))?;
+    info!("serving the control API on {}", path.display());
+    Ok(listener)
+}
+
+/// Serve the control API of the sidekick to every connection on the
+/// listener.
+pub async fn serve<Q: WireQuack + Send + 'static>(
+    sc: Arc<Mutex<SidekickMulti<Q>>>,
+    listener: UnixListener,
+    rules_changed: Option<mpsc::UnboundedSender<()>>,
+) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("error accepting control connection: {:?}", e);
+                continue;
+            }
+        };
+        let sc = sc.clone();
+        let rules_changed = rules_changed.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, sc, rules_changed).await {
+                debug!("error serving control connection: {}", e);
+            }
+        });
+    }
+}
+
+/// Answer each line of the connection with a line.
+async fn respond<Q: WireQuack>(
+    stream: UnixStream,
+    sc: Arc<Mutex<SidekickMulti<Q>>>,
+    rules_changed: Option<mpsc::UnboundedSender<()>>,
+) -> Result<(), String> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await.map_err(|e| format!("{:?}", e))? {
+        let response = answer(&sc, &line, rules_changed.as_ref());
+        let mut response = serde_json::to_string(&response).map_err(|e| format!("{:?}", e))?;
+        response.push('\n');
+        writer
+            .write_all(response.as_bytes())
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+    }
+    Ok(())
+}
+
+fn answer<Q: WireQuack>(
+    sc: &Mutex<SidekickMulti<Q>>,
+    line: &str,
+    rules_changed: Option<&mpsc::UnboundedSender<()>>,
+) -> Response {
+    let request = match serde_json::from_str::<Request>(line) {
+        Ok(request) => request,
+        Err(e) => {
+            return Response::Error {
+                error: format!("invalid request: {}", e),
+            }
+        }
+    };
+    debug!("{:?}", request);
+    handle(&mut sc.lock().unwrap(), request, rules_changed)
+        .unwrap_or_else(|error| Response::Error { error })
+}
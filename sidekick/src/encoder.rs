@@ -0,0 +1,516 @@
+//! Encoders that accumulate sniffed packet identifiers in different styles of
+//! quACKs, and the machinery to sniff packets into an encoder and emit its
+//! quACK messages.
+//!
+//! The power sum quACK is encoded by `Sidekick`. The strawmen are encoded by
+//! `StrawmanAEncoder`, which echoes the last identifier, and
+//! `StrawmanBEncoder`, which echoes a sliding window of identifiers.
+//!
+//! Packets are usually sniffed from a raw socket, but can also be replayed
+//! from a pcap file with `replay_every_pkts`.
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, info, trace};
+use quack::{StrawmanAQuack, StrawmanBQuack};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::oneshot;
+use tokio::time;
+
+use crate::buffer::{Direction, IdentifierProfile, QuicHeader, UdpParser, BUFFER_SIZE};
+use crate::filter::FlowFilter;
+use crate::socket::SockAddr;
+use crate::stats::{self, SniffCounters};
+use crate::Socket;
+
+// Attempts to connect to the quACK address over TCP, doubling the delay
+// between attempts from 100 ms up to 5 s.
+const TCP_CONNECT_ATTEMPTS: u32 = 20;
+const TCP_CONNECT_DELAY: Duration = Duration::from_millis(100);
+const TCP_CONNECT_MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// A sniffed packet to insert in a quACK.
+#[derive(Clone, Copy, Debug)]
+pub struct Packet {
+    /// The identifier, in the low `bits` bits
+    pub id: u64,
+    /// The IP total length
+    pub len: u16,
+    /// Whether the packet was marked Congestion Experienced
+    pub ce: bool,
+    /// When the packet was captured, if timestamps are enabled
+    pub timestamp: Option<SystemTime>,
+}
+
+/// Style of quACK to encode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Style {
+    /// Power sum quACK
+    PowerSum,
+    /// Strawman A, which echoes the last identifier
+    StrawmanA,
+    /// Strawman B, which echoes a sliding window of identifiers
+    StrawmanB,
+}
+
+impl std::str::FromStr for Style {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "power-sum" => Ok(Style::PowerSum),
+            "strawman-a" => Ok(Style::StrawmanA),
+            "strawman-b" => Ok(Style::StrawmanB),
+            _ => Err(format!(
+                "style must be power-sum, strawman-a, or strawman-b: {}",
+                s
+            )),
+        }
+    }
+}
+
+/// Accumulates sniffed packets in a quACK.
+pub trait QuackEncoder {
+    /// Insert a sniffed packet.
+    fn insert(&mut self, packet: &Packet);
+
+    /// Reset the quACK state.
+    fn reset(&mut self);
+
+    /// Number of identifiers in the quACK.
+    fn count(&self) -> u32;
+
+    /// Snapshot the quACK as a message, replacing the contents of the buffer.
+    /// Leaves the buffer empty if there is nothing to quACK.
+    fn encode(&mut self, buf: &mut Vec<u8>) -> Result<(), String>;
+}
+
+/// Encodes strawman A quACKs, which echo the last identifier.
+#[derive(Clone, Debug, Default)]
+pub struct StrawmanAEncoder {
+    last: Option<u32>,
+}
+
+impl QuackEncoder for StrawmanAEncoder {
+    fn insert(&mut self, packet: &Packet) {
+        self.last = Some(packet.id as u32);
+    }
+
+    fn reset(&mut self) {
+        self.last = None;
+    }
+
+    fn count(&self) -> u32 {
+        self.last.is_some() as u32
+    }
+
+    fn encode(&mut self, buf: &mut Vec<u8>) -> Result<(), String> {
+        buf.clear();
+        // Nothing to echo since the last reset
+        let sidekick_id = match self.last {
+            Some(sidekick_id) => sidekick_id,
+            None => return Ok(()),
+        };
+        bincode::serialize_into(buf, &StrawmanAQuack { sidekick_id }).map_err(|e| e.to_string())
+    }
+}
+
+/// Encodes strawman B quACKs, which echo a sliding window of identifiers.
+#[derive(Clone, Debug)]
+pub struct StrawmanBEncoder {
+    quack: StrawmanBQuack,
+}
+
+impl StrawmanBEncoder {
+    /// Create an encoder that echoes the last `window_size` identifiers.
+    pub fn new(window_size: usize) -> Self {
+        Self {
+            quack: StrawmanBQuack {
+                window: VecDeque::new(),
+                window_size,
+            },
+        }
+    }
+}
+
+impl QuackEncoder for StrawmanBEncoder {
+    fn insert(&mut self, packet: &Packet) {
+        self.quack.window.push_back(packet.id as u32);
+        if self.quack.window.len() > self.quack.window_size {
+            self.quack.window.pop_front();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.quack.window.clear();
+    }
+
+    fn count(&self) -> u32 {
+        self.quack.window.len() as u32
+    }
+
+    fn encode(&mut self, buf: &mut Vec<u8>) -> Result<(), String> {
+        buf.clear();
+        bincode::serialize_into(buf, &self.quack).map_err(|e| e.to_string())
+    }
+}
+
+/// Which packets to sniff on an interface.
+#[derive(Clone, Debug)]
+pub struct SniffConfig {
+    /// Interface to listen on
+    pub interface: String,
+    /// Number of identifier bits
+    pub bits: usize,
+    /// Direction of the packets to sniff
+    pub direction: Direction,
+    /// Whether to timestamp sniffed packets
+    pub timestamps: bool,
+    /// Whether to split frames coalesced by GRO into their UDP segments
+    pub gso: bool,
+    /// My IPv4 address to receive quACK resets, if any
+    pub my_ipv4_addr: Option<[u8; 4]>,
+    /// My port to receive acknowledged quACKs on instead of resets, if any
+    pub ack_port: Option<u16>,
+    /// Which flows to quACK
+    pub filter: FlowFilter,
+    /// Whether to only quACK QUIC packets with short headers, skipping
+    /// handshake packets and the datagrams they are coalesced in
+    pub short_headers_only: bool,
+    /// Where the identifier is in the packets of the encrypted protocol
+    pub profile: IdentifierProfile,
+    /// Counts of the sniffed packets by what was done with them
+    pub counters: Arc<SniffCounters>,
+}
+
+/// A sniffed packet and what to do with it.
+enum Sniffed {
+    Skip,
+    Reset,
+    Insert(Packet),
+}
+
+impl SniffConfig {
+    /// Open the raw socket to sniff on, with timestamps and GSO metadata if
+    /// enabled.
+    pub fn open_socket(&self) -> Result<Socket, String> {
+        let mut sock = Socket::new(self.interface.clone())?;
+        sock.set_promiscuous()?;
+        if self.timestamps {
+            sock.enable_timestamps()?;
+        }
+        if self.gso {
+            sock.enable_vnet_hdr()?;
+        }
+        Ok(sock)
+    }
+
+    fn parse(
+        &self,
+        n: isize,
+        buf: &mut [u8; BUFFER_SIZE],
+        addr: &libc::sockaddr_ll,
+        timestamp: Option<SystemTime>,
+    ) -> Sniffed {
+        trace!("received {} bytes: {:?}", n, buf);
+        stats::incr(&self.counters.frames);
+        if self.direction != addr.sll_pkttype.into() {
+            stats::incr(&self.counters.wrong_direction);
+            return Sniffed::Skip;
+        }
+        if addr.sll_protocol != (libc::ETH_P_IP as u16).to_be() {
+            trace!("not IP packet: {}", addr.sll_protocol);
+            stats::incr(&self.counters.not_ip);
+            return Sniffed::Skip;
+        }
+        self.parse_ip(n, buf, timestamp)
+    }
+
+    /// Parse an Ethernet frame containing an IPv4 packet.
+    fn parse_ip(
+        &self,
+        n: isize,
+        buf: &mut [u8; BUFFER_SIZE],
+        timestamp: Option<SystemTime>,
+    ) -> Sniffed {
+        let (validation, n) = UdpParser::validate(buf, n);
+        self.counters.record(validation);
+        if !validation.has_payload() {
+            trace!("skipped {:?} packet", validation);
+            return Sniffed::Skip;
+        }
+        let buf = &*buf;
+
+        // Reset the quack if the dst IP is our own (and not for another e2e
+        // quic connection). Acknowledged quACKs are handled separately.
+        if self
+            .my_ipv4_addr
+            .map_or(false, |addr| UdpParser::parse_dst_ip(buf) == addr)
+        {
+            if self.ack_port == Some(UdpParser::parse_dst_port(buf)) {
+                return Sniffed::Skip;
+            }
+            // TODO: check if dst port corresponds to this connection
+            stats::incr(&self.counters.resets);
+            return Sniffed::Reset;
+        }
+
+        // Skip the flows we were not asked to quACK.
+        let addr_key = UdpParser::parse_addr_key(buf);
+        if !self
+            .filter
+            .allows(&addr_key, UdpParser::parse_first_byte(buf))
+        {
+            trace!("filtered flow: {:?}", addr_key);
+            stats::incr(&self.counters.filtered);
+            return Sniffed::Skip;
+        }
+        if self.short_headers_only {
+            let header = UdpParser::parse_quic_header(buf);
+            if header != QuicHeader::Short {
+                trace!("skipped {:?} header: {:?}", header, addr_key);
+                return Sniffed::Skip;
+            }
+        }
+
+        // Otherwise parse the identifier and insert it into the quack.
+        let id = match UdpParser::parse_identifier_profile(
+            buf,
+            n.max(0) as usize,
+            self.bits,
+            &self.profile,
+        ) {
+            Some(id) => id,
+            None => {
+                trace!("no {:?} identifier in {} bytes", self.profile, n);
+                stats::incr(&self.counters.underfilled);
+                return Sniffed::Skip;
+            }
+        };
+        debug!("insert {} ({:#10x})", id, id);
+        stats::incr(&self.counters.inserts);
+        Sniffed::Insert(Packet {
+            id,
+            len: UdpParser::parse_ip_len(buf),
+            ce: UdpParser::is_ce(buf),
+            timestamp,
+        })
+    }
+}
+
+/// Where to emit quACK messages.
+pub enum QuackSink {
+    /// Send each message in a UDP datagram to the address
+    Udp { sock: UdpSocket, addr: SocketAddr },
+    /// Write each message to the TCP stream
+    Tcp(TcpStream),
+}
+
+impl QuackSink {
+    /// Send quACKs to the UDP socket address.
+    pub async fn udp(addr: SocketAddr) -> Result<Self, String> {
+        let sock = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .map_err(|e| format!("error binding to UDP socket: {:?}", e))?;
+        Ok(Self::Udp { sock, addr })
+    }
+
+    /// Send quACKs over a TCP connection to the socket address, retrying
+    /// with backoff until the connection is established. Errors if the peer
+    /// is still unreachable after `TCP_CONNECT_ATTEMPTS` attempts.
+    pub async fn tcp(addr: SocketAddr) -> Result<Self, String> {
+        let mut delay = TCP_CONNECT_DELAY;
+        let mut attempt = 1;
+        let stream = loop {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => break stream,
+                Err(e) if attempt >= TCP_CONNECT_ATTEMPTS => {
+                    return Err(format!("error connecting to {}: {:?}", addr, e));
+                }
+                Err(e) => debug!("error connecting to {}, retrying: {:?}", addr, e),
+            }
+            time::sleep(delay).await;
+            delay = (delay * 2).min(TCP_CONNECT_MAX_DELAY);
+            attempt += 1;
+        };
+        stream
+            .set_nodelay(true)
+            .map_err(|e| format!("error setting nodelay: {:?}", e))?;
+        Ok(Self::Tcp(stream))
+    }
+
+    /// Send a quACK message.
+    pub async fn send(&mut self, buf: &[u8]) -> Result<(), String> {
+        match self {
+            Self::Udp { sock, addr } => sock.send_to(buf, *addr).await.map(|_| ()),
+            Self::Tcp(stream) => match stream.write_all(buf).await {
+                Ok(()) => stream.flush().await,
+                Err(e) => Err(e),
+            },
+        }
+        .map_err(|e| format!("error sending quack: {:?}", e))
+    }
+}
+
+/// Start the raw socket that sniffs packets on the configured interface and
+/// inserts them into the encoder, in a blocking thread. Returns a channel that
+/// indicates when the first packet is sniffed.
+pub fn start<E: QuackEncoder + Send + 'static>(
+    encoder: Arc<Mutex<E>>,
+    config: SniffConfig,
+) -> Result<oneshot::Receiver<()>, String> {
+    let mut sock = config.open_socket()?;
+
+    // Creates the channel that indicates when the first packet is sniffed.
+    let (tx, rx) = oneshot::channel();
+
+    // Loop over received packets
+    tokio::task::spawn_blocking(move || {
+        info!(
+            "tapping socket on fd={} interface={}",
+            sock.fd, config.interface
+        );
+        let mut buf: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+        let mut addr = SockAddr::new_sockaddr_ll();
+        let mut tx = Some(tx);
+        while let Ok((n, timestamp)) = sock.recv_segment(&mut addr, &mut buf) {
+            match config.parse(n, &mut buf, &addr, timestamp) {
+                Sniffed::Skip => {}
+                Sniffed::Reset => encoder.lock().unwrap().reset(),
+                Sniffed::Insert(packet) => {
+                    let mut encoder = encoder.lock().unwrap();
+                    if let Some(tx) = tx.take() {
+                        tx.send(()).unwrap();
+                    }
+                    encoder.insert(&packet);
+                }
+            }
+        }
+    });
+    Ok(rx)
+}
+
+/// Emit a snapshot of the encoder's quACK at the specified frequency, starting
+/// when the first packet is sniffed.
+pub async fn emit_every<E: QuackEncoder>(
+    encoder: Arc<Mutex<E>>,
+    rx: oneshot::Receiver<()>,
+    frequency: Duration,
+    sink: &mut QuackSink,
+) -> Result<(), String> {
+    rx.await
+        .map_err(|_| "couldn't receive notice that 1st packet was sniffed")?;
+    let mut buf = vec![];
+    let mut interval = time::interval(frequency);
+    // The first tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        encoder.lock().unwrap().encode(&mut buf)?;
+        if buf.is_empty() {
+            continue;
+        }
+        trace!("quack {} bytes", buf.len());
+        sink.send(&buf).await?;
+    }
+}
+
+/// Sniff packets on the configured interface and insert them into the
+/// encoder, emitting a snapshot of its quACK every `frequency_pkts` packets.
+pub async fn run_every_pkts<E: QuackEncoder>(
+    encoder: &mut E,
+    config: &SniffConfig,
+    frequency_pkts: usize,
+    sink: &mut QuackSink,
+) -> Result<(), String> {
+    let mut sock = config.open_socket()?;
+    info!(
+        "tapping socket on fd={} interface={}",
+        sock.fd, config.interface
+    );
+    let mut buf: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+    let mut addr = SockAddr::new_sockaddr_ll();
+    let mut mod_count = 0;
+    let mut quack_buf = vec![];
+    while let Ok((n, timestamp)) = sock.recv_segment(&mut addr, &mut buf) {
+        match config.parse(n, &mut buf, &addr, timestamp) {
+            Sniffed::Skip => {}
+            Sniffed::Reset => encoder.reset(),
+            Sniffed::Insert(packet) => {
+                encoder.insert(&packet);
+                mod_count = (mod_count + 1) % frequency_pkts;
+                if mod_count == 0 {
+                    encoder.encode(&mut quack_buf)?;
+                    if quack_buf.is_empty() {
+                        continue;
+                    }
+                    trace!("quack {}", encoder.count());
+                    sink.send(&quack_buf).await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replay the Ethernet frames captured in a pcap file into the encoder,
+/// regardless of their direction, emitting a snapshot of its quACK every
+/// `frequency_pkts` packets to the sink, or logging its count if there is no
+/// sink. Returns the number of packets inserted.
+pub async fn replay_every_pkts<E: QuackEncoder>(
+    encoder: &mut E,
+    config: &SniffConfig,
+    path: &Path,
+    frequency_pkts: usize,
+    mut sink: Option<&mut QuackSink>,
+) -> Result<usize, String> {
+    let mut capture = pcap::Capture::from_file(path)
+        .map_err(|e| format!("error opening {}: {:?}", path.display(), e))?;
+    info!("replaying {}", path.display());
+    let ip_protocol = (libc::ETH_P_IP as u16).to_be_bytes();
+    let mut buf: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+    let mut inserted = 0;
+    let mut quack_buf = vec![];
+    while let Ok(frame) = capture.next_packet() {
+        let n = frame.data.len().min(BUFFER_SIZE);
+        buf.fill(0);
+        buf[..n].copy_from_slice(&frame.data[..n]);
+        stats::incr(&config.counters.frames);
+        if buf[12..14] != ip_protocol {
+            trace!("not IP packet: {:?}", &buf[12..14]);
+            stats::incr(&config.counters.not_ip);
+            continue;
+        }
+        let timestamp = config.timestamps.then(|| {
+            UNIX_EPOCH
+                + Duration::new(
+                    frame.header.ts.tv_sec as u64,
+                    frame.header.ts.tv_usec as u32 * 1000,
+                )
+        });
+        match config.parse_ip(frame.data.len() as isize, &mut buf, timestamp) {
+            Sniffed::Skip => {}
+            Sniffed::Reset => encoder.reset(),
+            Sniffed::Insert(packet) => {
+                encoder.insert(&packet);
+                inserted += 1;
+                if inserted % frequency_pkts != 0 {
+                    continue;
+                }
+                if let Some(sink) = sink.as_deref_mut() {
+                    encoder.encode(&mut quack_buf)?;
+                    if !quack_buf.is_empty() {
+                        sink.send(&quack_buf).await?;
+                    }
+                } else {
+                    info!("quack {}", encoder.count());
+                }
+            }
+        }
+    }
+    Ok(inserted)
+}
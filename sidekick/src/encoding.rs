@@ -0,0 +1,722 @@
+//! Compact, fixed-layout wire encoding of a power sum quACK.
+//!
+//! All integers are big-endian. Words are the width of the quACK's modular
+//! arithmetic, i.e., 2 bytes for `PowerSumQuackU16`, 4 bytes for
+//! `PowerSumQuackU32`, and 8 bytes for `PowerSumQuackU64`. The threshold and
+//! word width are not sent since the quACK receiver already knows them, and
+//! the last value is only meaningful if the count is non-zero.
+//!
+//! ```text
+//! 0         1         2                   6              6+W
+//! +---------+---------+-------------------+--------------+-----------
+//! | version | k (u8)  | count (u32)       | last value   | k * word
+//! +---------+---------+-------------------+--------------+-----------
+//! ```
+//!
+//! The trailing `k` words are the first `k` power sums. A sidekick may
+//! truncate the quACK to fewer power sums than its threshold, in which case
+//! the decoded quACK can only recover up to `k` missing packets.
+//!
+//! The low 4 bits of the version byte are the version and the high 4 bits
+//! are flags for optional extension fields, which follow the power sums in
+//! the order of their flag bits:
+//!
+//! * `FLAG_TIMESTAMPS`: the capture time of the last inserted packet and the
+//!   emission time of the quACK, each a u64 in ns since the UNIX epoch.
+//! * `FLAG_BYTES`: the cumulative number of IP bytes inserted, a u64.
+//! * `FLAG_CE`: the cumulative number of inserted packets marked with the ECN
+//!   Congestion Experienced codepoint, a u32.
+//! * `FLAG_TLV`: type-length-value fields up to the end of the message, each
+//!   a u8 type, a u8 length, and the value. Unknown types are skipped.
+//!
+//! The known TLV types are:
+//!
+//! * `TLV_WINDOW`: the oldest identifier in a sliding-window quACK, a u64. A
+//!   sliding-window quACK only contains the last identifiers the sidekick
+//!   received, starting with this one.
+//! * `TLV_RECENT_IDS`: the identifiers the sidekick received most recently,
+//!   oldest first, each a word. The sidekick attaches them as a fallback to
+//!   the next quACK after the end host requests them with `ACK_FALLBACK`.
+//!
+//! An end host that acknowledges quACKs sends the sidekick either a quACK
+//! message that it fully processed, or a single byte: `ACK_RESET` to reset
+//! the quACK, or `ACK_FALLBACK` after it could not decode a quACK.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use quack::arithmetic::{ModularArithmetic, ModularInteger};
+use quack::{PowerSumQuack, PowerSumQuackU16, PowerSumQuackU32, PowerSumQuackU64};
+
+/// Version of the wire encoding, in the low bits of the first byte. Version
+/// 1 had no flags or extension fields.
+pub const VERSION: u8 = 2;
+const VERSION_MASK: u8 = 0x0f;
+/// Flag indicating the timestamps extension is present.
+pub const FLAG_TIMESTAMPS: u8 = 0x10;
+/// Flag indicating the byte count extension is present.
+pub const FLAG_BYTES: u8 = 0x20;
+/// Flag indicating the CE count extension is present.
+pub const FLAG_CE: u8 = 0x40;
+/// Flag indicating type-length-value fields are present.
+pub const FLAG_TLV: u8 = 0x80;
+const KNOWN_FLAGS: u8 = FLAG_TIMESTAMPS | FLAG_BYTES | FLAG_CE | FLAG_TLV;
+/// Type of the sliding-window TLV field.
+pub const TLV_WINDOW: u8 = 1;
+/// Type of the recent identifiers TLV field.
+pub const TLV_RECENT_IDS: u8 = 2;
+/// Maximum length of the value of a TLV field.
+const MAX_TLV_LEN: usize = u8::MAX as usize;
+/// Length of the type and length of a TLV field.
+const TLV_HEADER_LEN: usize = 2;
+/// Maximum number of bytes of extensions in a quACK message.
+pub const MAX_EXTENSIONS_LEN: usize = Extensions::flags_len(KNOWN_FLAGS & !FLAG_TLV)
+    + (TLV_HEADER_LEN + 8)
+    + (TLV_HEADER_LEN + MAX_TLV_LEN);
+/// Length of the version, k, and count fields.
+const PREFIX_LEN: usize = 6;
+/// Maximum number of power sums that can be encoded.
+pub const MAX_POWER_SUMS: usize = u8::MAX as usize;
+/// Single-byte acknowledgment that resets the quACK.
+pub const ACK_RESET: u8 = 0;
+/// Single-byte acknowledgment that requests the recent identifiers.
+pub const ACK_FALLBACK: u8 = 1;
+
+/// A power sum quACK that can be encoded on the wire, whose elements are
+/// sidekick identifiers of up to `8 * WORD_LEN` bits.
+pub trait WireQuack: PowerSumQuack + Clone + Sized {
+    /// Number of bytes in each power sum and in the last value.
+    const WORD_LEN: usize;
+
+    /// Converts an identifier parsed from a packet to a quACK element.
+    fn element(id: u64) -> Self::Element;
+
+    /// Write the last value and the first `num_sums` power sums as words.
+    fn encode_words(&self, num_sums: usize, buf: &mut [u8]);
+
+    /// Read the last value and power sums from words.
+    fn decode_words(count: u32, buf: &[u8]) -> Self;
+
+    /// Number of bytes needed to encode a quACK with `num_sums` power sums,
+    /// without extensions.
+    fn encoded_len(num_sums: usize) -> usize {
+        PREFIX_LEN + Self::WORD_LEN * (1 + num_sums)
+    }
+
+    /// Encode the quACK into the buffer, including only the first `num_sums`
+    /// power sums, or all of them if `None`. Returns the number of bytes
+    /// written.
+    fn encode_into(&self, num_sums: Option<usize>, buf: &mut [u8]) -> Result<usize, String> {
+        encode_message(self, num_sums, &Extensions::default(), buf)
+    }
+
+    /// Decode a quACK from the buffer, ignoring any extensions. The threshold
+    /// of the decoded quACK is the number of power sums that were encoded.
+    fn decode(buf: &[u8]) -> Result<Self, String> {
+        decode_message(buf).map(|(quack, _)| quack)
+    }
+}
+
+/// Optional fields of a quACK message.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Extensions {
+    pub timestamps: Option<Timestamps>,
+    /// Cumulative number of IP bytes in the packets inserted in the quACK.
+    pub bytes: Option<u64>,
+    /// Cumulative number of packets inserted in the quACK that were marked
+    /// Congestion Experienced.
+    pub ce_count: Option<u32>,
+    /// Oldest identifier in a sliding-window quACK.
+    pub window_start: Option<u64>,
+    /// Identifiers of the packets the sidekick received most recently, oldest
+    /// first, at most `max_recent_ids` of them.
+    pub recent_ids: Option<Vec<u64>>,
+}
+
+/// Maximum number of recent identifiers of `word_len` bytes in a quACK
+/// message.
+pub const fn max_recent_ids(word_len: usize) -> usize {
+    MAX_TLV_LEN / word_len
+}
+
+/// Timestamps from the sidekick's clock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timestamps {
+    /// When the sidekick captured the last inserted packet.
+    pub captured: SystemTime,
+    /// When the sidekick emitted the quACK.
+    pub emitted: SystemTime,
+}
+
+impl Timestamps {
+    /// Returns a near-path RTT sample given the time between the end host
+    /// sending the packet with the quACK's last value and receiving the
+    /// quACK. Subtracts the time the sidekick held the packet before quACKing.
+    pub fn near_rtt(&self, since_sent: Duration) -> Option<Duration> {
+        let held = self.emitted.duration_since(self.captured).ok()?;
+        since_sent.checked_sub(held)
+    }
+
+    /// Returns the one-way delay from the end host to the sidekick given the
+    /// time the packet with the quACK's last value was sent. Only meaningful
+    /// if the end host and sidekick clocks are synchronized.
+    pub fn one_way_delay(&self, sent: SystemTime) -> Option<Duration> {
+        self.captured.duration_since(sent).ok()
+    }
+}
+
+fn to_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn from_nanos(buf: &[u8]) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos(u64::from_be_bytes(buf.try_into().unwrap()))
+}
+
+impl Extensions {
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.timestamps.is_some() {
+            flags |= FLAG_TIMESTAMPS;
+        }
+        if self.bytes.is_some() {
+            flags |= FLAG_BYTES;
+        }
+        if self.ce_count.is_some() {
+            flags |= FLAG_CE;
+        }
+        if self.window_start.is_some() || self.recent_ids.is_some() {
+            flags |= FLAG_TLV;
+        }
+        flags
+    }
+
+    /// Number of bytes of the fixed-length extensions indicated by the flags.
+    const fn flags_len(flags: u8) -> usize {
+        let mut len = 0;
+        if flags & FLAG_TIMESTAMPS != 0 {
+            len += 16;
+        }
+        if flags & FLAG_BYTES != 0 {
+            len += 8;
+        }
+        if flags & FLAG_CE != 0 {
+            len += 4;
+        }
+        len
+    }
+
+    /// Number of bytes of the TLV fields, with words of `word_len` bytes.
+    fn tlv_len(&self, word_len: usize) -> usize {
+        let mut len = 0;
+        if self.window_start.is_some() {
+            len += TLV_HEADER_LEN + 8;
+        }
+        if let Some(recent_ids) = &self.recent_ids {
+            len += TLV_HEADER_LEN + word_len * recent_ids.len();
+        }
+        len
+    }
+
+    /// Number of bytes needed to encode the extensions, with words of
+    /// `word_len` bytes.
+    pub fn encoded_len(&self, word_len: usize) -> usize {
+        Self::flags_len(self.flags()) + self.tlv_len(word_len)
+    }
+
+    fn encode(&self, word_len: usize, buf: &mut [u8]) {
+        let mut offset = 0;
+        if let Some(timestamps) = &self.timestamps {
+            buf[offset..(offset + 8)].copy_from_slice(&to_nanos(timestamps.captured).to_be_bytes());
+            buf[(offset + 8)..(offset + 16)]
+                .copy_from_slice(&to_nanos(timestamps.emitted).to_be_bytes());
+            offset += 16;
+        }
+        if let Some(bytes) = self.bytes {
+            buf[offset..(offset + 8)].copy_from_slice(&bytes.to_be_bytes());
+            offset += 8;
+        }
+        if let Some(ce_count) = self.ce_count {
+            buf[offset..(offset + 4)].copy_from_slice(&ce_count.to_be_bytes());
+            offset += 4;
+        }
+        if let Some(window_start) = self.window_start {
+            buf[offset] = TLV_WINDOW;
+            buf[offset + 1] = 8;
+            offset += TLV_HEADER_LEN;
+            buf[offset..(offset + 8)].copy_from_slice(&window_start.to_be_bytes());
+            offset += 8;
+        }
+        if let Some(recent_ids) = &self.recent_ids {
+            buf[offset] = TLV_RECENT_IDS;
+            buf[offset + 1] = (word_len * recent_ids.len()) as u8;
+            offset += TLV_HEADER_LEN;
+            for id in recent_ids {
+                buf[offset..(offset + word_len)]
+                    .copy_from_slice(&id.to_be_bytes()[(8 - word_len)..]);
+                offset += word_len;
+            }
+        }
+        debug_assert_eq!(offset, buf.len());
+    }
+
+    /// Decode the extensions from the buffer, which must contain exactly the
+    /// extensions indicated by the flags, with words of `word_len` bytes.
+    fn decode(flags: u8, word_len: usize, buf: &[u8]) -> Result<Self, String> {
+        if buf.len() < Self::flags_len(flags) {
+            return Err(format!("truncated extensions: {} bytes", buf.len()));
+        }
+        if flags & FLAG_TLV == 0 && buf.len() != Self::flags_len(flags) {
+            return Err(format!(
+                "bad extensions length: {} != {}",
+                buf.len(),
+                Self::flags_len(flags)
+            ));
+        }
+        let mut offset = 0;
+        let mut extensions = Extensions::default();
+        if flags & FLAG_TIMESTAMPS != 0 {
+            extensions.timestamps = Some(Timestamps {
+                captured: from_nanos(&buf[offset..(offset + 8)]),
+                emitted: from_nanos(&buf[(offset + 8)..(offset + 16)]),
+            });
+            offset += 16;
+        }
+        if flags & FLAG_BYTES != 0 {
+            let bytes = buf[offset..(offset + 8)].try_into().unwrap();
+            extensions.bytes = Some(u64::from_be_bytes(bytes));
+            offset += 8;
+        }
+        if flags & FLAG_CE != 0 {
+            let ce_count = buf[offset..(offset + 4)].try_into().unwrap();
+            extensions.ce_count = Some(u32::from_be_bytes(ce_count));
+            offset += 4;
+        }
+        if flags & FLAG_TLV != 0 {
+            while offset < buf.len() {
+                if offset + TLV_HEADER_LEN > buf.len() {
+                    return Err("truncated TLV header".to_string());
+                }
+                let (tlv_type, len) = (buf[offset], buf[offset + 1] as usize);
+                offset += TLV_HEADER_LEN;
+                if offset + len > buf.len() {
+                    return Err(format!("truncated TLV {}: {} bytes", tlv_type, len));
+                }
+                // Skip unknown types.
+                let value = &buf[offset..(offset + len)];
+                if tlv_type == TLV_WINDOW {
+                    let window_start = value
+                        .try_into()
+                        .map_err(|_| format!("bad window length: {}", len))?;
+                    extensions.window_start = Some(u64::from_be_bytes(window_start));
+                } else if tlv_type == TLV_RECENT_IDS {
+                    if len % word_len != 0 {
+                        return Err(format!("bad recent identifiers length: {}", len));
+                    }
+                    let recent_ids = value
+                        .chunks(word_len)
+                        .map(|word| word.iter().fold(0, |id, &b| (id << 8) | b as u64))
+                        .collect();
+                    extensions.recent_ids = Some(recent_ids);
+                }
+                offset += len;
+            }
+        }
+        Ok(extensions)
+    }
+
+    /// Returns the near-path goodput in bits/s since an earlier quACK message
+    /// from the same sidekick, given the time elapsed between receiving them.
+    /// Uses the sidekick's capture timestamps instead if both messages have
+    /// them.
+    pub fn goodput_since(&self, earlier: &Extensions, elapsed: Duration) -> Option<f64> {
+        let bytes = self.bytes?.checked_sub(earlier.bytes?)?;
+        let elapsed = match (self.timestamps, earlier.timestamps) {
+            (Some(now), Some(then)) => now.captured.duration_since(then.captured).ok()?,
+            _ => elapsed,
+        };
+        if elapsed.is_zero() {
+            return None;
+        }
+        Some(bytes as f64 * 8.0 / elapsed.as_secs_f64())
+    }
+
+    /// Returns the number of packets marked Congestion Experienced since an
+    /// earlier quACK message from the same sidekick.
+    pub fn ce_marks_since(&self, earlier: &Extensions) -> Option<u32> {
+        self.ce_count?.checked_sub(earlier.ce_count?)
+    }
+}
+
+/// Encode the quACK and extensions into the buffer, including only the first
+/// `num_sums` power sums, or all of them if `None`. Returns the number of
+/// bytes written.
+pub fn encode_message<Q: WireQuack>(
+    quack: &Q,
+    num_sums: Option<usize>,
+    extensions: &Extensions,
+    buf: &mut [u8],
+) -> Result<usize, String> {
+    let threshold = quack.threshold();
+    let k = num_sums.unwrap_or(threshold);
+    if k > threshold || k > MAX_POWER_SUMS {
+        return Err(format!("cannot encode {} power sums", k));
+    }
+    let base_len = Q::encoded_len(k);
+    if let Some(recent_ids) = &extensions.recent_ids {
+        if recent_ids.len() > max_recent_ids(Q::WORD_LEN) {
+            return Err(format!("cannot encode {} recent ids", recent_ids.len()));
+        }
+    }
+    let len = base_len + extensions.encoded_len(Q::WORD_LEN);
+    if buf.len() < len {
+        return Err(format!("buffer too small: {} < {}", buf.len(), len));
+    }
+    buf[0] = VERSION | extensions.flags();
+    buf[1] = k as u8;
+    buf[2..PREFIX_LEN].copy_from_slice(&quack.count().to_be_bytes());
+    quack.encode_words(k, &mut buf[PREFIX_LEN..base_len]);
+    extensions.encode(Q::WORD_LEN, &mut buf[base_len..len]);
+    Ok(len)
+}
+
+/// Decode a quACK and its extensions from the buffer. The threshold of the
+/// decoded quACK is the number of power sums that were encoded.
+pub fn decode_message<Q: WireQuack>(buf: &[u8]) -> Result<(Q, Extensions), String> {
+    if buf.len() < Q::encoded_len(0) {
+        return Err(format!("truncated header: {} bytes", buf.len()));
+    }
+    if buf[0] & VERSION_MASK != VERSION {
+        return Err(format!("unknown version: {}", buf[0] & VERSION_MASK));
+    }
+    let flags = buf[0] & !VERSION_MASK;
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(format!("unknown flags: {:#x}", flags));
+    }
+    let k = buf[1] as usize;
+    let base_len = Q::encoded_len(k);
+    if buf.len() < base_len {
+        return Err(format!(
+            "truncated quACK with {} power sums: {} < {}",
+            k,
+            buf.len(),
+            base_len
+        ));
+    }
+    let extensions = Extensions::decode(flags, Q::WORD_LEN, &buf[base_len..])?;
+    let count = u32::from_be_bytes([buf[2], buf[3], buf[4], buf[5]]);
+    let quack = Q::decode_words(count, &buf[PREFIX_LEN..base_len]);
+    Ok((quack, extensions))
+}
+
+macro_rules! impl_wire_quack {
+    ($quack:ty, $element:ty) => {
+        impl WireQuack for $quack {
+            const WORD_LEN: usize = std::mem::size_of::<$element>();
+
+            fn element(id: u64) -> $element {
+                id as $element
+            }
+
+            fn encode_words(&self, num_sums: usize, buf: &mut [u8]) {
+                let words = buf.chunks_exact_mut(Self::WORD_LEN);
+                let values = std::iter::once(self.last_value().unwrap_or(0))
+                    .chain(self.power_sums().iter().take(num_sums).map(|x| x.value()));
+                for (word, value) in words.zip(values) {
+                    word.copy_from_slice(&value.to_be_bytes());
+                }
+            }
+
+            fn decode_words(count: u32, buf: &[u8]) -> Self {
+                let mut words = buf
+                    .chunks_exact(Self::WORD_LEN)
+                    .map(|x| <$element>::from_be_bytes(x.try_into().unwrap()));
+                let last_value = words.next().filter(|_| count != 0);
+                let power_sums = words.map(ModularInteger::<$element>::new).collect();
+                Self::from_power_sums(power_sums, count, last_value)
+            }
+        }
+    };
+}
+
+impl_wire_quack!(PowerSumQuackU16, u16);
+impl_wire_quack!(PowerSumQuackU32, u32);
+impl_wire_quack!(PowerSumQuackU64, u64);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A quACK with a threshold of 3 and the identifiers 1 and 2, whose power
+    /// sums 3, 5, and 9 are small enough not to depend on the modulus.
+    fn quack<Q: WireQuack>() -> Q {
+        let mut quack = Q::new(3);
+        quack.insert(Q::element(1));
+        quack.insert(Q::element(2));
+        quack
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s = s.replace(' ', "");
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..(i + 2)], 16).unwrap())
+            .collect()
+    }
+
+    /// Check that the quACK and extensions encode to the expected bytes, and
+    /// that the bytes decode and encode back to themselves.
+    fn check<Q: WireQuack>(num_sums: Option<usize>, extensions: Extensions, expected: &str) {
+        let expected = hex(expected);
+        let mut buf = vec![0; Q::encoded_len(3) + MAX_EXTENSIONS_LEN + 2 * TLV_HEADER_LEN + 16];
+        let len = encode_message(&quack::<Q>(), num_sums, &extensions, &mut buf).unwrap();
+        assert_eq!(&buf[..len], &expected[..]);
+
+        let (decoded, decoded_extensions) = decode_message::<Q>(&expected).unwrap();
+        assert_eq!(decoded.count(), 2);
+        assert_eq!(decoded.threshold(), num_sums.unwrap_or(3));
+        assert_eq!(decoded_extensions, extensions);
+        let len = encode_message(&decoded, None, &decoded_extensions, &mut buf).unwrap();
+        assert_eq!(&buf[..len], &expected[..]);
+    }
+
+    fn at_nanos(nanos: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_nanos(nanos)
+    }
+
+    #[test]
+    fn test_encode_u16() {
+        check::<PowerSumQuackU16>(
+            None,
+            Extensions::default(),
+            "02 03 00000002 0002 0003 0005 0009",
+        );
+    }
+
+    #[test]
+    fn test_encode_u32() {
+        check::<PowerSumQuackU32>(
+            None,
+            Extensions::default(),
+            "02 03 00000002 00000002 00000003 00000005 00000009",
+        );
+    }
+
+    #[test]
+    fn test_encode_u64() {
+        check::<PowerSumQuackU64>(
+            None,
+            Extensions::default(),
+            "02 03 00000002 0000000000000002 0000000000000003 0000000000000005 \
+             0000000000000009",
+        );
+    }
+
+    #[test]
+    fn test_encode_truncated() {
+        check::<PowerSumQuackU32>(
+            Some(1),
+            Extensions::default(),
+            "02 01 00000002 00000002 00000003",
+        );
+        check::<PowerSumQuackU32>(Some(0), Extensions::default(), "02 00 00000002 00000002");
+        let mut buf = [0; 64];
+        assert!(encode_message(
+            &quack::<PowerSumQuackU32>(),
+            Some(4),
+            &Extensions::default(),
+            &mut buf
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_encode_empty() {
+        let quack = PowerSumQuackU32::new(1);
+        let mut buf = [0; 14];
+        let len = quack.encode_into(None, &mut buf).unwrap();
+        assert_eq!(&buf[..len], &hex("02 01 00000000 00000000 00000000")[..]);
+        let decoded = PowerSumQuackU32::decode(&buf[..len]).unwrap();
+        assert_eq!(decoded.count(), 0);
+        assert!(decoded.last_value().is_none());
+    }
+
+    #[test]
+    fn test_encode_timestamps() {
+        let extensions = Extensions {
+            timestamps: Some(Timestamps {
+                captured: at_nanos(0x0102030405060708),
+                emitted: at_nanos(0x0102030405060709),
+            }),
+            ..Default::default()
+        };
+        check::<PowerSumQuackU32>(
+            Some(0),
+            extensions,
+            "12 00 00000002 00000002 0102030405060708 0102030405060709",
+        );
+    }
+
+    #[test]
+    fn test_encode_bytes() {
+        let extensions = Extensions {
+            bytes: Some(1500),
+            ..Default::default()
+        };
+        check::<PowerSumQuackU32>(
+            Some(0),
+            extensions,
+            "22 00 00000002 00000002 00000000000005dc",
+        );
+    }
+
+    #[test]
+    fn test_encode_ce() {
+        let extensions = Extensions {
+            ce_count: Some(7),
+            ..Default::default()
+        };
+        check::<PowerSumQuackU32>(Some(0), extensions, "42 00 00000002 00000002 00000007");
+    }
+
+    #[test]
+    fn test_encode_window() {
+        let extensions = Extensions {
+            window_start: Some(0x1234),
+            ..Default::default()
+        };
+        check::<PowerSumQuackU32>(
+            Some(0),
+            extensions,
+            "82 00 00000002 00000002 01 08 0000000000001234",
+        );
+    }
+
+    #[test]
+    fn test_encode_recent_ids() {
+        let extensions = Extensions {
+            recent_ids: Some(vec![1, 0xabcd]),
+            ..Default::default()
+        };
+        check::<PowerSumQuackU16>(
+            Some(0),
+            extensions.clone(),
+            "82 00 00000002 0002 02 04 0001 abcd",
+        );
+        check::<PowerSumQuackU32>(
+            Some(0),
+            extensions,
+            "82 00 00000002 00000002 02 08 00000001 0000abcd",
+        );
+    }
+
+    #[test]
+    fn test_encode_all_extensions() {
+        let extensions = Extensions {
+            timestamps: Some(Timestamps {
+                captured: at_nanos(1),
+                emitted: at_nanos(2),
+            }),
+            bytes: Some(3),
+            ce_count: Some(4),
+            window_start: Some(5),
+            recent_ids: Some(vec![6]),
+        };
+        check::<PowerSumQuackU16>(
+            Some(1),
+            extensions,
+            "f2 01 00000002 0002 0003 0000000000000001 0000000000000002 \
+             0000000000000003 00000004 01 08 0000000000000005 02 02 0006",
+        );
+    }
+
+    #[test]
+    fn test_near_rtt() {
+        let timestamps = Timestamps {
+            captured: at_nanos(1_000_000_000),
+            emitted: at_nanos(1_005_000_000),
+        };
+        let rtt = timestamps.near_rtt(Duration::from_millis(25));
+        assert_eq!(rtt, Some(Duration::from_millis(20)));
+        // Held longer than the packet was in flight
+        assert_eq!(timestamps.near_rtt(Duration::from_millis(4)), None);
+        let delay = timestamps.one_way_delay(at_nanos(990_000_000));
+        assert_eq!(delay, Some(Duration::from_millis(10)));
+        assert_eq!(timestamps.one_way_delay(at_nanos(1_001_000_000)), None);
+    }
+
+    #[test]
+    fn test_goodput_since() {
+        let earlier = Extensions {
+            bytes: Some(1000),
+            ..Default::default()
+        };
+        let later = Extensions {
+            bytes: Some(126000),
+            ..Default::default()
+        };
+        let goodput = later.goodput_since(&earlier, Duration::from_millis(100));
+        assert_eq!(goodput, Some(10_000_000.0));
+        assert_eq!(later.goodput_since(&earlier, Duration::ZERO), None);
+        // The counter was reset
+        assert_eq!(
+            earlier.goodput_since(&later, Duration::from_millis(100)),
+            None
+        );
+        assert_eq!(
+            Extensions::default().goodput_since(&earlier, Duration::from_millis(100)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_goodput_since_timestamps() {
+        let at = |captured: u64, bytes: u64| Extensions {
+            timestamps: Some(Timestamps {
+                captured: at_nanos(captured),
+                emitted: at_nanos(captured + 1),
+            }),
+            bytes: Some(bytes),
+            ..Default::default()
+        };
+        let (earlier, later) = (at(0, 0), at(50_000_000, 125000));
+        let goodput = later.goodput_since(&earlier, Duration::from_millis(100));
+        assert_eq!(goodput, Some(20_000_000.0));
+    }
+
+    #[test]
+    fn test_ce_marks_since() {
+        let at = |ce_count: u32| Extensions {
+            ce_count: Some(ce_count),
+            ..Default::default()
+        };
+        assert_eq!(at(7).ce_marks_since(&at(4)), Some(3));
+        assert_eq!(at(4).ce_marks_since(&at(7)), None);
+        assert_eq!(Extensions::default().ce_marks_since(&at(4)), None);
+    }
+
+    #[test]
+    fn test_decode_unknown_tlv() {
+        let buf = hex("82 00 00000002 00000002 7f 02 ffff 01 08 0000000000000005");
+        let (_, extensions) = decode_message::<PowerSumQuackU32>(&buf).unwrap();
+        assert_eq!(extensions.window_start, Some(5));
+        assert!(extensions.recent_ids.is_none());
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let decode = |s: &str| decode_message::<PowerSumQuackU32>(&hex(s)).map(|_| ());
+        // The previous version, without flags
+        assert!(decode("01 00 00000002 00000002").is_err());
+        // Truncated power sums
+        assert!(decode("02 01 00000002 00000002").is_err());
+        // Extra bytes without TLVs
+        assert!(decode("02 00 00000002 00000002 00").is_err());
+        // Truncated extensions
+        assert!(decode("42 00 00000002 00000002 000000").is_err());
+        // Truncated TLV
+        assert!(decode("82 00 00000002 00000002 01 08 00").is_err());
+        // Recent identifiers that are not whole words
+        assert!(decode("82 00 00000002 00000002 02 03 000001").is_err());
+    }
+}
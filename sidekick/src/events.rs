@@ -0,0 +1,217 @@
+//! Structured log of quACK events, for analysis across runs.
+//!
+//! Each event is a line of JSON with the wall-clock time and the monotonic
+//! time in ns, the flow if the sidekick quACKs multiple flows, and the event:
+//!
+//! ```text
+//! {"wall_ns":1700000000123456789,"mono_ns":5123456789,"src":"10.0.2.10:5000","dst":"10.0.1.1:443","event":"insert","id":3735928559,"count":7}
+//! ```
+//!
+//! The monotonic time is that of `CLOCK_MONOTONIC`, which `Instant` uses, so
+//! it can be matched with the logs of other processes on the same host. The
+//! file is rotated when it reaches a maximum size, and insert events, which
+//! are logged for every sniffed packet, can be sampled.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddrV4;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::sidekick_multi;
+use crate::stats::FlowStats;
+
+/// Number of rotated files to keep, at `<path>.1` to `<path>.<N>` from the
+/// newest to the oldest.
+pub const ROTATED_FILES: usize = 4;
+
+/// Where and how much to log.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct EventLogConfig {
+    /// File to append the events to
+    pub path: PathBuf,
+    /// Size in bytes at which to rotate the file, or 0 to never rotate it
+    #[serde(default)]
+    pub max_bytes: u64,
+    /// Log one in every `sample` insert events
+    #[serde(default = "default_sample")]
+    pub sample: u64,
+}
+
+fn default_sample() -> u64 {
+    1
+}
+
+/// What happened to a quACK or flow.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// An identifier was inserted into the quACK, which now has `count`
+    /// identifiers
+    Insert { id: u64, count: u32 },
+    /// The quACK was reset
+    Reset,
+    /// A quACK of `count` identifiers was encoded into a `len`-byte message
+    /// to be sent
+    Quack { count: u32, len: usize },
+    /// The flow was created
+    FlowCreated {
+        rule: Option<usize>,
+        threshold: usize,
+    },
+    /// The flow was removed from the table
+    FlowEvicted {
+        reason: Eviction,
+        #[serde(flatten)]
+        stats: FlowStats,
+    },
+}
+
+/// Why a flow was removed from the table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Eviction {
+    /// All flows were removed on a quACK reset
+    Reset,
+    /// A flow that moved to the same address replaced it
+    Replaced,
+}
+
+#[derive(Serialize)]
+struct Record {
+    wall_ns: u64,
+    mono_ns: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    src: Option<SocketAddrV4>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dst: Option<SocketAddrV4>,
+    #[serde(flatten)]
+    event: Event,
+}
+
+/// The open file and what was written to it.
+struct Writer {
+    file: BufWriter<File>,
+    /// Number of bytes in the file
+    len: u64,
+    /// Number of insert events, logged or not
+    inserts: u64,
+}
+
+/// A log of events, shared between the socket thread and the tasks that
+/// emit quACKs.
+pub struct EventLog {
+    config: EventLogConfig,
+    writer: Mutex<Writer>,
+}
+
+impl EventLog {
+    /// Open the file to append events to.
+    pub fn open(config: EventLogConfig) -> Result<Self, String> {
+        if config.sample == 0 {
+            return Err("event log sample must be positive".to_string());
+        }
+        let file = open_append(&config.path)
+            .map_err(|e| format!("error opening {}: {}", config.path.display(), e))?;
+        let len = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        info!("logging events to {}", config.path.display());
+        Ok(Self {
+            config,
+            writer: Mutex::new(Writer {
+                file: BufWriter::new(file),
+                len,
+                inserts: 0,
+            }),
+        })
+    }
+
+    /// Log an event of the flow with the address key, if any. Insert events
+    /// are sampled, and are only flushed with the next other event.
+    pub fn log(&self, flow: Option<&[u8; 12]>, event: Event) {
+        let mut writer = self.writer.lock().unwrap();
+        let insert = matches!(event, Event::Insert { .. });
+        if insert {
+            writer.inserts += 1;
+            if (writer.inserts - 1) % self.config.sample != 0 {
+                return;
+            }
+        }
+        let (src, dst) = flow.map(sidekick_multi::flow_addrs).unzip();
+        let record = Record {
+            wall_ns: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64,
+            mono_ns: monotonic_nanos(),
+            src,
+            dst,
+            event,
+        };
+        if let Err(e) = self.write(&mut writer, &record, !insert) {
+            warn!("error writing to {}: {}", self.config.path.display(), e);
+        }
+    }
+
+    /// Flush the events written so far, e.g., before the process exits.
+    pub fn flush(&self) {
+        if let Err(e) = self.writer.lock().unwrap().file.flush() {
+            warn!("error writing to {}: {}", self.config.path.display(), e);
+        }
+    }
+
+    fn write(&self, writer: &mut Writer, record: &Record, flush: bool) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let max_bytes = self.config.max_bytes;
+        if max_bytes > 0 && writer.len > 0 && writer.len + line.len() as u64 > max_bytes {
+            self.rotate(writer)?;
+        }
+        writer.file.write_all(&line)?;
+        writer.len += line.len() as u64;
+        if flush {
+            writer.file.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Move the file to `<path>.1`, shifting the older rotated files and
+    /// dropping the oldest, and start a new file.
+    fn rotate(&self, writer: &mut Writer) -> io::Result<()> {
+        writer.file.flush()?;
+        let path = &self.config.path;
+        for i in (1..ROTATED_FILES).rev() {
+            let rotated = rotated_path(path, i);
+            if rotated.exists() {
+                fs::rename(&rotated, rotated_path(path, i + 1))?;
+            }
+        }
+        fs::rename(path, rotated_path(path, 1))?;
+        writer.file = BufWriter::new(open_append(path)?);
+        writer.len = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path, i: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", i));
+    PathBuf::from(rotated)
+}
+
+/// The time of `CLOCK_MONOTONIC`, in ns.
+fn monotonic_nanos() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
@@ -0,0 +1,347 @@
+//! Rules for which flows a sidekick quACKs, so that other UDP traffic on the
+//! interface, e.g., DNS and NTP, does not consume quACK state.
+//!
+//! A flow is identified by its address key, the source and destination IPv4
+//! address and port. A `FlowFilter` evaluates its rules in order and applies
+//! the action of the first rule that matches, or the default action if none
+//! do. `SidekickMulti` evaluates the filter once per new flow and caches the
+//! result.
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::buffer::QuicHeader;
+
+/// An IPv4 address prefix, e.g., `10.0.0.0/8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    pub addr: Ipv4Addr,
+    pub prefix_len: u8,
+}
+
+impl Cidr {
+    fn mask(&self) -> u32 {
+        u32::MAX
+            .checked_shl(32 - self.prefix_len as u32)
+            .unwrap_or(0)
+    }
+
+    /// Whether the address is in the prefix.
+    pub fn contains(&self, addr: [u8; 4]) -> bool {
+        let mask = self.mask();
+        u32::from_be_bytes(addr) & mask == u32::from(self.addr) & mask
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, prefix_len),
+            None => (s, "32"),
+        };
+        let addr = addr
+            .parse()
+            .map_err(|_| format!("invalid IPv4 address: {}", s))?;
+        let prefix_len = prefix_len
+            .parse()
+            .ok()
+            .filter(|prefix_len| *prefix_len <= 32)
+            .ok_or(format!("invalid prefix length: {}", s))?;
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// An inclusive range of ports, e.g., `443` or `1024-65535`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    /// Whether the port is in the range.
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let parse = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("invalid port range: {}", s))
+        };
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start > end {
+            return Err(format!("invalid port range: {}", s));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Conditions on a flow, all of which must hold for the flow to match. A
+/// missing condition matches any flow.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FlowMatch {
+    /// Source address prefix
+    pub src: Option<Cidr>,
+    /// Destination address prefix
+    pub dst: Option<Cidr>,
+    /// Source ports
+    pub src_ports: Option<PortRange>,
+    /// Destination ports
+    pub dst_ports: Option<PortRange>,
+    /// Whether the UDP payload must be a QUIC packet with a short header,
+    /// with the header form bit clear and the fixed bit set
+    #[serde(default)]
+    pub quic: bool,
+}
+
+impl FlowMatch {
+    /// Whether the address key of the flow matches the address conditions,
+    /// ignoring the conditions on the packet contents.
+    pub fn matches_addr(&self, addr_key: &[u8; 12]) -> bool {
+        let port = |i: usize| u16::from_be_bytes([addr_key[i], addr_key[i + 1]]);
+        let ip = |i: usize| {
+            [
+                addr_key[i],
+                addr_key[i + 1],
+                addr_key[i + 2],
+                addr_key[i + 3],
+            ]
+        };
+        self.src.map_or(true, |src| src.contains(ip(0)))
+            && self.src_ports.map_or(true, |ports| ports.contains(port(4)))
+            && self.dst.map_or(true, |dst| dst.contains(ip(6)))
+            && self
+                .dst_ports
+                .map_or(true, |ports| ports.contains(port(10)))
+    }
+
+    /// Whether the flow matches, given its address key and the first byte of
+    /// the UDP payload of one of its packets.
+    pub fn matches(&self, addr_key: &[u8; 12], first_byte: u8) -> bool {
+        (!self.quic || QuicHeader::from(first_byte) == QuicHeader::Short)
+            && self.matches_addr(addr_key)
+    }
+}
+
+/// What to do with a flow.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// QuACK the flow
+    #[default]
+    Allow,
+    /// Ignore the flow
+    Deny,
+}
+
+/// An action to apply to the flows that match.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FilterRule {
+    pub action: Action,
+    pub flows: FlowMatch,
+}
+
+/// Ordered rules for which flows to quACK. By default, allows all flows.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FlowFilter {
+    pub rules: Vec<FilterRule>,
+    /// Action if no rule matches
+    pub default: Action,
+}
+
+impl FlowFilter {
+    /// Allow only the flows that match, and deny the rest.
+    pub fn allow_only(flows: FlowMatch) -> Self {
+        Self {
+            rules: vec![FilterRule {
+                action: Action::Allow,
+                flows,
+            }],
+            default: Action::Deny,
+        }
+    }
+
+    /// Whether to quACK the flow, given its address key and the first byte of
+    /// the UDP payload of one of its packets.
+    pub fn allows(&self, addr_key: &[u8; 12], first_byte: u8) -> bool {
+        let action = self
+            .rules
+            .iter()
+            .find(|rule| rule.flows.matches(addr_key, first_byte))
+            .map_or(self.default, |rule| rule.action);
+        action == Action::Allow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The address key of a flow from 10.0.0.2:50000 to the destination.
+    fn key(dst: [u8; 4], dst_port: u16) -> [u8; 12] {
+        let mut key = [0; 12];
+        key[0..4].copy_from_slice(&[10, 0, 0, 2]);
+        key[4..6].copy_from_slice(&50000u16.to_be_bytes());
+        key[6..10].copy_from_slice(&dst);
+        key[10..12].copy_from_slice(&dst_port.to_be_bytes());
+        key
+    }
+
+    // First bytes of QUIC packets with short and long headers
+    const SHORT: u8 = 0x40;
+    const LONG: u8 = 0xc0;
+
+    #[test]
+    fn test_cidr() {
+        let cidr: Cidr = "10.42.0.0/16".parse().unwrap();
+        assert!(cidr.contains([10, 42, 0, 1]));
+        assert!(cidr.contains([10, 42, 255, 255]));
+        assert!(!cidr.contains([10, 43, 0, 1]));
+
+        let host: Cidr = "10.42.0.1".parse().unwrap();
+        assert_eq!(host.prefix_len, 32);
+        assert!(host.contains([10, 42, 0, 1]));
+        assert!(!host.contains([10, 42, 0, 2]));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains([10, 42, 0, 1]));
+        assert!(any.contains([255, 255, 255, 255]));
+
+        // Host bits of the address are ignored
+        let cidr: Cidr = "10.42.1.1/24".parse().unwrap();
+        assert!(cidr.contains([10, 42, 1, 200]));
+        assert!(!cidr.contains([10, 42, 2, 1]));
+    }
+
+    #[test]
+    fn test_cidr_invalid() {
+        assert!("10.42.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.42.0.0/".parse::<Cidr>().is_err());
+        assert!("10.42.0/24".parse::<Cidr>().is_err());
+        assert!("localhost".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_port_range() {
+        let range: PortRange = "1024-65535".parse().unwrap();
+        assert!(range.contains(1024));
+        assert!(range.contains(65535));
+        assert!(!range.contains(1023));
+
+        let port: PortRange = "443".parse().unwrap();
+        assert_eq!((port.start, port.end), (443, 443));
+        assert!(port.contains(443));
+        assert!(!port.contains(444));
+
+        assert_eq!("80 - 81".parse(), Ok(PortRange { start: 80, end: 81 }));
+        assert!("81-80".parse::<PortRange>().is_err());
+        assert!("65536".parse::<PortRange>().is_err());
+        assert!("http".parse::<PortRange>().is_err());
+    }
+
+    #[test]
+    fn test_flow_match() {
+        let flows = FlowMatch {
+            dst: Some("34.221.0.0/16".parse().unwrap()),
+            dst_ports: Some("443".parse().unwrap()),
+            ..FlowMatch::default()
+        };
+        assert!(flows.matches(&key([34, 221, 237, 169], 443), SHORT));
+        assert!(!flows.matches(&key([34, 222, 237, 169], 443), SHORT));
+        assert!(!flows.matches(&key([34, 221, 237, 169], 80), SHORT));
+        // The source conditions apply to the source address
+        let flows = FlowMatch {
+            src: Some("10.0.0.0/8".parse().unwrap()),
+            src_ports: Some("50000-50010".parse().unwrap()),
+            ..FlowMatch::default()
+        };
+        assert!(flows.matches(&key([34, 221, 237, 169], 443), SHORT));
+        let flows = FlowMatch {
+            src: Some("34.221.0.0/16".parse().unwrap()),
+            ..FlowMatch::default()
+        };
+        assert!(!flows.matches(&key([34, 221, 237, 169], 443), SHORT));
+        // No conditions match any flow
+        assert!(FlowMatch::default().matches(&key([1, 2, 3, 4], 5), 0));
+    }
+
+    #[test]
+    fn test_flow_match_quic() {
+        let flows = FlowMatch {
+            quic: true,
+            ..FlowMatch::default()
+        };
+        let key = key([34, 221, 237, 169], 443);
+        assert!(flows.matches(&key, SHORT));
+        assert!(flows.matches_addr(&key));
+        assert!(!flows.matches(&key, LONG));
+        // Fixed bit not set
+        assert!(!flows.matches(&key, 0x00));
+        assert!(!flows.matches(&key, 0x80));
+    }
+
+    #[test]
+    fn test_flow_filter() {
+        let filter = FlowFilter {
+            rules: vec![
+                FilterRule {
+                    action: Action::Deny,
+                    flows: FlowMatch {
+                        dst_ports: Some("53".parse().unwrap()),
+                        ..FlowMatch::default()
+                    },
+                },
+                FilterRule {
+                    action: Action::Allow,
+                    flows: FlowMatch {
+                        dst: Some("34.221.0.0/16".parse().unwrap()),
+                        ..FlowMatch::default()
+                    },
+                },
+            ],
+            default: Action::Deny,
+        };
+        assert!(filter.allows(&key([34, 221, 237, 169], 443), SHORT));
+        // The first matching rule applies
+        assert!(!filter.allows(&key([34, 221, 237, 169], 53), SHORT));
+        // The default applies if no rule matches
+        assert!(!filter.allows(&key([8, 8, 8, 8], 443), SHORT));
+        assert!(FlowFilter::default().allows(&key([8, 8, 8, 8], 443), SHORT));
+
+        let filter = FlowFilter::allow_only(FlowMatch {
+            dst_ports: Some("443".parse().unwrap()),
+            ..FlowMatch::default()
+        });
+        assert!(filter.allows(&key([8, 8, 8, 8], 443), SHORT));
+        assert!(!filter.allows(&key([8, 8, 8, 8], 80), SHORT));
+    }
+}
@@ -1,10 +1,44 @@
+//! Without the default `sniffer` feature, only the quACK wire encoding in
+//! `encoding` is built, e.g., for end hosts that decode quACKs.
+#[cfg(feature = "sniffer")]
 pub mod buffer;
+#[cfg(feature = "sniffer")]
+pub mod calibrate;
+#[cfg(feature = "sniffer")]
+pub mod cli;
+#[cfg(feature = "sniffer")]
+pub mod collision;
+#[cfg(feature = "sniffer")]
+pub mod config;
+#[cfg(feature = "sniffer")]
+pub mod control;
+#[cfg(feature = "sniffer")]
+pub mod encoder;
+#[cfg(feature = "encoding")]
+pub mod encoding;
+#[cfg(feature = "sniffer")]
+pub mod events;
+#[cfg(feature = "sniffer")]
+pub mod filter;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "sniffer")]
 mod sidekick;
+#[cfg(feature = "sniffer")]
 pub mod sidekick_multi;
+#[cfg(feature = "sniffer")]
+pub mod stats;
 
+#[cfg(feature = "sniffer")]
 pub use buffer::ID_OFFSET;
-pub use sidekick::Sidekick;
+#[cfg(feature = "sniffer")]
+pub use encoder::QuackEncoder;
+#[cfg(feature = "sniffer")]
+pub use sidekick::{needs_fallback, Decoded, Sidekick};
+#[cfg(feature = "sniffer")]
 pub use sidekick_multi::SidekickMulti;
 
+#[cfg(feature = "sniffer")]
 pub mod socket;
+#[cfg(feature = "sniffer")]
 pub use socket::Socket;
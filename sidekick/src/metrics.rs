@@ -0,0 +1,295 @@
+//! Prometheus metrics of a sidekick that quACKs multiple flows, served over
+//! HTTP in the text exposition format.
+//!
+//! The metrics are rendered from the counters of the sidekick and the flows
+//! in its table whenever they are scraped, so they cost nothing between
+//! scrapes except for the sniff-loop latency histogram.
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::encoding::WireQuack;
+use crate::sidekick_multi;
+use crate::SidekickMulti;
+
+/// Upper bounds of the buckets of the sniff-loop latency histogram, in
+/// seconds.
+const LATENCY_BUCKETS: [f64; 10] = [
+    1e-6, 2.5e-6, 5e-6, 1e-5, 2.5e-5, 5e-5, 1e-4, 2.5e-4, 1e-3, 1e-2,
+];
+/// Maximum length of the head of an HTTP request.
+const MAX_REQUEST_LEN: usize = 4096;
+
+/// Histogram of the time the sniff loop takes to process each packet.
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    /// Number of observations in each bucket, not cumulative
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&le| secs <= le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str) -> std::fmt::Result {
+        let mut cumulative = 0;
+        for (le, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative)?;
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count)?;
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        writeln!(out, "{}_sum {}", name, sum)?;
+        writeln!(out, "{}_count {}", name, count)
+    }
+}
+
+/// The labels of the flow with the address key.
+fn flow_labels(addr_key: &[u8; 12]) -> String {
+    let (src, dst) = sidekick_multi::flow_addrs(addr_key);
+    format!("src=\"{}\",dst=\"{}\"", src, dst)
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) -> std::fmt::Result {
+    writeln!(out, "# HELP {} {}", name, help)?;
+    writeln!(out, "# TYPE {} {}", name, kind)
+}
+
+/// Render the metrics of the sidekick in the Prometheus text format.
+pub fn render<Q: WireQuack>(sc: &SidekickMulti<Q>) -> Result<String, std::fmt::Error> {
+    let stats = sc.stats();
+    let flow_stats = sc.flow_stats();
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "sidekick_frames_total",
+        "counter",
+        "Frames sniffed.",
+    )?;
+    writeln!(out, "sidekick_frames_total {}", stats.frames)?;
+    header(
+        &mut out,
+        "sidekick_skipped_total",
+        "counter",
+        "Frames skipped without parsing an identifier, by reason.",
+    )?;
+    for (reason, count) in [
+        ("wrong_direction", stats.wrong_direction),
+        ("not_ip", stats.not_ip),
+        ("not_udp", stats.validation.not_udp),
+        ("filtered", stats.filtered),
+    ] {
+        writeln!(
+            out,
+            "sidekick_skipped_total{{reason=\"{}\"}} {}",
+            reason, count
+        )?;
+    }
+    header(
+        &mut out,
+        "sidekick_parse_failures_total",
+        "counter",
+        "UDP packets without a parseable identifier, by reason.",
+    )?;
+    for (reason, count) in [
+        ("fragment", stats.validation.fragments),
+        ("truncated", stats.validation.truncated),
+        ("bad_header", stats.validation.bad_headers),
+        ("underfilled", stats.underfilled),
+    ] {
+        writeln!(
+            out,
+            "sidekick_parse_failures_total{{reason=\"{}\"}} {}",
+            reason, count
+        )?;
+    }
+    header(
+        &mut out,
+        "sidekick_resets_total",
+        "counter",
+        "QuACK resets of all flows received.",
+    )?;
+    writeln!(out, "sidekick_resets_total {}", stats.resets)?;
+    header(
+        &mut out,
+        "sidekick_flows_created_total",
+        "counter",
+        "Flows created.",
+    )?;
+    writeln!(out, "sidekick_flows_created_total {}", stats.flows)?;
+    header(
+        &mut out,
+        "sidekick_evictions_total",
+        "counter",
+        "Flows removed from the table.",
+    )?;
+    writeln!(out, "sidekick_evictions_total {}", stats.evictions)?;
+    header(
+        &mut out,
+        "sidekick_active_flows",
+        "gauge",
+        "Flows in the table.",
+    )?;
+    writeln!(out, "sidekick_active_flows {}", sc.senders().len())?;
+
+    header(
+        &mut out,
+        "sidekick_inserted_total",
+        "counter",
+        "Identifiers inserted into the quACKs of all flows.",
+    )?;
+    writeln!(out, "sidekick_inserted_total {}", flow_stats.inserts)?;
+    header(
+        &mut out,
+        "sidekick_quacks_sent_total",
+        "counter",
+        "QuACKs sent for all flows.",
+    )?;
+    writeln!(out, "sidekick_quacks_sent_total {}", flow_stats.quacks)?;
+    header(
+        &mut out,
+        "sidekick_flow_inserted_total",
+        "counter",
+        "Identifiers inserted into the quACK of each flow.",
+    )?;
+    for (addr_key, flow) in sc.senders() {
+        let labels = flow_labels(addr_key);
+        writeln!(
+            out,
+            "sidekick_flow_inserted_total{{{}}} {}",
+            labels, flow.stats.inserts
+        )?;
+    }
+    header(
+        &mut out,
+        "sidekick_flow_quacks_sent_total",
+        "counter",
+        "QuACKs sent for each flow.",
+    )?;
+    for (addr_key, flow) in sc.senders() {
+        let labels = flow_labels(addr_key);
+        writeln!(
+            out,
+            "sidekick_flow_quacks_sent_total{{{}}} {}",
+            labels, flow.stats.quacks
+        )?;
+    }
+    header(
+        &mut out,
+        "sidekick_flow_resets_total",
+        "counter",
+        "QuACK resets of each flow.",
+    )?;
+    for (addr_key, flow) in sc.senders() {
+        let labels = flow_labels(addr_key);
+        writeln!(
+            out,
+            "sidekick_flow_resets_total{{{}}} {}",
+            labels, flow.stats.resets
+        )?;
+    }
+
+    header(
+        &mut out,
+        "sidekick_sniff_latency_seconds",
+        "histogram",
+        "Time the sniff loop takes to process each packet.",
+    )?;
+    sc.counters()
+        .latency
+        .render(&mut out, "sidekick_sniff_latency_seconds")?;
+    Ok(out)
+}
+
+/// Bind the TCP socket to serve metrics on.
+pub async fn bind(addr: SocketAddr) -> Result<TcpListener, String> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| format!("error binding to TCP socket: {:?}", e))?;
+    info!("serving metrics on http://{}/metrics", addr);
+    Ok(listener)
+}
+
+/// Serve the metrics of the sidekick to every HTTP request for `/metrics` on
+/// the listener.
+pub async fn serve<Q: WireQuack + Send + 'static>(
+    sc: Arc<Mutex<SidekickMulti<Q>>>,
+    listener: TcpListener,
+) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("error accepting metrics connection: {:?}", e);
+                continue;
+            }
+        };
+        let sc = sc.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, sc).await {
+                debug!("error serving metrics to {:?}: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn respond<Q: WireQuack>(
+    mut stream: TcpStream,
+    sc: Arc<Mutex<SidekickMulti<Q>>>,
+) -> Result<(), String> {
+    // Read the request head, of which only the request line matters.
+    let mut request = vec![0; MAX_REQUEST_LEN];
+    let mut len = 0;
+    while !request[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+        if len == request.len() {
+            return Err("request too long".to_string());
+        }
+        let n = stream
+            .read(&mut request[len..])
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+        if n == 0 {
+            return Err("connection closed".to_string());
+        }
+        len += n;
+    }
+    let request_line = String::from_utf8_lossy(&request[..len]);
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = render(&sc.lock().unwrap()).map_err(|e| format!("{:?}", e))?;
+            ("200 OK", body)
+        }
+        _ => ("404 Not Found", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body,
+    );
+    stream
+        .write_all(response.as_bytes())
+        .await
+        .map_err(|e| format!("{:?}", e))
+}
@@ -1,43 +1,147 @@
-use log::{debug, info, trace};
+use log::{info, trace, warn};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 use tokio::net::UdpSocket;
 use tokio::sync::oneshot;
 
-use crate::buffer::{Direction, UdpParser, BUFFER_SIZE};
-use crate::socket::SockAddr;
-use crate::Socket;
-use quack::{PowerSumQuack, PowerSumQuackU32};
+use crate::buffer::{Direction, IdentifierProfile};
+use crate::calibrate::IdMonitor;
+use crate::collision::{CollisionDetector, CollisionStats};
+use crate::encoder::{self, Packet, QuackEncoder, QuackSink, SniffConfig};
+use crate::encoding::{self, Extensions, Timestamps, WireQuack};
+use crate::events::{Event, EventLog};
+use crate::filter::FlowFilter;
+use crate::stats::{SniffCounters, SniffStats};
 
+/// A sidekick that accumulates identifiers of `bits` bits in a quACK of type
+/// `Q`, e.g., `PowerSumQuackU16` for 16-bit identifiers, `PowerSumQuackU32`
+/// for 24- or 32-bit identifiers, and `PowerSumQuackU64` for 64-bit
+/// identifiers.
+///
+/// By default the sidekick is a quACK sender that sniffs incoming packets. As
+/// a quACK receiver co-located with the data sender, it instead sniffs
+/// outgoing packets and logs their identifiers, so that it can decode the
+/// quACKs of a remote sidekick with `decode`.
 #[derive(Clone)]
-pub struct Sidekick {
+pub struct Sidekick<Q: WireQuack> {
     pub interface: String,
     pub threshold: usize,
     pub bits: usize,
+    /// Whether to timestamp sniffed packets and include timestamps in quACKs
+    pub timestamps: bool,
+    /// Whether to split frames coalesced by GRO into their UDP segments
+    pub gso: bool,
+    /// Whether to include the cumulative byte count in quACKs
+    pub byte_counts: bool,
+    /// Whether to include the cumulative CE-marked packet count in quACKs
+    pub ce_counts: bool,
+    /// Direction of the packets to sniff
+    pub direction: Direction,
+    /// Maximum number of identifiers to log, or 0 to not log identifiers
+    pub log_capacity: usize,
+    /// Number of most recent identifiers in a sliding-window quACK, or 0 for
+    /// a cumulative quACK
+    pub window: usize,
+    /// Maximum number of recent identifiers to attach to a quACK when the end
+    /// host requests them, or 0 to never attach them
+    pub fallback: usize,
+    /// Number of power sums to include in each quACK, or all of them if
+    /// `None`
+    pub num_sums: Option<usize>,
+    /// My port to receive acknowledged quACKs on with `listen_for_acks`, if
+    /// any. Other packets to my IP address reset the quACK.
+    pub ack_port: Option<u16>,
+    /// Which flows to quACK, evaluated for every sniffed packet
+    pub filter: FlowFilter,
+    /// Whether to only quACK QUIC packets with short headers
+    pub short_headers_only: bool,
+    /// Where the identifier is in the packets of the encrypted protocol
+    pub profile: IdentifierProfile,
+    /// Whether to warn if the identifiers do not look uniformly random
+    pub check_ids: bool,
+    /// Number of recent identifiers to detect collisions with, or 0 to not
+    /// detect collisions
+    pub collision_window: usize,
+    /// Log of the quACK events, if any
+    pub events: Option<Arc<EventLog>>,
     #[cfg(feature = "benchmark")]
     pub start_time: Option<tokio::time::Instant>,
-    quack: PowerSumQuackU32,
-    log: Vec<u32>,
+    quack: Q,
+    log: VecDeque<Q::Element>,
+    window_ids: VecDeque<u64>,
+    recent_ids: RecentIds,
+    last_capture: Option<SystemTime>,
+    bytes: u64,
+    ce_count: u32,
+    id_monitor: Option<IdMonitor>,
+    collisions: Option<CollisionDetector>,
+    counters: Arc<SniffCounters>,
 }
 
-impl Sidekick {
-    /// Create a new sidekick.
+impl<Q: WireQuack> Sidekick<Q> {
+    /// Create a new sidekick that sends quACKs of incoming packets.
     pub fn new(interface: &str, threshold: usize, bits: usize) -> Self {
-        assert_eq!(bits, 32, "ERROR: <num_bits_id> must be 32");
+        assert!(
+            bits > 0 && bits % 8 == 0 && bits <= 8 * Q::WORD_LEN,
+            "ERROR: <num_bits_id> must be a multiple of 8 up to {}",
+            8 * Q::WORD_LEN
+        );
         Self {
             interface: interface.to_string(),
             threshold,
             bits,
+            timestamps: false,
+            gso: false,
+            byte_counts: false,
+            ce_counts: false,
+            direction: Direction::Incoming,
+            log_capacity: 0,
+            window: 0,
+            fallback: 0,
+            num_sums: None,
+            ack_port: None,
+            filter: FlowFilter::default(),
+            short_headers_only: false,
+            profile: IdentifierProfile::default(),
+            check_ids: false,
+            collision_window: 0,
+            events: None,
             #[cfg(feature = "benchmark")]
             start_time: None,
-            quack: PowerSumQuackU32::new(threshold),
-            log: vec![],
+            quack: Q::new(threshold),
+            log: VecDeque::new(),
+            window_ids: VecDeque::new(),
+            recent_ids: RecentIds::default(),
+            last_capture: None,
+            bytes: 0,
+            ce_count: 0,
+            id_monitor: None,
+            collisions: None,
+            counters: Arc::default(),
         }
     }
 
+    /// Create a new sidekick that receives quACKs, logging up to
+    /// `log_capacity` identifiers of outgoing packets.
+    pub fn new_receiver(
+        interface: &str,
+        threshold: usize,
+        bits: usize,
+        log_capacity: usize,
+    ) -> Self {
+        let mut sc = Self::new(interface, threshold, bits);
+        sc.direction = Direction::Outgoing;
+        sc.log_capacity = log_capacity;
+        sc.collision_window = log_capacity;
+        sc
+    }
+
     /// Insert a packet into the cumulative quACK. Should be used by quACK
     /// receivers, such as in the client code, with direct access to sent
     /// packets. Typically if this function is used, do not call start().
-    pub fn insert_packet(&mut self, id: u32) {
+    pub fn insert_packet(&mut self, id: Q::Element) {
         if self.threshold != 0 {
             self.quack.insert(id);
         }
@@ -45,168 +149,576 @@ impl Sidekick {
 
     /// Reset the sidekick state.
     pub fn reset(&mut self) {
-        self.quack = PowerSumQuackU32::new(self.threshold);
-        self.log = vec![];
+        self.quack = Q::new(self.threshold);
+        self.log.clear();
+        self.window_ids.clear();
+        self.recent_ids.clear();
+        self.last_capture = None;
+        self.bytes = 0;
+        self.ce_count = 0;
+        if let Some(events) = &self.events {
+            events.log(None, Event::Reset);
+        }
     }
 
-    /// Start the raw socket that listens to the specified interface and
-    /// accumulates those packets in a quACK. If the sidekick is a quACK sender,
-    /// only listens for incoming packets. If the sidekick is a quACK receiver,
-    /// only listens for outgoing packets, and additionally logs the packet
-    /// identifiers.
-    /// Returns a channel that indicates when the first packet is sniffed.
-    pub fn start(
-        sc: Arc<Mutex<Sidekick>>,
-        my_ipv4_addr: [u8; 4],
-    ) -> Result<oneshot::Receiver<()>, String> {
-        let interface = sc.lock().unwrap().interface.clone();
-        let sock = Socket::new(interface.clone())?;
-        sock.set_promiscuous()?;
-
-        // Creates the channel that indicates when the first packet is sniffed.
-        let (tx, rx) = oneshot::channel();
-
-        // Loop over received packets
-        tokio::task::spawn_blocking(move || {
-            info!("tapping socket on fd={} interface={}", sock.fd, interface);
-            let mut buf: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
-            let mut addr = SockAddr::new_sockaddr_ll();
-            let ip_protocol = (libc::ETH_P_IP as u16).to_be();
-            let mut tx = Some(tx);
-            while let Ok(n) = sock.recvfrom(&mut addr, &mut buf) {
-                trace!("received {} bytes: {:?}", n, buf);
-                if Direction::Incoming != addr.sll_pkttype.into() {
-                    continue;
-                }
-                if addr.sll_protocol != ip_protocol {
-                    trace!("not IP packet: {}", addr.sll_protocol);
-                    continue;
-                }
-                if !UdpParser::is_udp(&buf) {
-                    trace!("not UDP packet");
-                    continue;
-                }
+    /// Subtract a quACK that the end host has fully processed from the
+    /// cumulative quACK, instead of resetting it, so that the packets sniffed
+    /// since that quACK are not forgotten. The processed quACK must have been
+    /// emitted by this sidekick since the last reset, with all its power sums.
+    pub fn acknowledge(&mut self, processed: Q) -> Result<(), String> {
+        subtract_processed(&mut self.quack, processed, self.window)
+    }
+
+    /// Attach the recent identifiers to the next quACK, if enabled, because
+    /// the end host could not decode the last one.
+    pub fn request_fallback(&mut self) {
+        self.recent_ids.request();
+    }
 
-                // Reset the quack if the dst IP is our own (and not for
-                // another e2e quic connection).
-                if UdpParser::parse_dst_ip(&buf) == my_ipv4_addr {
-                    // TODO: check if dst port corresponds to this connection
-                    sc.lock().unwrap().reset();
+    /// Listen for acknowledged quACKs from the end host on the UDP socket
+    /// address, and subtract them from the cumulative quACK. A single-byte
+    /// message is a full reset, unless it requests the recent identifiers as a
+    /// fallback. If an acknowledged quACK cannot be subtracted,
+    /// the quACK is reset and a single-byte NACK is sent back so that the end
+    /// host resets too.
+    pub async fn listen_for_acks(
+        sc: Arc<Mutex<Sidekick<Q>>>,
+        addr: SocketAddr,
+    ) -> Result<(), String> {
+        let sock = UdpSocket::bind(addr)
+            .await
+            .map_err(|e| format!("error binding to {}: {:?}", addr, e))?;
+        let threshold = sc.lock().unwrap().threshold;
+        let mut buf = vec![0; Q::encoded_len(threshold) + encoding::MAX_EXTENSIONS_LEN];
+        info!("listening for acknowledged quACKs on {:?}", addr);
+        loop {
+            let (len, peer) = sock
+                .recv_from(&mut buf)
+                .await
+                .map_err(|e| format!("error receiving ack: {:?}", e))?;
+            {
+                let mut sc = sc.lock().unwrap();
+                if len == 1 && buf[0] == encoding::ACK_FALLBACK {
+                    sc.request_fallback();
                     continue;
                 }
-
-                // Otherwise parse the identifier and insert it into the quack.
-                if n != (BUFFER_SIZE as _) {
-                    trace!("underfilled buffer: {} < {}", n, BUFFER_SIZE);
+                if len <= 1 {
+                    sc.reset();
                     continue;
                 }
-                let id = UdpParser::parse_identifier(&buf);
-                debug!("insert {} ({:#10x})", id, id);
-                // TODO: filter by QUIC connection?
-                {
-                    let mut sc = sc.lock().unwrap();
-                    if let Some(tx) = tx.take() {
-                        tx.send(()).unwrap();
-                        #[cfg(feature = "benchmark")]
-                        {
-                            sc.start_time = Some(tokio::time::Instant::now());
-                        }
+                let result = Q::decode(&buf[..len]).and_then(|processed| sc.acknowledge(processed));
+                match result {
+                    Ok(()) => continue,
+                    Err(e) => {
+                        warn!("bad ack, resetting: {}", e);
+                        sc.reset();
                     }
-                    sc.insert_packet(id);
-                    #[cfg(feature = "quack_log")]
-                    println!(
-                        "quack {:?} {} {}",
-                        std::time::Instant::now(),
-                        id,
-                        sc.quack.count()
-                    );
                 }
             }
-        });
-        Ok(rx)
+            if let Err(e) = sock.send_to(&[0], peer).await {
+                warn!("error sending nack: {:?}", e);
+            }
+        }
+    }
+
+    /// Insert a sniffed packet of `len` IP bytes, captured at `timestamp`.
+    fn insert_sniffed(&mut self, id: u64, len: u16, ce: bool, timestamp: Option<SystemTime>) {
+        if self.check_ids {
+            let bits = self.bits;
+            let monitor = self.id_monitor.get_or_insert_with(|| IdMonitor::new(bits));
+            if let Some(warning) = monitor.insert(id) {
+                warn!("{}", warning);
+            }
+        }
+        if self.collision_window > 0 {
+            let window = self.collision_window;
+            let collisions = self
+                .collisions
+                .get_or_insert_with(|| CollisionDetector::new(window));
+            if collisions.insert(id) {
+                trace!("identifier collision: {}", id);
+            }
+        }
+        if self.log_capacity > 0 {
+            // Drop the oldest identifier, which is too old to decode anyway.
+            if self.log.len() == self.log_capacity {
+                trace!("log full, dropping oldest identifier");
+                self.log.pop_front();
+            }
+            self.log.push_back(Q::element(id));
+        }
+        self.insert_packet(Q::element(id));
+        if self.fallback > 0 {
+            let capacity = self.fallback.min(encoding::max_recent_ids(Q::WORD_LEN));
+            self.recent_ids.insert(id, capacity);
+        }
+        if self.window > 0 {
+            // Expire the oldest identifier from the sliding window.
+            self.window_ids.push_back(id);
+            if self.window_ids.len() > self.window {
+                let expired = self.window_ids.pop_front().unwrap();
+                if self.threshold != 0 {
+                    self.quack.remove(Q::element(expired));
+                }
+            }
+        }
+        self.last_capture = timestamp;
+        self.bytes += len as u64;
+        if ce {
+            self.ce_count += 1;
+        }
+        if let Some(events) = &self.events {
+            let count = self.quack.count();
+            events.log(None, Event::Insert { id, count });
+        }
+    }
+
+    /// Which packets to sniff, resetting the quACK on resets sent to
+    /// `my_ipv4_addr`.
+    pub fn sniff_config(&self, my_ipv4_addr: [u8; 4]) -> SniffConfig {
+        SniffConfig {
+            interface: self.interface.clone(),
+            bits: self.bits,
+            direction: self.direction,
+            timestamps: self.timestamps,
+            gso: self.gso,
+            my_ipv4_addr: Some(my_ipv4_addr),
+            ack_port: self.ack_port,
+            filter: self.filter.clone(),
+            short_headers_only: self.short_headers_only,
+            profile: self.profile,
+            counters: self.counters.clone(),
+        }
     }
 
     /// Start the raw socket that listens to the specified interface and
     /// accumulates those packets in a quACK. If the sidekick is a quACK sender,
     /// only listens for incoming packets. If the sidekick is a quACK receiver,
     /// only listens for outgoing packets, and additionally logs the packet
-    /// identifiers.
+    /// identifiers. Acknowledged quACKs are handled by `listen_for_acks`.
     /// Returns a channel that indicates when the first packet is sniffed.
+    pub fn start(
+        sc: Arc<Mutex<Sidekick<Q>>>,
+        my_ipv4_addr: [u8; 4],
+    ) -> Result<oneshot::Receiver<()>, String>
+    where
+        Q: Send + 'static,
+        Q::Element: Send,
+    {
+        let config = sc.lock().unwrap().sniff_config(my_ipv4_addr);
+        encoder::start(sc, config)
+    }
+
+    /// Start the raw socket that listens to the specified interface and
+    /// accumulates those packets in a quACK, sending a quACK with the first
+    /// `num_sums` power sums to `sendaddr` every `frequency_pkts` packets.
+    /// Acknowledged quACKs are not handled in this mode.
     pub async fn start_frequency_pkts(
         &mut self,
         my_ipv4_addr: [u8; 4],
         frequency_pkts: usize,
+        num_sums: Option<usize>,
         sendaddr: std::net::SocketAddr,
     ) -> Result<(), String> {
-        let recvsock = Socket::new(self.interface.clone())?;
-        let sendsock = UdpSocket::bind("0.0.0.0:0").await.unwrap();
-        recvsock.set_promiscuous()?;
-
-        // Loop over received packets
-        let mut buf: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
-        info!(
-            "tapping socket on fd={} interface={}",
-            recvsock.fd, self.interface
-        );
-        let mut addr = SockAddr::new_sockaddr_ll();
-        let ip_protocol = (libc::ETH_P_IP as u16).to_be();
-        let mut mod_count = 0;
-        while let Ok(n) = recvsock.recvfrom(&mut addr, &mut buf) {
-            trace!("received {} bytes: {:?}", n, buf);
-            if Direction::Incoming != addr.sll_pkttype.into() {
-                continue;
-            }
-            if addr.sll_protocol != ip_protocol {
-                trace!("not IP packet: {}", addr.sll_protocol);
-                continue;
-            }
-            if !UdpParser::is_udp(&buf) {
-                trace!("not UDP packet");
-                continue;
-            }
+        let config = self.sniff_config(my_ipv4_addr);
+        let mut sink = QuackSink::udp(sendaddr).await?;
+        self.num_sums = num_sums;
+        encoder::run_every_pkts(self, &config, frequency_pkts, &mut sink).await
+    }
 
-            // Reset the quack if the dst IP is our own (and not for
-            // another e2e quic connection).
-            if UdpParser::parse_dst_ip(&buf) == my_ipv4_addr {
-                // TODO: check if dst port corresponds to this connection
-                self.reset();
-                continue;
-            }
+    /// Snapshot the quACK.
+    pub fn quack(&self) -> Q {
+        self.quack.clone()
+    }
 
-            // Otherwise parse the identifier and insert it into the quack.
-            if n != (BUFFER_SIZE as _) {
-                trace!("underfilled buffer: {} < {}", n, BUFFER_SIZE);
-                continue;
-            }
-            let id = UdpParser::parse_identifier(&buf);
-            debug!("insert {} ({:#10x})", id, id);
-            // TODO: filter by QUIC connection?
-            self.insert_packet(id);
-            mod_count = (mod_count + 1) % frequency_pkts;
-            if mod_count == 0 {
-                let bytes = bincode::serialize(&self.quack).unwrap();
-                trace!("quack {}", self.quack.count());
-                sendsock.send_to(&bytes, sendaddr).await.unwrap();
-            }
-            #[cfg(feature = "quack_log")]
-            println!(
-                "quack {:?} {} {}",
-                std::time::Instant::now(),
-                id,
-                self.quack.count()
-            );
+    /// Cumulative number of IP bytes in the sniffed packets in the quACK.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Cumulative number of CE-marked sniffed packets in the quACK.
+    pub fn ce_count(&self) -> u32 {
+        self.ce_count
+    }
+
+    /// Extensions to include in the next quACK message.
+    pub fn extensions(&self) -> Extensions {
+        Extensions {
+            timestamps: self.last_capture.map(|captured| Timestamps {
+                captured,
+                emitted: SystemTime::now(),
+            }),
+            bytes: self.byte_counts.then_some(self.bytes),
+            ce_count: self.ce_counts.then_some(self.ce_count),
+            window_start: self.window_ids.front().copied(),
+            recent_ids: self.recent_ids.fallback(),
         }
+    }
+
+    /// Encode the quACK message into the buffer without cloning the quACK,
+    /// including only the first `num_sums` power sums if specified. Returns
+    /// the encoded length.
+    pub fn encode_quack(
+        &mut self,
+        num_sums: Option<usize>,
+        buf: &mut [u8],
+    ) -> Result<usize, String> {
+        let len = encoding::encode_message(&self.quack, num_sums, &self.extensions(), buf)?;
+        self.recent_ids.emitted();
+        if let Some(events) = &self.events {
+            let count = self.quack.count();
+            events.log(None, Event::Quack { count, len });
+        }
+        Ok(len)
+    }
+
+    /// Snapshot the quACK and drain the current log.
+    pub fn quack_with_log(&mut self) -> (Q, Vec<Q::Element>) {
+        (self.quack.clone(), std::mem::take(&mut self.log).into())
+    }
+
+    /// Number of identifiers and collisions among the recent identifiers.
+    pub fn collision_stats(&self) -> CollisionStats {
+        self.collisions
+            .as_ref()
+            .map_or(CollisionStats::default(), CollisionDetector::stats)
+    }
+
+    /// Counts of the sniffed packets by what was done with them.
+    pub fn stats(&self) -> SniffStats {
+        self.counters.stats()
+    }
+
+    /// The counters, which the socket thread updates without the lock.
+    pub fn counters(&self) -> Arc<SniffCounters> {
+        self.counters.clone()
+    }
+
+    /// Number of identifiers in the log.
+    pub fn log_len(&self) -> usize {
+        self.log.len()
+    }
+
+    /// Decode the identifiers missing from a quACK `received` from a remote
+    /// sidekick, using the log of identifiers sniffed by this sidekick. The
+    /// logged identifiers up to the last value received are drained from the
+    /// log, and the missing packets are removed from the cumulative quACK so
+    /// that they are only reported once. Identifiers shared by several logged
+    /// packets are reported as indeterminate if any of them are missing.
+    ///
+    /// Returns an error if the last value received is not in the log or the
+    /// number of missing packets exceeds the threshold, in which case both
+    /// sidekicks should be reset.
+    pub fn decode(&mut self, received: Q) -> Result<Decoded<Q::Element>, String>
+    where
+        Q::Element: Clone + PartialEq,
+    {
+        let last_value = match received.last_value() {
+            Some(last_value) => last_value,
+            None => return Ok(Decoded::default()),
+        };
+        let index = self
+            .log
+            .iter()
+            .rposition(|id| *id == last_value)
+            .ok_or("last value received is not in the log")?;
+
+        // Exclude the packets sent after the last value received, which the
+        // remote sidekick could not have seen yet.
+        let mut diff = self.quack.clone();
+        for id in self.log.iter().skip(index + 1) {
+            diff.remove(id.clone());
+        }
+        let (missing, decoded) = decode_missing(
+            diff,
+            received,
+            self.threshold,
+            &self.log.make_contiguous()[..(index + 1)],
+        )?;
+        self.quack.sub_assign(missing);
+        self.log.drain(..(index + 1));
+        Ok(decoded)
+    }
+
+    /// Decode the identifiers missing from a sliding-window quACK `received`
+    /// from a remote sidekick, whose oldest identifier is `window_start`,
+    /// using the log of identifiers sniffed by this sidekick. The logged
+    /// identifiers before the window are drained from the log. Unlike
+    /// `decode`, a missing identifier is reported by every quACK whose window
+    /// spans it.
+    pub fn decode_window(
+        &mut self,
+        received: Q,
+        window_start: u64,
+    ) -> Result<Decoded<Q::Element>, String>
+    where
+        Q::Element: Clone + PartialEq,
+    {
+        let last_value = match received.last_value() {
+            Some(last_value) => last_value,
+            None => return Ok(Decoded::default()),
+        };
+        let end = self
+            .log
+            .iter()
+            .rposition(|id| *id == last_value)
+            .ok_or("last value received is not in the log")?;
+        let window_start = Q::element(window_start);
+        let start = self
+            .log
+            .iter()
+            .take(end + 1)
+            .rposition(|id| *id == window_start)
+            .ok_or("window start received is not in the log")?;
+
+        // The window spans every packet sent from its oldest identifier to
+        // the last value received.
+        self.log.drain(..start);
+        let window = &self.log.make_contiguous()[..(end - start + 1)];
+        let mut sent = Q::new(self.threshold);
+        for id in window {
+            sent.insert(id.clone());
+        }
+        decode_missing(sent, received, self.threshold, window).map(|(_, decoded)| decoded)
+    }
+}
+
+impl<Q: WireQuack> QuackEncoder for Sidekick<Q> {
+    fn insert(&mut self, packet: &Packet) {
+        #[cfg(feature = "benchmark")]
+        if self.start_time.is_none() {
+            self.start_time = Some(tokio::time::Instant::now());
+        }
+        self.insert_sniffed(packet.id, packet.len, packet.ce, packet.timestamp);
+    }
+
+    fn reset(&mut self) {
+        Sidekick::reset(self);
+    }
+
+    fn count(&self) -> u32 {
+        self.quack.count()
+    }
+
+    fn encode(&mut self, buf: &mut Vec<u8>) -> Result<(), String> {
+        buf.resize(
+            Q::encoded_len(self.threshold) + encoding::MAX_EXTENSIONS_LEN,
+            0,
+        );
+        let len = self.encode_quack(self.num_sums, buf)?;
+        buf.truncate(len);
         Ok(())
     }
+}
 
-    /// Snapshot the quACK.
-    pub fn quack(&self) -> PowerSumQuackU32 {
-        self.quack.clone()
+/// The identifiers a sidekick received most recently, to attach to the next
+/// quACK as a fallback when the end host could not decode the last one.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RecentIds {
+    ids: VecDeque<u64>,
+    /// Whether the end host requested the identifiers since the last quACK
+    /// was emitted
+    requested: bool,
+}
+
+impl RecentIds {
+    /// Insert an identifier, keeping at most `capacity` identifiers.
+    pub(crate) fn insert(&mut self, id: u64, capacity: usize) {
+        if self.ids.len() >= capacity {
+            self.ids.pop_front();
+        }
+        self.ids.push_back(id);
+    }
+
+    /// Record that the end host requested the identifiers.
+    pub(crate) fn request(&mut self) {
+        self.requested = true;
+    }
+
+    /// Returns the recent identifiers if the end host requested them.
+    pub(crate) fn fallback(&self) -> Option<Vec<u64>> {
+        if !self.requested || self.ids.is_empty() {
+            return None;
+        }
+        Some(self.ids.iter().copied().collect())
+    }
+
+    /// Record that a quACK was emitted, with the identifiers if requested.
+    pub(crate) fn emitted(&mut self) {
+        self.requested = false;
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.ids.clear();
+        self.requested = false;
+    }
+}
+
+/// The identifiers of the packets missing from a decoded quACK.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Decoded<T> {
+    /// Identifiers of the missing packets
+    pub missing: Vec<T>,
+    /// Identifiers shared by several packets, some of which are missing, but
+    /// which ones is indeterminate
+    pub indeterminate: Vec<T>,
+}
+
+/// Whether the end host should request the recent identifiers as a fallback
+/// after decoding a quACK: if the decoding failed, e.g., because more than the
+/// threshold number of packets are missing, or if some missing packets are
+/// indeterminate.
+pub fn needs_fallback<T>(decoded: &Result<Decoded<T>, String>) -> bool {
+    match decoded {
+        Ok(decoded) => !decoded.indeterminate.is_empty(),
+        Err(_) => true,
+    }
+}
+
+impl<T> Default for Decoded<T> {
+    fn default() -> Self {
+        Self {
+            missing: vec![],
+            indeterminate: vec![],
+        }
     }
+}
 
-    /// Snapshot the quACK and current log.
-    pub fn quack_with_log(&self) -> (PowerSumQuackU32, Vec<u32>) {
-        // TODO: don't clone the log
-        (self.quack.clone(), self.log.clone())
+/// Subtract a quACK that the end host has fully processed from a cumulative
+/// `quack` whose sliding window is `window`.
+pub(crate) fn subtract_processed<Q: WireQuack>(
+    quack: &mut Q,
+    processed: Q,
+    window: usize,
+) -> Result<(), String> {
+    if window > 0 {
+        return Err("cannot acknowledge a sliding-window quACK".to_string());
+    }
+    if processed.threshold() != quack.threshold() {
+        return Err(format!(
+            "acknowledged {} power sums, expected {}",
+            processed.threshold(),
+            quack.threshold()
+        ));
+    }
+    if processed.count() > quack.count() {
+        return Err(format!(
+            "acknowledged more packets than sniffed: {} > {}",
+            processed.count(),
+            quack.count()
+        ));
+    }
+    quack.sub_assign(processed);
+    Ok(())
+}
+
+/// Decode the identifiers in `log` that are in the `sent` quACK but not the
+/// `received` quACK. Returns the quACK of the missing packets and their
+/// identifiers.
+fn decode_missing<Q: WireQuack>(
+    mut sent: Q,
+    received: Q,
+    threshold: usize,
+    log: &[Q::Element],
+) -> Result<(Q, Decoded<Q::Element>), String>
+where
+    Q::Element: PartialEq,
+{
+    if sent.count() < received.count() {
+        return Err(format!(
+            "received more packets than sent: {} < {}",
+            sent.count(),
+            received.count()
+        ));
+    }
+    sent.sub_assign(received);
+    if sent.count() as usize > threshold {
+        return Err(format!(
+            "missing packets exceed threshold: {} > {}",
+            sent.count(),
+            threshold
+        ));
+    }
+    let mut decoded = Decoded::default();
+    if sent.count() == 0 {
+        return Ok((sent, decoded));
+    }
+    for id in sent.decode_with_log(log) {
+        if log.iter().filter(|logged| **logged == id).count() == 1 {
+            decoded.missing.push(id);
+        } else if !decoded.indeterminate.contains(&id) {
+            decoded.indeterminate.push(id);
+        }
+    }
+    Ok((sent, decoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quack::{PowerSumQuack, PowerSumQuackU32};
+
+    /// A receiver with a threshold of 3 that logged the identifiers.
+    fn receiver(logged: &[u64]) -> Sidekick<PowerSumQuackU32> {
+        let mut sc = Sidekick::new_receiver("lo", 3, 32, 100);
+        for &id in logged {
+            sc.insert_sniffed(id, 100, false, None);
+        }
+        sc
+    }
+
+    fn quack(ids: &[u64]) -> PowerSumQuackU32 {
+        let mut quack = PowerSumQuackU32::new(3);
+        for &id in ids {
+            quack.insert(PowerSumQuackU32::element(id));
+        }
+        quack
+    }
+
+    #[test]
+    fn test_decoded() {
+        let mut sc = receiver(&[1, 2, 3, 4, 5]);
+        let decoded = sc.decode(quack(&[1, 2, 4, 5]));
+        assert_eq!(decoded.as_ref().unwrap().missing, vec![3]);
+        assert!(!needs_fallback(&decoded));
+    }
+
+    #[test]
+    fn test_fallback_decode_fails() {
+        let mut sc = receiver(&[1, 2, 3, 4, 5]);
+        let decoded = sc.decode(quack(&[1, 2, 6]));
+        assert!(decoded.is_err());
+        assert!(needs_fallback(&decoded));
+    }
+
+    #[test]
+    fn test_fallback_indeterminate() {
+        let mut sc = receiver(&[1, 2, 2, 3]);
+        let decoded = sc.decode(quack(&[1, 2, 3]));
+        assert_eq!(decoded.as_ref().unwrap().indeterminate, vec![2]);
+        assert!(needs_fallback(&decoded));
+    }
+
+    #[test]
+    fn test_fallback_exceeds_threshold() {
+        let mut sc = receiver(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        let decoded = sc.decode(quack(&[1, 2, 7, 8]));
+        assert!(decoded.is_err());
+        assert!(needs_fallback(&decoded));
+    }
+
+    #[test]
+    fn test_fallback_requested() {
+        let mut sc = Sidekick::<PowerSumQuackU32>::new("lo", 3, 32);
+        sc.fallback = 2;
+        for id in 1..=3 {
+            sc.insert_sniffed(id, 100, false, None);
+        }
+        assert_eq!(sc.extensions().recent_ids, None);
+        sc.request_fallback();
+        assert_eq!(sc.extensions().recent_ids, Some(vec![2, 3]));
+        let mut buf = vec![0; PowerSumQuackU32::encoded_len(3) + encoding::MAX_EXTENSIONS_LEN];
+        sc.encode_quack(None, &mut buf).unwrap();
+        assert_eq!(sc.extensions().recent_ids, None);
     }
 }
@@ -1,18 +1,65 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
-use log::{info, trace};
+use log::{info, trace, warn};
 use tokio;
-use tokio::{net::UdpSocket, sync::oneshot, time::Instant};
+use tokio::{
+    net::UdpSocket,
+    sync::oneshot,
+    time::{self, Duration, Instant},
+};
 
-use crate::buffer::{Direction, UdpParser, BUFFER_SIZE};
+use crate::buffer::{
+    Direction, IdentifierProfile, QuicHeader, UdpParser, BUFFER_SIZE, DCID_OFFSET, MAX_CID_LEN,
+};
+use crate::calibrate::IdMonitor;
+use crate::collision::{CollisionDetector, CollisionStats};
+use crate::config::Rule;
+use crate::encoding::{self, Extensions, Timestamps, WireQuack};
+use crate::events::{Event, EventLog, Eviction};
+use crate::filter::{Action as FilterAction, FlowFilter};
+use crate::sidekick::{subtract_processed, RecentIds};
 use crate::socket::SockAddr;
+use crate::stats::{self, FlowStats, SniffCounters, SniffStats};
 use crate::Socket;
-use quack::{PowerSumQuack, PowerSumQuackU32};
 
 type AddrKey = [u8; 12];
+/// Destination connection ID of a QUIC packet, padded with zeros
+type ConnId = [u8; MAX_CID_LEN];
 
 const IP_PROTOCOL: u16 = (libc::ETH_P_IP as u16).to_be();
+/// Maximum number of denied flows to remember, after which they are
+/// forgotten and evaluated by the filter again
+const MAX_DENIED_FLOWS: usize = 1 << 16;
+/// Maximum number of connection IDs and of aliases to remember, after which
+/// those of flows that no longer exist are forgotten
+const MAX_CIDS: usize = 1 << 16;
+
+/// The address key of the flow from `src` to `dst`.
+pub fn addr_key(src: SocketAddrV4, dst: SocketAddrV4) -> AddrKey {
+    let mut addr_key = [0; 12];
+    addr_key[0..4].copy_from_slice(&src.ip().octets());
+    addr_key[4..6].copy_from_slice(&src.port().to_be_bytes());
+    addr_key[6..10].copy_from_slice(&dst.ip().octets());
+    addr_key[10..12].copy_from_slice(&dst.port().to_be_bytes());
+    addr_key
+}
+
+/// The source and destination addresses of the flow with the address key.
+pub fn flow_addrs(addr_key: &AddrKey) -> (SocketAddrV4, SocketAddrV4) {
+    let k = addr_key;
+    let src = SocketAddrV4::new(
+        Ipv4Addr::new(k[0], k[1], k[2], k[3]),
+        u16::from_be_bytes([k[4], k[5]]),
+    );
+    let dst = SocketAddrV4::new(
+        Ipv4Addr::new(k[6], k[7], k[8], k[9]),
+        u16::from_be_bytes([k[10], k[11]]),
+    );
+    (src, dst)
+}
 
 #[cfg(any(feature = "cycles"))]
 static mut CYCLES_COUNT: u64 = 0;
@@ -20,7 +67,7 @@ static mut CYCLES_COUNT: u64 = 0;
 static mut CYCLES: [u64; 5] = [0; 5];
 
 #[derive(Clone)]
-pub struct SidekickMulti {
+pub struct SidekickMulti<Q: WireQuack> {
     /// Interface to listen on
     pub interface: String,
 
@@ -28,48 +75,587 @@ pub struct SidekickMulti {
     pub threshold: usize,
     pub bits: usize,
 
+    /// Whether to timestamp sniffed packets and include timestamps in quACKs
+    pub timestamps: bool,
+    /// Whether to split frames coalesced by GRO into their UDP segments
+    pub gso: bool,
+    /// Whether to include the cumulative byte count in quACKs
+    pub byte_counts: bool,
+    /// Whether to include the cumulative CE-marked packet count in quACKs
+    pub ce_counts: bool,
+    /// Direction of the packets to sniff
+    pub direction: Direction,
+    /// Number of most recent identifiers in a sliding-window quACK, or 0 for
+    /// a cumulative quACK
+    pub window: usize,
+    /// Maximum number of recent identifiers to attach to a quACK when the end
+    /// host requests them, or 0 to never attach them
+    pub fallback: usize,
+    /// Whether acknowledged quACKs are received on my address with
+    /// `listen_for_acks_multi`, instead of resets
+    pub acks: bool,
+    /// Whether to only quACK QUIC packets with short headers, skipping
+    /// handshake packets and the datagrams they are coalesced in
+    pub short_headers_only: bool,
+    /// Length of the destination connection IDs of QUIC packets to
+    /// demultiplex flows by, or 0 to demultiplex flows by address only. Only
+    /// packets with short headers are quACKed if set.
+    pub cid_len: usize,
+    /// Where the identifier is in the packets of flows whose rule does not
+    /// specify a profile
+    pub profile: IdentifierProfile,
+    /// Whether to warn if the identifiers of a flow do not look uniformly
+    /// random
+    pub check_ids: bool,
+    /// Number of recent identifiers of each flow to detect collisions with,
+    /// or 0 to not detect collisions
+    pub collision_window: usize,
+    /// Log of the quACK events of each flow, if any
+    pub events: Option<Arc<EventLog>>,
+
     /// Time the first packet is inserted, for benchmarking
     #[cfg(feature = "benchmark")]
     pub start_time: Option<Instant>,
 
-    /// Map from UDP source and dest address to the quack
-    senders: HashMap<AddrKey, PowerSumQuackU32>,
+    /// Map from UDP source and dest address to the flow state
+    senders: HashMap<AddrKey, Flow<Q>>,
+    /// Rules for how to quACK the flows to each destination
+    rules: Vec<Rule>,
+    /// Which flows to quACK, evaluated once for each new flow
+    filter: FlowFilter,
+    /// Flows that the filter denied, at most `MAX_DENIED_FLOWS`
+    denied: HashSet<AddrKey>,
+    /// Map from connection ID to the address key of the flow, at most
+    /// `MAX_CIDS`
+    cids: HashMap<ConnId, AddrKey>,
+    /// Map from the previous address keys of flows that moved to a new path
+    /// to their current address key, at most `MAX_CIDS`
+    aliases: HashMap<AddrKey, AddrKey>,
+    /// Counts of the sniffed packets by what was done with them
+    counters: Arc<SniffCounters>,
+    /// Counts of what was done with the flows that were removed
+    evicted: FlowStats,
+}
+
+/// State of a single flow.
+#[derive(Clone)]
+pub struct Flow<Q: WireQuack> {
+    /// The cumulative quACK
+    pub quack: Q,
+    /// Capture time of the last packet inserted in the quACK
+    pub last_capture: Option<SystemTime>,
+    /// When the last packet of the flow was inserted, captured or not
+    pub last_seen: Option<SystemTime>,
+    /// Cumulative number of IP bytes in the packets inserted in the quACK
+    pub bytes: u64,
+    /// Cumulative number of CE-marked packets inserted in the quACK
+    pub ce_count: u32,
+    /// Whether to include the byte count in quACKs
+    byte_counts: bool,
+    /// Whether to include the CE count in quACKs
+    ce_counts: bool,
+    /// Number of most recent identifiers in the quACK, or 0 if cumulative
+    window: usize,
+    /// The most recent identifiers in a sliding-window quACK
+    window_ids: VecDeque<u64>,
+    /// Maximum number of recent identifiers to attach as a fallback
+    fallback: usize,
+    /// The recent identifiers to attach as a fallback
+    recent_ids: RecentIds,
+    /// Number of packets inserted since the flow was last quACKed, which is
+    /// not the quACK count once a sliding window is full
+    pkts_since_quack: u32,
+    /// Index of the first rule that matches the flow, if any
+    pub rule: Option<usize>,
+    /// First byte of the UDP payload of the packet that created the flow, to
+    /// match the flow against rules
+    first_byte: u8,
+    /// Checks that the identifiers look uniformly random, if enabled
+    monitor: Option<IdMonitor>,
+    /// Detects collisions among the recent identifiers, if enabled
+    collisions: Option<CollisionDetector>,
+    /// Counts of what was done with the flow since it was created
+    pub stats: FlowStats,
+}
+
+impl<Q: WireQuack> Flow<Q> {
+    fn new(
+        rule: Option<usize>,
+        threshold: usize,
+        byte_counts: bool,
+        ce_counts: bool,
+        window: usize,
+        fallback: usize,
+        monitor: Option<IdMonitor>,
+    ) -> Self {
+        Self {
+            quack: Q::new(threshold),
+            last_capture: None,
+            last_seen: None,
+            bytes: 0,
+            ce_count: 0,
+            byte_counts,
+            ce_counts,
+            window,
+            window_ids: VecDeque::new(),
+            fallback: fallback.min(encoding::max_recent_ids(Q::WORD_LEN)),
+            recent_ids: RecentIds::default(),
+            pkts_since_quack: 0,
+            rule,
+            first_byte: 0,
+            monitor,
+            collisions: None,
+            stats: FlowStats::default(),
+        }
+    }
+
+    /// Insert the identifier in the quACK, expiring the oldest identifier if
+    /// the quACK is a full sliding window.
+    fn insert_id(&mut self, id: u64) {
+        self.quack.insert(Q::element(id));
+        self.pkts_since_quack = self.pkts_since_quack.saturating_add(1);
+        if self.fallback > 0 {
+            self.recent_ids.insert(id, self.fallback);
+        }
+        if self.window > 0 {
+            self.window_ids.push_back(id);
+            if self.window_ids.len() > self.window {
+                let expired = self.window_ids.pop_front().unwrap();
+                self.quack.remove(Q::element(expired));
+            }
+        }
+    }
+
+    /// Number of identifiers and collisions among the recent identifiers.
+    pub fn collision_stats(&self) -> CollisionStats {
+        self.collisions
+            .as_ref()
+            .map_or(CollisionStats::default(), CollisionDetector::stats)
+    }
+
+    /// Extensions to include in the next quACK message.
+    pub fn extensions(&self) -> Extensions {
+        Extensions {
+            timestamps: self.last_capture.map(|captured| Timestamps {
+                captured,
+                emitted: SystemTime::now(),
+            }),
+            bytes: self.byte_counts.then_some(self.bytes),
+            ce_count: self.ce_counts.then_some(self.ce_count),
+            window_start: self.window_ids.front().copied(),
+            recent_ids: self.recent_ids.fallback(),
+        }
+    }
+
+    /// Whether a quACK is due if the flow is quACKed every `frequency_pkts`
+    /// packets.
+    pub fn is_due(&self, frequency_pkts: u32) -> bool {
+        self.pkts_since_quack >= frequency_pkts
+    }
+
+    /// Encode the quACK message into the buffer, including only the first
+    /// `num_sums` power sums if specified. Returns the encoded length.
+    pub fn encode(&mut self, num_sums: Option<usize>, buf: &mut [u8]) -> Result<usize, String> {
+        let len = encoding::encode_message(&self.quack, num_sums, &self.extensions(), buf)?;
+        self.recent_ids.emitted();
+        self.pkts_since_quack = 0;
+        self.stats.quacks += 1;
+        Ok(len)
+    }
 }
 
 enum Action {
     Skip,
     Reset { addr_key: AddrKey },
-    Insert { addr_key: AddrKey, sidekick_id: u32 },
+    Insert {
+        addr_key: AddrKey,
+        cid: Option<ConnId>,
+        n: usize,
+        len: u16,
+        ce: bool,
+        first_byte: u8,
+    },
 }
 
-impl SidekickMulti {
+impl<Q: WireQuack> SidekickMulti<Q> {
     /// Create a new sidekick.
     pub fn new(interface: &str, threshold: usize, bits: usize) -> Self {
-        assert_eq!(bits, 32, "ERROR: <num_bits_id> must be 32");
+        assert!(
+            bits > 0 && bits % 8 == 0 && bits <= 8 * Q::WORD_LEN,
+            "ERROR: <num_bits_id> must be a multiple of 8 up to {}",
+            8 * Q::WORD_LEN
+        );
         Self {
             interface: interface.to_string(),
             threshold,
             bits,
+            timestamps: false,
+            gso: false,
+            byte_counts: false,
+            ce_counts: false,
+            direction: Direction::Incoming,
+            window: 0,
+            fallback: 0,
+            short_headers_only: false,
+            cid_len: 0,
+            profile: IdentifierProfile::default(),
+            check_ids: false,
+            collision_window: 0,
+            events: None,
+            acks: false,
             #[cfg(feature = "benchmark")]
             start_time: None,
             senders: HashMap::new(),
+            rules: vec![],
+            filter: FlowFilter::default(),
+            denied: HashSet::new(),
+            cids: HashMap::new(),
+            aliases: HashMap::new(),
+            counters: Arc::default(),
+            evicted: FlowStats::default(),
+        }
+    }
+
+    /// How to parse sniffed packets, resetting all flows on resets sent to
+    /// `my_addr`.
+    fn parse_config(&self, my_addr: [u8; 6]) -> ParseConfig {
+        ParseConfig {
+            my_addr,
+            acks: self.acks,
+            direction: self.direction,
+            short_headers_only: self.short_headers_only || self.cid_len > 0,
+            cid_len: self.cid_len,
+            counters: self.counters.clone(),
+        }
+    }
+
+    /// Open the raw socket to sniff on, with timestamps and GSO metadata if
+    /// enabled.
+    fn open_socket(&self) -> Result<Socket, String> {
+        let mut sock = Socket::new(self.interface.clone())?;
+        sock.set_promiscuous()?;
+        if self.timestamps {
+            sock.enable_timestamps()?;
+        }
+        if self.gso {
+            sock.enable_vnet_hdr()?;
+        }
+        Ok(sock)
+    }
+
+    /// Rules for how to quACK the flows to each destination.
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Replace the rules, matching the existing flows against the new rules
+    /// without resetting them.
+    pub fn set_rules(&mut self, rules: Vec<Rule>) {
+        for (addr_key, flow) in self.senders.iter_mut() {
+            (flow.rule, _) = match_rule(&rules, self.threshold, addr_key, flow.first_byte);
+        }
+        self.rules = rules;
+    }
+
+    /// Which flows to quACK.
+    pub fn filter(&self) -> &FlowFilter {
+        &self.filter
+    }
+
+    /// Replace the filter of which flows to quACK. Flows that were already
+    /// allowed are kept.
+    pub fn set_filter(&mut self, filter: FlowFilter) {
+        self.filter = filter;
+        self.denied.clear();
+    }
+
+    /// Whether to quACK the flow, given the first byte of the UDP payload of
+    /// its latest packet. The filter is only evaluated for new flows, and the
+    /// flows it denies are remembered so that they do not consume quACK
+    /// state. Flows denied on a QUIC packet with a long header are not
+    /// remembered, since a `quic` condition only matches their packets with
+    /// short headers after the handshake.
+    pub fn admit(&mut self, addr_key: &AddrKey, first_byte: u8) -> bool {
+        if self.filter.rules.is_empty() && self.filter.default == FilterAction::Allow {
+            return true;
+        }
+        if self.senders.contains_key(addr_key) {
+            return true;
+        }
+        if self.denied.contains(addr_key) {
+            stats::incr(&self.counters.filtered);
+            return false;
+        }
+        if self.filter.allows(addr_key, first_byte) {
+            true
+        } else {
+            trace!("denied flow {:?}", addr_key);
+            stats::incr(&self.counters.filtered);
+            if QuicHeader::from(first_byte) == QuicHeader::Long {
+                return false;
+            }
+            if self.denied.len() >= MAX_DENIED_FLOWS {
+                self.denied.clear();
+            }
+            self.denied.insert(*addr_key);
+            false
+        }
+    }
+
+    /// The address key that the flow of a packet is stored under, given the
+    /// packet's address key and connection ID.
+    ///
+    /// If the flow with the connection ID was last seen on another path, e.g.,
+    /// after a NAT rebinding, it is moved to the new address key with its
+    /// quACK, and the old address key becomes an alias of the new one. Packets
+    /// on an alias, e.g., reordered across the path change, are stored under
+    /// the current address key. An endpoint that migrates on purpose switches
+    /// to a new connection ID, which starts a new flow.
+    ///
+    /// The packet must already be admitted, so that denied flows do not
+    /// consume connection ID state.
+    pub fn flow_key(&mut self, addr_key: AddrKey, cid: Option<ConnId>) -> AddrKey {
+        let cid = match cid {
+            Some(cid) => cid,
+            None => return addr_key,
+        };
+        if self.cids.len() >= MAX_CIDS && !self.cids.contains_key(&cid) {
+            self.prune_cids();
+        }
+        let current = *self.cids.entry(cid).or_insert(addr_key);
+        if current == addr_key || self.aliases.get(&addr_key) == Some(&current) {
+            return current;
+        }
+
+        // The connection moved to a new path, replacing the flow there, if
+        // any, whose connection IDs are forgotten.
+        if self.senders.contains_key(&current) && self.senders.contains_key(&addr_key) {
+            self.forget_cids(&addr_key);
+        }
+        if self.aliases.len() >= MAX_CIDS {
+            self.prune_cids();
+        }
+        self.cids.insert(cid, addr_key);
+        self.aliases.remove(&addr_key);
+        for alias in self.aliases.values_mut() {
+            if *alias == current {
+                *alias = addr_key;
+            }
+        }
+        self.aliases.insert(current, addr_key);
+        if let Some(mut flow) = self.senders.remove(&current) {
+            info!("flow {:?} moved to {:?}", current, addr_key);
+            (flow.rule, _) = match_rule(&self.rules, self.threshold, &addr_key, flow.first_byte);
+            if let Some(replaced) = self.senders.insert(addr_key, flow) {
+                warn!("replaced flow {:?} with a moved flow", addr_key);
+                self.evicted += replaced.stats;
+                stats::incr(&self.counters.evictions);
+                self.log_event(
+                    &addr_key,
+                    Event::FlowEvicted {
+                        reason: Eviction::Replaced,
+                        stats: replaced.stats,
+                    },
+                );
+            }
+        }
+        addr_key
+    }
+
+    /// Forget the connection IDs and aliases of flows that no longer exist,
+    /// or of all flows if there are still too many.
+    fn prune_cids(&mut self) {
+        let senders = &self.senders;
+        self.cids
+            .retain(|_, addr_key| senders.contains_key(addr_key));
+        self.aliases
+            .retain(|_, addr_key| senders.contains_key(addr_key));
+        if self.cids.len() >= MAX_CIDS || self.aliases.len() >= MAX_CIDS {
+            warn!("forgetting the connection IDs of all flows");
+            self.cids.clear();
+            self.aliases.clear();
+        }
+    }
+
+    /// Forget the connection IDs and aliases of the flow.
+    fn forget_cids(&mut self, addr_key: &AddrKey) {
+        self.cids.retain(|_, key| key != addr_key);
+        self.aliases
+            .retain(|alias, key| alias != addr_key && key != addr_key);
+    }
+
+    /// Remove all flows, e.g., on a quACK reset.
+    pub fn clear(&mut self) {
+        for (addr_key, flow) in self.senders.iter() {
+            self.evicted += flow.stats;
+            stats::incr(&self.counters.evictions);
+            self.log_event(
+                addr_key,
+                Event::FlowEvicted {
+                    reason: Eviction::Reset,
+                    stats: flow.stats,
+                },
+            );
+        }
+        self.senders.clear();
+        self.denied.clear();
+        self.cids.clear();
+        self.aliases.clear();
+    }
+
+    /// Log an event of the flow, if there is an event log.
+    fn log_event(&self, addr_key: &AddrKey, event: Event) {
+        if let Some(events) = &self.events {
+            events.log(Some(addr_key), event);
+        }
+    }
+
+    /// Where the identifier is in the packets of the flow: the profile of its
+    /// rule, if it has one, or the default profile. A new flow is matched
+    /// against the rules with the first byte of the UDP payload of its packet.
+    pub fn profile(&self, addr_key: &AddrKey, first_byte: u8) -> IdentifierProfile {
+        if self.rules.is_empty() {
+            return self.profile;
+        }
+        let rule = match self.senders.get(addr_key) {
+            Some(flow) => flow.rule,
+            None => match_rule(&self.rules, self.threshold, addr_key, first_byte).0,
+        };
+        rule.and_then(|i| self.rules[i].profile)
+            .unwrap_or(self.profile)
+    }
+
+    /// Parse the identifier of a packet of the flow from the first `n` bytes
+    /// of the buffer, or `None` if the packet has none.
+    fn parse_identifier(
+        &self,
+        addr_key: &AddrKey,
+        n: usize,
+        buf: &[u8; BUFFER_SIZE],
+    ) -> Option<u64> {
+        let profile = self.profile(addr_key, UdpParser::parse_first_byte(buf));
+        let id = UdpParser::parse_identifier_profile(buf, n, self.bits, &profile);
+        if id.is_none() {
+            stats::incr(&self.counters.underfilled);
+        }
+        id
+    }
+
+    /// Number of identifiers and collisions among the recent identifiers of
+    /// all flows.
+    pub fn collision_stats(&self) -> CollisionStats {
+        let mut stats = CollisionStats::default();
+        for flow in self.senders.values() {
+            stats += flow.collision_stats();
+        }
+        stats
+    }
+
+    /// Counts of the sniffed packets by what was done with them.
+    pub fn stats(&self) -> SniffStats {
+        self.counters.stats()
+    }
+
+    /// The counters, which the socket thread updates without the lock.
+    pub fn counters(&self) -> Arc<SniffCounters> {
+        self.counters.clone()
+    }
+
+    /// Counts of what was done with all flows, including removed flows.
+    pub fn flow_stats(&self) -> FlowStats {
+        let mut stats = self.evicted;
+        for flow in self.senders.values() {
+            stats += flow.stats;
         }
+        stats
+    }
+
+    /// Maximum threshold of any flow, to size the buffers of quACK messages.
+    pub fn max_threshold(&self) -> usize {
+        self.rules
+            .iter()
+            .filter_map(|rule| rule.threshold)
+            .chain(self.senders.values().map(|flow| flow.quack.threshold()))
+            .fold(self.threshold, usize::max)
     }
 
     pub fn reset(&mut self, addr_key: &AddrKey) {
-        if let Some(quack) = self.senders.get_mut(addr_key) {
-            *quack = PowerSumQuackU32::new(self.threshold);
+        if let Some(flow) = self.senders.get_mut(addr_key) {
+            let first_byte = flow.first_byte;
+            let (rule, threshold) = match_rule(&self.rules, self.threshold, addr_key, first_byte);
+            let stats = FlowStats {
+                resets: flow.stats.resets + 1,
+                ..flow.stats
+            };
+            let last_seen = flow.last_seen;
+            *flow = Flow::new(
+                rule,
+                threshold,
+                self.byte_counts,
+                self.ce_counts,
+                self.window,
+                self.fallback,
+                self.check_ids.then(|| IdMonitor::new(self.bits)),
+            );
+            flow.stats = stats;
+            flow.last_seen = last_seen;
+            flow.first_byte = first_byte;
+            self.forget_cids(addr_key);
+            self.log_event(addr_key, Event::Reset);
+        }
+    }
+
+    /// Subtract a quACK that the end host has fully processed from the
+    /// cumulative quACK of the flow, instead of resetting it.
+    pub fn acknowledge(&mut self, addr_key: &AddrKey, processed: Q) -> Result<(), String> {
+        let flow = self
+            .senders
+            .get_mut(addr_key)
+            .ok_or(format!("no flow to acknowledge: {:?}", addr_key))?;
+        subtract_processed(&mut flow.quack, processed, flow.window)
+    }
+
+    /// Attach the recent identifiers to the next quACK of the flow, if
+    /// enabled, because the end host could not decode the last one.
+    pub fn request_fallback(&mut self, addr_key: &AddrKey) {
+        if let Some(flow) = self.senders.get_mut(addr_key) {
+            flow.recent_ids.request();
         }
     }
 
-    pub fn insert(&mut self, addr_key: AddrKey, sidekick_id: u32) -> &PowerSumQuackU32 {
+    /// Insert the identifier of a packet of the flow, creating the flow if
+    /// the packet, with the first byte of its UDP payload, is its first.
+    pub fn insert(
+        &mut self,
+        addr_key: AddrKey,
+        first_byte: u8,
+        sidekick_id: u64,
+        len: u16,
+        ce: bool,
+        timestamp: Option<SystemTime>,
+    ) -> &mut Flow<Q> {
         // ***CYCLES START step 2 hash address key
         #[cfg(feature = "cycles")]
         let start2 = unsafe { core::arch::x86_64::_rdtsc() };
-        let entry = self
-            .senders
-            .entry(addr_key)
-            .or_insert(PowerSumQuackU32::new(self.threshold));
+        stats::incr(&self.counters.inserts);
+        let entry = self.senders.entry(addr_key).or_insert_with(|| {
+            let (rule, threshold) = match_rule(&self.rules, self.threshold, &addr_key, first_byte);
+            stats::incr(&self.counters.flows);
+            if let Some(events) = &self.events {
+                events.log(Some(&addr_key), Event::FlowCreated { rule, threshold });
+            }
+            let mut flow = Flow::new(
+                rule,
+                threshold,
+                self.byte_counts,
+                self.ce_counts,
+                self.window,
+                self.fallback,
+                self.check_ids.then(|| IdMonitor::new(self.bits)),
+            );
+            flow.first_byte = first_byte;
+            flow
+        });
         // ***CYCLES STOP step 2 hash address key
         #[cfg(feature = "cycles")]
         unsafe {
@@ -79,66 +665,177 @@ impl SidekickMulti {
         // ***CYCLES START step 4 insert id into quack
         #[cfg(feature = "cycles")]
         let start4 = unsafe { core::arch::x86_64::_rdtsc() };
-        entry.insert(sidekick_id);
+        entry.insert_id(sidekick_id);
+        entry.stats.inserts += 1;
+        if let Some(warning) = entry
+            .monitor
+            .as_mut()
+            .and_then(|monitor| monitor.insert(sidekick_id))
+        {
+            warn!("flow {:?}: {}", addr_key, warning);
+        }
+        if self.collision_window > 0 {
+            let window = self.collision_window;
+            let collisions = entry
+                .collisions
+                .get_or_insert_with(|| CollisionDetector::new(window));
+            if collisions.insert(sidekick_id) {
+                trace!("collision in flow {:?}: {}", addr_key, sidekick_id);
+            }
+        }
+        entry.last_capture = timestamp;
+        entry.last_seen = Some(timestamp.unwrap_or_else(SystemTime::now));
+        entry.bytes += len as u64;
+        if ce {
+            entry.ce_count += 1;
+        }
+        if let Some(events) = &self.events {
+            let (id, count) = (sidekick_id, entry.quack.count());
+            events.log(Some(&addr_key), Event::Insert { id, count });
+        }
         // ***CYCLES STOP step 4 insert id into quack
         #[cfg(feature = "cycles")]
         unsafe {
             let stop4 = core::arch::x86_64::_rdtsc();
             CYCLES[4] += stop4 - start4;
         }
-        self.senders.get(&addr_key).as_ref().unwrap()
+        self.senders.get_mut(&addr_key).unwrap()
     }
 
-    pub fn quack(&self, addr_key: &AddrKey) -> Option<PowerSumQuackU32> {
-        self.senders.get(addr_key).cloned()
+    /// Encode the quACK of the flow into the buffer if its rule quACKs every
+    /// `frequency_pkts` packets and one is due. Returns the address to send
+    /// the quACK to.
+    fn encode_if_due(
+        &mut self,
+        addr_key: &AddrKey,
+        buf: &mut Vec<u8>,
+    ) -> Result<Option<SocketAddr>, String> {
+        let flow = match self.senders.get_mut(addr_key) {
+            Some(flow) => flow,
+            None => return Ok(None),
+        };
+        let rule = match flow.rule {
+            Some(i) => &self.rules[i],
+            None => return Ok(None),
+        };
+        match rule.frequency_pkts {
+            Some(frequency_pkts) if flow.is_due(frequency_pkts) => {}
+            _ => return Ok(None),
+        }
+        buf.resize(
+            Q::encoded_len(flow.quack.threshold()) + encoding::MAX_EXTENSIONS_LEN,
+            0,
+        );
+        let len = flow.encode(rule.num_sums, buf)?;
+        buf.truncate(len);
+        if let Some(events) = &self.events {
+            let count = flow.quack.count();
+            events.log(Some(addr_key), Event::Quack { count, len });
+        }
+        Ok(Some(rule.quack_addr))
     }
 
-    pub fn senders(&self) -> &HashMap<AddrKey, PowerSumQuackU32> {
+    pub fn quack(&self, addr_key: &AddrKey) -> Option<Q> {
+        self.senders.get(addr_key).map(|flow| flow.quack.clone())
+    }
+
+    pub fn senders(&self) -> &HashMap<AddrKey, Flow<Q>> {
         &self.senders
     }
+
+    pub fn senders_mut(&mut self) -> &mut HashMap<AddrKey, Flow<Q>> {
+        &mut self.senders
+    }
+}
+
+/// Index of the first rule that matches the flow, if any, and the threshold
+/// of the flow, given the first byte of the UDP payload of the packet that
+/// created the flow.
+fn match_rule(
+    rules: &[Rule],
+    threshold: usize,
+    addr_key: &AddrKey,
+    first_byte: u8,
+) -> (Option<usize>, usize) {
+    let rule = rules
+        .iter()
+        .position(|rule| rule.matches(addr_key, first_byte));
+    let threshold = rule.and_then(|i| rules[i].threshold).unwrap_or(threshold);
+    (rule, threshold)
+}
+
+/// How to parse sniffed packets.
+#[derive(Clone)]
+struct ParseConfig {
+    /// My IP and port to receive quACK resets
+    my_addr: [u8; 6],
+    /// Whether to skip the packets to my address, which are acknowledged
+    /// quACKs handled by `listen_for_acks_multi`
+    acks: bool,
+    /// Direction of the packets to sniff
+    direction: Direction,
+    /// Whether to only quACK QUIC packets with short headers
+    short_headers_only: bool,
+    /// Length of the connection IDs to demultiplex flows by, or 0
+    cid_len: usize,
+    /// Counts of the sniffed packets by what was done with them
+    counters: Arc<SniffCounters>,
 }
 
 fn process_one_packet(
     n: isize,
-    buf: &[u8; BUFFER_SIZE],
+    buf: &mut [u8; BUFFER_SIZE],
     addr: &libc::sockaddr_ll,
-    my_addr: [u8; 6],
+    config: &ParseConfig,
 ) -> Action {
-    if Direction::Incoming != addr.sll_pkttype.into() {
+    stats::incr(&config.counters.frames);
+    if config.direction != addr.sll_pkttype.into() {
+        stats::incr(&config.counters.wrong_direction);
         return Action::Skip;
     }
     if addr.sll_protocol != IP_PROTOCOL {
+        stats::incr(&config.counters.not_ip);
         return Action::Skip;
     }
-    if !UdpParser::is_udp(buf) {
+    let (validation, n) = UdpParser::validate(buf, n);
+    config.counters.record(validation);
+    if !validation.has_payload() {
         return Action::Skip;
     }
+    let buf = &*buf;
 
     // Reset the quack if the dst IP is our own (and not for another e2e quic
-    // connection).
+    // connection). Acknowledged quACKs are handled by `listen_for_acks_multi`.
     let addr_key = UdpParser::parse_addr_key(buf);
-    if addr_key[6..12] == my_addr {
+    if addr_key[6..12] == config.my_addr {
+        if config.acks {
+            return Action::Skip;
+        }
+        stats::incr(&config.counters.resets);
         return Action::Reset { addr_key };
     }
 
-    // Otherwise parse the identifier and insert it into the quack.
-    if n != (BUFFER_SIZE as _) {
+    // Otherwise insert the identifier into the quack, parsed with the profile
+    // of the flow.
+    if n < (DCID_OFFSET + config.cid_len) as _ {
+        stats::incr(&config.counters.underfilled);
         return Action::Skip;
     }
-
-    // ***CYCLES START step 3 parse identifier
-    #[cfg(feature = "cycles")]
-    let start3 = unsafe { core::arch::x86_64::_rdtsc() };
-    let sidekick_id = UdpParser::parse_identifier(buf);
-    // ***CYCLES STOP step 3 parse identifier
-    #[cfg(feature = "cycles")]
-    unsafe {
-        let stop3 = core::arch::x86_64::_rdtsc();
-        CYCLES[3] += stop3 - start3;
+    if config.short_headers_only && UdpParser::parse_quic_header(buf) != QuicHeader::Short {
+        return Action::Skip;
     }
+    let cid = (config.cid_len > 0).then(|| {
+        let mut cid = [0; MAX_CID_LEN];
+        cid[..config.cid_len].copy_from_slice(UdpParser::parse_dcid(buf, config.cid_len));
+        cid
+    });
     Action::Insert {
         addr_key,
-        sidekick_id,
+        cid,
+        n: n as usize,
+        len: UdpParser::parse_ip_len(buf),
+        ce: UdpParser::is_ce(buf),
+        first_byte: UdpParser::parse_first_byte(buf),
     }
 }
 
@@ -164,17 +861,79 @@ unsafe fn print_cycles_count_summary() {
     }
 }
 
+/// Listen for acknowledged quACKs from end hosts on the UDP socket address,
+/// and subtract them from the quACK of the flow from the end host's address
+/// to `dst`. The end host must send from the address of its flow. A
+/// single-byte message resets the flow, unless it requests the recent
+/// identifiers as a fallback. If an acknowledged quACK cannot be
+/// subtracted, the flow is reset and a single-byte NACK is sent back so that
+/// the end host resets too.
+pub async fn listen_for_acks_multi<Q: WireQuack>(
+    sc: Arc<Mutex<SidekickMulti<Q>>>,
+    addr: SocketAddr,
+    dst: SocketAddrV4,
+) -> Result<(), String> {
+    let sock = UdpSocket::bind(addr)
+        .await
+        .map_err(|e| format!("error binding to {}: {:?}", addr, e))?;
+    let threshold = sc.lock().unwrap().max_threshold();
+    let mut buf = vec![0; Q::encoded_len(threshold) + encoding::MAX_EXTENSIONS_LEN];
+    info!("listening for acknowledged quACKs on {:?}", addr);
+    loop {
+        let (len, peer) = sock
+            .recv_from(&mut buf)
+            .await
+            .map_err(|e| format!("error receiving ack: {:?}", e))?;
+        let addr_key = match peer {
+            SocketAddr::V4(peer) => addr_key(peer, dst),
+            SocketAddr::V6(_) => continue,
+        };
+        {
+            let mut sc = sc.lock().unwrap();
+            if !sc.senders.contains_key(&addr_key) {
+                warn!("no flow to acknowledge from {:?}", peer);
+                continue;
+            }
+            if len == 1 && buf[0] == encoding::ACK_FALLBACK {
+                sc.request_fallback(&addr_key);
+                continue;
+            }
+            if len <= 1 {
+                sc.reset(&addr_key);
+                continue;
+            }
+            let result =
+                Q::decode(&buf[..len]).and_then(|processed| sc.acknowledge(&addr_key, processed));
+            match result {
+                Ok(()) => continue,
+                Err(e) => {
+                    warn!("bad ack, resetting: {}", e);
+                    sc.reset(&addr_key);
+                }
+            }
+        }
+        if let Err(e) = sock.send_to(&[0], peer).await {
+            warn!("error sending nack: {:?}", e);
+        }
+    }
+}
+
 /// Start the raw socket that listens to the specified interface. Creates a new
-/// quack for every source socket address and accumulates the packets for that
-/// connection. Returns a channel that indicates the start time of when the
+/// quack for every source socket address, or every connection ID if
+/// `cid_len` is set, and accumulates the packets for that connection. Flows
+/// whose rule quACKs every `frequency_pkts` packets are quACKed from the
+/// socket thread. Returns a channel that indicates the start time of when the
 /// first packet is sniffed.
-pub fn start_sidekick_multi(
-    sc: Arc<Mutex<SidekickMulti>>,
+pub fn start_sidekick_multi<Q: WireQuack + Send + 'static>(
+    sc: Arc<Mutex<SidekickMulti<Q>>>,
     my_addr: [u8; 6],
 ) -> Result<oneshot::Receiver<Instant>, String> {
-    let interface = sc.lock().unwrap().interface.clone();
-    let sock = Socket::new(interface.clone())?;
-    sock.set_promiscuous()?;
+    let (mut sock, config) = {
+        let sc = sc.lock().unwrap();
+        (sc.open_socket()?, sc.parse_config(my_addr))
+    };
+    let sendsock = std::net::UdpSocket::bind("0.0.0.0:0")
+        .map_err(|e| format!("error binding to UDP socket: {:?}", e))?;
 
     // Creates the channel that indicates the time of when the first packet is
     // sniffed and inserted into a quack
@@ -182,42 +941,93 @@ pub fn start_sidekick_multi(
     tokio::task::spawn_blocking(move || {
         let mut addr = SockAddr::new_sockaddr_ll();
         let mut buf: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+        let mut quack_buf = vec![];
         let mut tx = Some(tx);
+        // When the last packet was sniffed, to observe how long it took to
+        // process when the next packet is sniffed
+        #[cfg(feature = "metrics")]
+        let mut sniffed: Option<std::time::Instant> = None;
 
         loop {
+            #[cfg(feature = "metrics")]
+            if let Some(sniffed) = sniffed.take() {
+                config.counters.latency.observe(sniffed.elapsed());
+            }
             // ***CYCLES START step 0 total
             #[cfg(feature = "cycles")]
             let start0 = unsafe { core::arch::x86_64::_rdtsc() };
             // ***CYCLES START step 1 sniff packet
             #[cfg(feature = "cycles")]
             let start1 = unsafe { core::arch::x86_64::_rdtsc() };
-            let n = sock.recvfrom(&mut addr, &mut buf).unwrap();
+            let (n, timestamp) = sock.recv_segment(&mut addr, &mut buf).unwrap();
+            #[cfg(feature = "metrics")]
+            {
+                sniffed = Some(std::time::Instant::now());
+            }
             // ***CYCLES STOP step 1 sniff packet
             #[cfg(feature = "cycles")]
             let stop1 = unsafe { core::arch::x86_64::_rdtsc() };
             trace!("received {} bytes: {:?}", n, buf);
-            match process_one_packet(n, &buf, &addr, my_addr) {
+            match process_one_packet(n, &mut buf, &addr, &config) {
                 Action::Skip => {
                     continue;
                 }
                 Action::Reset { addr_key } => {
                     info!("resetting quacks {:?}", addr_key);
-                    sc.lock().unwrap().senders = HashMap::new();
+                    sc.lock().unwrap().clear();
                 }
                 Action::Insert {
                     addr_key,
-                    sidekick_id,
+                    cid,
+                    n,
+                    len,
+                    ce,
+                    first_byte,
                 } => {
-                    let mut sc = sc.lock().unwrap();
-                    if let Some(tx) = tx.take() {
-                        let now = Instant::now();
-                        tx.send(now).unwrap();
-                        #[cfg(feature = "benchmark")]
-                        {
-                            sc.start_time = Some(now);
+                    let quack_addr = {
+                        let mut sc = sc.lock().unwrap();
+                        if !sc.admit(&addr_key, first_byte) {
+                            continue;
+                        }
+                        let addr_key = sc.flow_key(addr_key, cid);
+                        // ***CYCLES START step 3 parse identifier
+                        #[cfg(feature = "cycles")]
+                        let start3 = unsafe { core::arch::x86_64::_rdtsc() };
+                        let sidekick_id = match sc.parse_identifier(&addr_key, n, &buf) {
+                            Some(sidekick_id) => sidekick_id,
+                            None => continue,
+                        };
+                        // ***CYCLES STOP step 3 parse identifier
+                        #[cfg(feature = "cycles")]
+                        unsafe {
+                            let stop3 = core::arch::x86_64::_rdtsc();
+                            CYCLES[3] += stop3 - start3;
+                        }
+                        if let Some(tx) = tx.take() {
+                            let now = Instant::now();
+                            tx.send(now).unwrap();
+                            #[cfg(feature = "benchmark")]
+                            {
+                                sc.start_time = Some(now);
+                            }
+                        }
+                        sc.insert(addr_key, first_byte, sidekick_id, len, ce, timestamp);
+                        if sc.rules.is_empty() {
+                            None
+                        } else {
+                            sc.encode_if_due(&addr_key, &mut quack_buf)
+                                .unwrap_or_else(|e| {
+                                    warn!("error encoding quack: {}", e);
+                                    None
+                                })
+                        }
+                    };
+                    if let Some(quack_addr) = quack_addr {
+                        trace!("quack {:?} to {:?}", addr_key, quack_addr);
+                        if let Err(e) = sendsock.send_to(&quack_buf, quack_addr) {
+                            warn!("error sending quack: {:?}", e);
                         }
                     }
-                    sc.insert(addr_key, sidekick_id);
                 }
             }
             // ***CYCLES STOP step 0 total
@@ -237,51 +1047,244 @@ pub fn start_sidekick_multi(
     Ok(rx)
 }
 
-pub async fn start_sidekick_multi_frequency_pkts(
-    sc: Arc<Mutex<SidekickMulti>>,
+pub async fn start_sidekick_multi_frequency_pkts<Q: WireQuack>(
+    sc: Arc<Mutex<SidekickMulti<Q>>>,
     my_addr: [u8; 6],
     frequency_pkts: u32,
+    num_sums: Option<usize>,
     sendaddr: std::net::SocketAddr,
 ) -> Result<(), String> {
-    let interface = sc.lock().unwrap().interface.clone();
-    let sock = Socket::new(interface.clone())?;
-    sock.set_promiscuous()?;
+    let (mut sock, threshold, config) = {
+        let sc = sc.lock().unwrap();
+        (
+            sc.open_socket()?,
+            sc.max_threshold(),
+            sc.parse_config(my_addr),
+        )
+    };
 
     // Creates the channel that indicates the time of when the first packet is
     // sniffed and inserted into a quack
     let mut addr = SockAddr::new_sockaddr_ll();
     let mut buf: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
     let sendsock = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+    let mut quack_buf = vec![0; Q::encoded_len(threshold) + encoding::MAX_EXTENSIONS_LEN];
 
     loop {
-        let n = sock.recvfrom(&mut addr, &mut buf).unwrap();
+        let (n, timestamp) = sock.recv_segment(&mut addr, &mut buf).unwrap();
         trace!("received {} bytes: {:?}", n, buf);
-        match process_one_packet(n, &buf, &addr, my_addr) {
+        match process_one_packet(n, &mut buf, &addr, &config) {
             Action::Skip => {
                 continue;
             }
             Action::Reset { addr_key } => {
                 info!("resetting quacks {:?}", addr_key);
-                sc.lock().unwrap().senders = HashMap::new();
+                sc.lock().unwrap().clear();
             }
             Action::Insert {
                 addr_key,
-                sidekick_id,
+                cid,
+                n,
+                len,
+                ce,
+                first_byte,
             } => {
-                let quack = {
+                let len = {
                     let mut sc = sc.lock().unwrap();
-                    let quack = sc.insert(addr_key, sidekick_id);
-                    if quack.count() % frequency_pkts == 0 {
-                        trace!("quack {} {:?}", quack.count(), addr_key);
-                        Some(bincode::serialize(&quack).unwrap())
-                    } else {
-                        None
+                    if !sc.admit(&addr_key, first_byte) {
+                        continue;
+                    }
+                    let addr_key = sc.flow_key(addr_key, cid);
+                    let sidekick_id = match sc.parse_identifier(&addr_key, n, &buf) {
+                        Some(sidekick_id) => sidekick_id,
+                        None => continue,
+                    };
+                    let events = sc.events.clone();
+                    let flow = sc.insert(addr_key, first_byte, sidekick_id, len, ce, timestamp);
+                    if !flow.is_due(frequency_pkts) {
+                        continue;
+                    }
+                    trace!("quack {} {:?}", flow.quack.count(), addr_key);
+                    match flow.encode(num_sums, &mut quack_buf) {
+                        Ok(len) => {
+                            if let Some(events) = &events {
+                                let count = flow.quack.count();
+                                events.log(Some(&addr_key), Event::Quack { count, len });
+                            }
+                            len
+                        }
+                        Err(e) => {
+                            warn!("error encoding the quACK of flow {:?}: {}", addr_key, e);
+                            continue;
+                        }
                     }
                 };
-                if let Some(quack) = quack {
-                    sendsock.send_to(&quack, sendaddr).await.unwrap();
+                sendsock.send_to(&quack_buf[..len], sendaddr).await.unwrap();
+            }
+        }
+    }
+}
+
+/// Send the quACKs of the flows matching the rule at index `rule` to its quACK
+/// address every `frequency_ms`, until the task is aborted. The task must be
+/// aborted before the rules are replaced.
+pub async fn send_rule_quacks_ms<Q: WireQuack>(
+    sc: Arc<Mutex<SidekickMulti<Q>>>,
+    rule: usize,
+) -> Result<(), String> {
+    let (frequency_ms, num_sums, quack_addr) = {
+        let sc = sc.lock().unwrap();
+        let rule = &sc.rules[rule];
+        let frequency_ms = rule.frequency_ms.ok_or("rule does not quACK every ms")?;
+        (frequency_ms, rule.num_sums, rule.quack_addr)
+    };
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .map_err(|e| format!("error binding to UDP socket: {:?}", e))?;
+    // Reused across intervals, with the quACKs of the flows back to back
+    let mut buf: Vec<u8> = vec![];
+    let mut lens: Vec<usize> = vec![];
+    let mut interval = time::interval(Duration::from_millis(frequency_ms));
+    // The first tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        buf.clear();
+        lens.clear();
+        {
+            let mut sc = sc.lock().unwrap();
+            let events = sc.events.clone();
+            let flows = sc
+                .senders
+                .iter_mut()
+                .filter(|(_, flow)| flow.rule == Some(rule));
+            for (addr_key, flow) in flows {
+                let start = buf.len();
+                buf.resize(
+                    start + Q::encoded_len(flow.quack.threshold()) + encoding::MAX_EXTENSIONS_LEN,
+                    0,
+                );
+                let len = match flow.encode(num_sums, &mut buf[start..]) {
+                    Ok(len) => len,
+                    Err(e) => {
+                        warn!("error encoding the quACK of flow {:?}: {}", addr_key, e);
+                        buf.truncate(start);
+                        continue;
+                    }
+                };
+                buf.truncate(start + len);
+                lens.push(len);
+                if let Some(events) = &events {
+                    let count = flow.quack.count();
+                    events.log(Some(addr_key), Event::Quack { count, len });
                 }
             }
         }
+        let mut start = 0;
+        for &len in &lens {
+            socket
+                .send_to(&buf[start..(start + len)], quack_addr)
+                .await
+                .map_err(|e| format!("error sending quack: {:?}", e))?;
+            start += len;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::FlowMatch;
+    use quack::{PowerSumQuack, PowerSumQuackU32};
+
+    fn key(src_port: u16) -> AddrKey {
+        addr_key(
+            SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), src_port),
+            SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 443),
+        )
+    }
+
+    fn rule(frequency_pkts: u32) -> Rule {
+        Rule {
+            flows: FlowMatch::default(),
+            threshold: None,
+            profile: None,
+            frequency_ms: None,
+            frequency_pkts: Some(frequency_pkts),
+            num_sums: None,
+            quack_addr: "10.0.0.1:5103".parse().unwrap(),
+        }
+    }
+
+    /// Insert `n` packets into the flow, returning after which of them a
+    /// quACK was due.
+    fn quacked_after(sc: &mut SidekickMulti<PowerSumQuackU32>, n: u64) -> Vec<u64> {
+        let mut buf = vec![];
+        (1..=n)
+            .filter(|&id| {
+                sc.insert(key(5000), 0x40, id, 100, false, None);
+                sc.encode_if_due(&key(5000), &mut buf).unwrap().is_some()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_flow_key_moved() {
+        let mut sc = SidekickMulti::<PowerSumQuackU32>::new("lo", 10, 32);
+        let cid = Some([1; MAX_CID_LEN]);
+        assert_eq!(sc.flow_key(key(5000), cid), key(5000));
+        sc.insert(key(5000), 0x40, 1, 100, false, None);
+        assert_eq!(sc.flow_key(key(5001), cid), key(5001));
+        assert_eq!(sc.senders()[&key(5001)].quack.count(), 1);
+        assert!(!sc.senders().contains_key(&key(5000)));
+        // A reordered packet on the previous path
+        assert_eq!(sc.flow_key(key(5000), cid), key(5001));
+    }
+
+    #[test]
+    fn test_flow_key_reset() {
+        let mut sc = SidekickMulti::<PowerSumQuackU32>::new("lo", 10, 32);
+        let cid = Some([1; MAX_CID_LEN]);
+        sc.flow_key(key(5000), cid);
+        sc.insert(key(5000), 0x40, 1, 100, false, None);
+        sc.reset(&key(5000));
+        assert!(sc.cids.is_empty());
+        assert!(sc.aliases.is_empty());
+    }
+
+    #[test]
+    fn test_flow_key_bounded() {
+        let mut sc = SidekickMulti::<PowerSumQuackU32>::new("lo", 10, 32);
+        for i in 0..(MAX_CIDS as u32 + 1) {
+            let mut cid = [0; MAX_CID_LEN];
+            cid[..4].copy_from_slice(&i.to_be_bytes());
+            sc.flow_key(key(5000), Some(cid));
+        }
+        assert!(sc.cids.len() <= MAX_CIDS);
+    }
+
+    #[test]
+    fn test_frequency_pkts() {
+        let mut sc = SidekickMulti::<PowerSumQuackU32>::new("lo", 10, 32);
+        sc.set_rules(vec![rule(3)]);
+        assert_eq!(quacked_after(&mut sc, 10), vec![3, 6, 9]);
+    }
+
+    #[test]
+    fn test_frequency_pkts_window() {
+        let mut sc = SidekickMulti::<PowerSumQuackU32>::new("lo", 10, 32);
+        sc.window = 4;
+        sc.set_rules(vec![rule(3)]);
+        assert_eq!(quacked_after(&mut sc, 12), vec![3, 6, 9, 12]);
+        assert_eq!(sc.senders()[&key(5000)].quack.count(), 4);
+    }
+
+    #[test]
+    fn test_frequency_pkts_reset() {
+        let mut sc = SidekickMulti::<PowerSumQuackU32>::new("lo", 10, 32);
+        sc.set_rules(vec![rule(3)]);
+        assert_eq!(quacked_after(&mut sc, 2), vec![]);
+        sc.reset(&key(5000));
+        assert_eq!(quacked_after(&mut sc, 3), vec![3]);
     }
 }
@@ -1,12 +1,25 @@
-use crate::buffer::BUFFER_SIZE;
+use crate::buffer::{GsoSegments, BUFFER_SIZE, MAX_FRAME_LEN, VNET_HDR_LEN};
 use libc::*;
 use log::{debug, error};
 use std::ffi::CString;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Enough space for a control message with a timespec.
+const CMSG_BUFFER_SIZE: usize = 64;
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/if_packet.h
+const PACKET_VNET_HDR: c_int = 15;
 
 pub struct Socket {
     pub fd: i32,
     interface: String,
     interface_c: CString,
+    timestamps: bool,
+    /// Whether each frame is preceded by a virtio net header
+    vnet_hdr: bool,
+    /// The last frame received with a virtio net header
+    frame: Vec<u8>,
+    /// The remaining segments of the last frame, and its timestamp
+    segments: Option<(GsoSegments, Option<SystemTime>)>,
 }
 
 pub struct SockAddr {}
@@ -38,6 +51,10 @@ impl Socket {
                 fd,
                 interface: interface.clone(),
                 interface_c: CString::new(interface).unwrap(),
+                timestamps: false,
+                vnet_hdr: false,
+                frame: vec![],
+                segments: None,
             };
             sock.bind(protocol)?;
             Ok(sock)
@@ -103,6 +120,26 @@ impl Socket {
         Ok(())
     }
 
+    /// Enable kernel receive timestamps with nanosecond resolution.
+    pub fn enable_timestamps(&mut self) -> Result<(), String> {
+        debug!("enabling SO_TIMESTAMPNS");
+        let enable: c_int = 1;
+        let res = unsafe {
+            setsockopt(
+                self.fd,
+                SOL_SOCKET,
+                SO_TIMESTAMPNS,
+                (&enable as *const c_int) as _,
+                std::mem::size_of::<c_int>() as _,
+            )
+        };
+        if res < 0 {
+            return Err(format!("setsockopt: {}", res));
+        }
+        self.timestamps = true;
+        Ok(())
+    }
+
     /// Receive first `BUFFER_SIZE` packets of a buffer.
     pub fn recv(&self, buf: &[u8; BUFFER_SIZE]) -> Result<isize, String> {
         let n = unsafe { recv(self.fd, buf.as_ptr() as *mut c_void, buf.len(), 0) };
@@ -113,13 +150,32 @@ impl Socket {
         Ok(n)
     }
 
-    /// Receive first `BUFFER_SIZE` packets of a buffer, and fill in socket
-    /// address information.
-    pub fn recvfrom(
-        &self,
-        addr: &mut sockaddr_ll,
-        buf: &mut [u8; BUFFER_SIZE],
-    ) -> Result<isize, String> {
+    /// Enable the virtio net header before each received frame, whose GSO
+    /// size is used to split frames coalesced by GRO into their segments in
+    /// `recv_segment`.
+    pub fn enable_vnet_hdr(&mut self) -> Result<(), String> {
+        debug!("enabling PACKET_VNET_HDR");
+        let enable: c_int = 1;
+        let res = unsafe {
+            setsockopt(
+                self.fd,
+                SOL_PACKET,
+                PACKET_VNET_HDR,
+                (&enable as *const c_int) as _,
+                std::mem::size_of::<c_int>() as _,
+            )
+        };
+        if res < 0 {
+            return Err(format!("setsockopt: {}", res));
+        }
+        self.vnet_hdr = true;
+        self.frame = vec![0; VNET_HDR_LEN + MAX_FRAME_LEN];
+        Ok(())
+    }
+
+    /// Receive first `buf.len()` bytes of a frame, and fill in socket address
+    /// information.
+    pub fn recvfrom(&self, addr: &mut sockaddr_ll, buf: &mut [u8]) -> Result<isize, String> {
         let mut socklen = std::mem::size_of::<sockaddr_ll>() as u32;
         // wrapping our own libc functions because nix-rust is buggy:
         // https://github.com/nix-rust/nix/pull/1896
@@ -139,4 +195,83 @@ impl Socket {
         }
         Ok(n)
     }
+
+    /// Receive first `BUFFER_SIZE` bytes of the next frame, or of the next
+    /// segment of a frame coalesced by GRO if the virtio net header is
+    /// enabled. Fills in socket address information, which is the same for
+    /// every segment of a frame, and returns the length of the frame and the
+    /// kernel receive timestamp if timestamps are enabled.
+    pub fn recv_segment(
+        &mut self,
+        addr: &mut sockaddr_ll,
+        buf: &mut [u8; BUFFER_SIZE],
+    ) -> Result<(isize, Option<SystemTime>), String> {
+        if !self.vnet_hdr {
+            return self.recvfrom_with_timestamp(addr, buf);
+        }
+        loop {
+            if let Some((segments, timestamp)) = &mut self.segments {
+                if let Some(n) = segments.next_into(&self.frame, buf) {
+                    return Ok((n as isize, *timestamp));
+                }
+            }
+            // Reuse the frame buffer owned by the socket for every frame.
+            let (n, timestamp) = recvmsg_with_timestamp(self.fd, addr, &mut self.frame)?;
+            self.segments = Some((GsoSegments::new(&self.frame[..n as usize]), timestamp));
+        }
+    }
+
+    /// Receive first `buf.len()` bytes of a frame, fill in socket address
+    /// information, and return the kernel receive timestamp if timestamps are
+    /// enabled.
+    pub fn recvfrom_with_timestamp(
+        &self,
+        addr: &mut sockaddr_ll,
+        buf: &mut [u8],
+    ) -> Result<(isize, Option<SystemTime>), String> {
+        if !self.timestamps {
+            return self.recvfrom(addr, buf).map(|n| (n, None));
+        }
+        recvmsg_with_timestamp(self.fd, addr, buf)
+    }
+}
+
+/// Receive first `buf.len()` bytes of a frame, fill in socket address
+/// information, and return the kernel receive timestamp if timestamps are
+/// enabled on the socket.
+fn recvmsg_with_timestamp(
+    fd: i32,
+    addr: &mut sockaddr_ll,
+    buf: &mut [u8],
+) -> Result<(isize, Option<SystemTime>), String> {
+    let mut iov = iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+    // u64 for the alignment of cmsghdr
+    let mut cmsg_buf = [0u64; CMSG_BUFFER_SIZE / 8];
+    let mut msg: msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = (addr as *mut sockaddr_ll) as _;
+    msg.msg_namelen = std::mem::size_of::<sockaddr_ll>() as u32;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = CMSG_BUFFER_SIZE as _;
+    let n = unsafe { recvmsg(fd, &mut msg, 0) };
+    if n < 0 {
+        error!("failed to recv: {}", n);
+        return Err(format!("recv: {}", n));
+    }
+    let mut timestamp = None;
+    unsafe {
+        let mut cmsg = CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == SOL_SOCKET && (*cmsg).cmsg_type == SCM_TIMESTAMPNS {
+                let ts = std::ptr::read_unaligned(CMSG_DATA(cmsg) as *const timespec);
+                timestamp = Some(UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32));
+            }
+            cmsg = CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok((n, timestamp))
 }
@@ -0,0 +1,172 @@
+//! Counters of sniffed packets, cheap enough to update for every packet from
+//! the socket thread and to read from any other thread.
+//!
+//! The counters say why packets are not quACKed without `trace!` logging.
+//! The binaries print them on exit, or whenever the process receives SIGUSR1.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use serde::Serialize;
+use signal_hook::consts::{SIGINT, SIGTERM, SIGUSR1};
+use signal_hook::iterator::Signals;
+
+use crate::buffer::IpValidation;
+use crate::events::EventLog;
+
+/// Number of sniffed IPv4 packets with each validation result.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ValidationStats {
+    pub valid: u64,
+    pub first_fragments: u64,
+    pub fragments: u64,
+    pub truncated: u64,
+    pub bad_headers: u64,
+    pub not_udp: u64,
+}
+
+/// A snapshot of the counters of a sidekick.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SniffStats {
+    /// Frames received from the socket
+    pub frames: u64,
+    /// Frames skipped because they were sniffed in the other direction
+    pub wrong_direction: u64,
+    /// Frames skipped because they are not IPv4
+    pub not_ip: u64,
+    /// IPv4 packets by their validation result
+    pub validation: ValidationStats,
+    /// Packets of flows that the filter denied
+    pub filtered: u64,
+    /// Packets skipped because they are too short to have an identifier, or
+    /// are not encrypted packets of the profile
+    pub underfilled: u64,
+    /// QuACK resets received
+    pub resets: u64,
+    /// Identifiers inserted into quACKs
+    pub inserts: u64,
+    /// Flows created, by a sidekick that quACKs multiple flows
+    pub flows: u64,
+    /// Flows removed, e.g., on quACK resets
+    pub evictions: u64,
+}
+
+impl std::fmt::Display for SniffStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let v = &self.validation;
+        write!(
+            f,
+            "frames={} wrong_direction={} not_ip={} not_udp={} fragments={} \
+             first_fragments={} truncated={} bad_headers={} filtered={} \
+             underfilled={} resets={} inserts={} flows={} evictions={}",
+            self.frames,
+            self.wrong_direction,
+            self.not_ip,
+            v.not_udp,
+            v.fragments,
+            v.first_fragments,
+            v.truncated,
+            v.bad_headers,
+            self.filtered,
+            self.underfilled,
+            self.resets,
+            self.inserts,
+            self.flows,
+            self.evictions,
+        )
+    }
+}
+
+/// Counts of what was done with a flow since it was created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FlowStats {
+    /// Identifiers inserted into the quACK
+    pub inserts: u64,
+    /// QuACKs encoded to be sent
+    pub quacks: u64,
+    /// Resets of the quACK
+    pub resets: u64,
+}
+
+impl std::ops::AddAssign for FlowStats {
+    fn add_assign(&mut self, other: Self) {
+        self.inserts += other.inserts;
+        self.quacks += other.quacks;
+        self.resets += other.resets;
+    }
+}
+
+/// The counters of a sidekick, shared between the socket thread and the
+/// sidekick.
+#[derive(Debug, Default)]
+pub struct SniffCounters {
+    pub frames: AtomicU64,
+    pub wrong_direction: AtomicU64,
+    pub not_ip: AtomicU64,
+    pub filtered: AtomicU64,
+    pub underfilled: AtomicU64,
+    pub resets: AtomicU64,
+    pub inserts: AtomicU64,
+    pub flows: AtomicU64,
+    pub evictions: AtomicU64,
+    /// Time the sniff loop takes to process each packet
+    #[cfg(feature = "metrics")]
+    pub latency: crate::metrics::LatencyHistogram,
+    validation: [AtomicU64; 6],
+}
+
+/// Increment a counter.
+pub fn incr(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+impl SniffCounters {
+    pub fn record(&self, validation: IpValidation) {
+        incr(&self.validation[validation as usize]);
+    }
+
+    pub fn stats(&self) -> SniffStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let count = |validation: IpValidation| load(&self.validation[validation as usize]);
+        SniffStats {
+            frames: load(&self.frames),
+            wrong_direction: load(&self.wrong_direction),
+            not_ip: load(&self.not_ip),
+            validation: ValidationStats {
+                valid: count(IpValidation::Valid),
+                first_fragments: count(IpValidation::FirstFragment),
+                fragments: count(IpValidation::Fragment),
+                truncated: count(IpValidation::Truncated),
+                bad_headers: count(IpValidation::BadHeader),
+                not_udp: count(IpValidation::NotUdp),
+            },
+            filtered: load(&self.filtered),
+            underfilled: load(&self.underfilled),
+            resets: load(&self.resets),
+            inserts: load(&self.inserts),
+            flows: load(&self.flows),
+            evictions: load(&self.evictions),
+        }
+    }
+}
+
+/// Print the stats of the counters whenever the process receives SIGUSR1,
+/// and before it exits on SIGINT or SIGTERM, flushing the event log if any.
+pub fn print_on_signal(
+    counters: Arc<SniffCounters>,
+    events: Option<Arc<EventLog>>,
+) -> Result<(), String> {
+    let mut signals = Signals::new([SIGUSR1, SIGINT, SIGTERM])
+        .map_err(|e| format!("error registering signals: {:?}", e))?;
+    std::thread::spawn(move || {
+        for signal in &mut signals {
+            println!("{}", counters.stats());
+            if signal != SIGUSR1 {
+                if let Some(events) = &events {
+                    events.flush();
+                }
+                std::process::exit(128 + signal);
+            }
+        }
+    });
+    Ok(())
+}